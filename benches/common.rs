@@ -1,8 +1,9 @@
+// `test` crate is nightly-only: run with `cargo +nightly bench --features nightly`
+#![cfg(feature = "nightly")]
 #![feature(test)]
 extern crate test;
 
-use test::{Bencher, black_box};
-use merino::*;
+use test::{black_box, Bencher};
 
 #[bench]
 fn bench_pow(b: &mut Bencher) {
@@ -12,7 +13,7 @@ fn bench_pow(b: &mut Bencher) {
 
     b.iter(|| {
         // Inner closure, the actual test
-        for i in 1..100 {
+        for _ in 1..100 {
             black_box(x.powf(y).powf(x));
         }
     });
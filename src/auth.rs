@@ -1,19 +1,42 @@
 use crate::*;
 use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::net::{TcpListener, UdpSocket};
 use tokio::time::timeout;
 
+/// Version of the username/password sub-negotiation
+const USERPASS_VERSION: u8 = 0x01;
+
+/// Steps of authenticating a SOCKS5 client
+enum AuthState {
+    /// Choosing one of the methods offered by the client
+    Negotiating,
+    /// Waiting for the username and password
+    UserPass,
+    /// The client got its reply
+    Done(AuthOutcome),
+}
+
+enum AuthOutcome {
+    /// The client may send its request, as the user if it logged in
+    Granted(Option<Box<Identity>>),
+    Failed(AuthFailure),
+}
+
 pub struct SOCKClient<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> {
     pub(crate) stream: T,
+    /// Address of the client, used to restrict UDP associations
+    peer_addr: SocketAddr,
+    /// Local address of the client connection, used for BIND and UDP sockets
+    local_addr: SocketAddr,
     auth_nmethods: u8,
-    auth_methods: Arc<Vec<u8>>,
-    authed_users: Arc<Vec<User>>,
+    config: Arc<Config>,
     whitelisted: bool,
     socks_version: u8,
-    timeout: Option<Duration>,
+    /// User who passed authentication
+    identity: Option<Identity>,
 }
 
 impl<T> SOCKClient<T>
@@ -23,38 +46,20 @@ where
     /// Create a new SOCKClient
     pub fn new(
         stream: T,
-        authed_users: Arc<Vec<User>>,
-        auth_methods: Arc<Vec<u8>>,
+        peer_addr: SocketAddr,
+        local_addr: SocketAddr,
+        config: Arc<Config>,
         whitelisted: bool,
-        timeout: Option<Duration>,
     ) -> Self {
         SOCKClient {
             stream,
+            peer_addr,
+            local_addr,
             auth_nmethods: 0,
             socks_version: 0,
-            authed_users,
-            auth_methods,
+            identity: None,
+            config,
             whitelisted,
-            timeout,
-        }
-    }
-
-    #[allow(dead_code)]
-    /// Create a new SOCKClient with no auth
-    pub fn new_no_auth(stream: T, timeout: Option<Duration>) -> Self {
-        // FIXME: use option here
-        let authed_users: Arc<Vec<User>> = Arc::new(Vec::new());
-        let no_auth: Vec<u8> = vec![AuthMethods::NoAuth as u8];
-        let auth_methods: Arc<Vec<u8>> = Arc::new(no_auth);
-
-        SOCKClient {
-            stream,
-            auth_nmethods: 0,
-            socks_version: 0,
-            authed_users,
-            auth_methods,
-            whitelisted: false,
-            timeout,
         }
     }
 
@@ -64,19 +69,53 @@ where
         &mut self.stream
     }
 
-    /// Check if username + password pair are valid
-    fn authed(&self, user: &User) -> bool {
-        self.authed_users.contains(user)
-    }
-
     /// Shutdown a client
     pub async fn shutdown(&mut self) -> io::Result<()> {
         self.stream.shutdown().await?;
         Ok(())
     }
 
-    pub async fn init(&mut self) -> Result<(), MerinoError> {
+    /// Turn the client away during version negotiation, the way its SOCKS version expects
+    pub async fn refuse(&mut self) -> io::Result<()> {
+        let mut header = [0u8; 2];
+        self.stream.read_exact(&mut header).await?;
+        self.socks_version = header[0];
+
+        match self.socks_version {
+            SOCKS_VERSION => {
+                let mut methods = vec![0u8; header[1] as usize];
+                self.stream.read_exact(&mut methods).await?;
+                let response = [SOCKS_VERSION, AuthMethods::NoMethods as u8];
+                self.stream.write_all(&response).await?;
+            }
+            SOCKS4_VERSION => self.reply(ResponseCode::RuleFailure, None).await?,
+            _ => {}
+        }
+
+        self.shutdown().await
+    }
+
+    /// Serve the client, returning the summary of the relayed session if there was one
+    pub async fn init(&mut self) -> Result<Option<SessionStats>, MerinoError> {
         debug!("New connection");
+
+        // Slow clients are cut off before they get to the request
+        let req = timeout(self.config.timeouts.handshake, self.handshake())
+            .await
+            .map_err(|_| {
+                warn!("Handshake timed out");
+                MerinoError::Socks(ResponseCode::TtlExpired)
+            })??;
+
+        match req {
+            // Handle requests
+            Some(req) => Ok(Some(self.handle_client(req).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Negotiate the version and authentication, then read the request
+    async fn handshake(&mut self) -> Result<Option<SOCKSReq>, MerinoError> {
         let mut header = [0u8; 2];
         // Read a byte from the stream and determine the version being requested
         self.stream.read_exact(&mut header).await?;
@@ -94,101 +133,198 @@ where
             SOCKS_VERSION => {
                 // Authenticate w/ client
                 self.auth().await?;
-                // Handle requests
-                self.handle_client().await?;
+                Ok(Some(SOCKSReq::from_stream(&mut self.stream).await?))
             }
+            // The second byte is CD, SOCKS4 has no method negotiation
+            SOCKS4_VERSION if self.config.socks4 => Ok(Some(self.socks4_request(header[1]).await?)),
             _ => {
                 warn!("Init: Unsupported version: SOCKS{}", self.socks_version);
                 self.shutdown().await?;
+                Ok(None)
             }
         }
-
-        Ok(())
     }
 
+    /// Negotiate the method and authenticate, the client gets its reply either way
     async fn auth(&mut self) -> Result<(), MerinoError> {
         debug!("Authenticating");
-        // Get valid auth methods
+        let mut state = AuthState::Negotiating;
+        let outcome = loop {
+            state = match state {
+                AuthState::Negotiating => self.negotiate().await?,
+                AuthState::UserPass => self.userpass().await?,
+                AuthState::Done(outcome) => break outcome,
+            };
+        };
+
+        match outcome {
+            AuthOutcome::Granted(identity) => {
+                self.identity = identity.map(|identity| *identity);
+                Ok(())
+            }
+            AuthOutcome::Failed(failure) => {
+                self.shutdown().await?;
+                Err(MerinoError::Denied(failure))
+            }
+        }
+    }
+
+    /// Choose a method from those offered by the client
+    async fn negotiate(&mut self) -> Result<AuthState, MerinoError> {
         let methods = self.get_avalible_methods().await?;
         trace!("methods: {:?}", methods);
 
-        let mut response = [0u8; 2];
-
-        // Set the version in the response
-        response[0] = SOCKS_VERSION;
-
-        if methods.contains(&(AuthMethods::UserPass as u8)) {
-            // Set the default auth method (NO AUTH)
-            response[1] = AuthMethods::UserPass as u8;
-
+        // USER/PASS is preferred, so whitelisted clients with credentials are still identified
+        let (method, next) = if methods.contains(&(AuthMethods::UserPass as u8)) {
             debug!("Sending USER/PASS packet");
-            self.stream.write_all(&response).await?;
-
-            let mut header = [0u8; 2];
-
-            // Read a byte from the stream and determine the version being requested
-            self.stream.read_exact(&mut header).await?;
-
-            // debug!("Auth Header: [{}, {}]", header[0], header[1]);
-
-            // Username parsing
-            let ulen = header[1] as usize;
-
-            let mut username = vec![0; ulen];
-
-            self.stream.read_exact(&mut username).await?;
-
-            // Password Parsing
-            let mut plen = [0u8; 1];
-            self.stream.read_exact(&mut plen).await?;
-
-            let mut password = vec![0; plen[0] as usize];
-            self.stream.read_exact(&mut password).await?;
+            (AuthMethods::UserPass, AuthState::UserPass)
+        } else if methods.contains(&(AuthMethods::NoAuth as u8)) {
+            debug!("Sending NOAUTH packet");
+            (
+                AuthMethods::NoAuth,
+                AuthState::Done(AuthOutcome::Granted(None)),
+            )
+        } else {
+            warn!("Client has no suitable Auth methods!");
+            let failure = AuthFailure::NoAcceptableMethod;
+            (
+                AuthMethods::NoMethods,
+                AuthState::Done(AuthOutcome::Failed(failure)),
+            )
+        };
+
+        self.stream
+            .write_all(&[SOCKS_VERSION, method as u8])
+            .await?;
+        Ok(next)
+    }
 
-            let username = String::from_utf8_lossy(&username).to_string();
-            let password = String::from_utf8_lossy(&password).to_string();
+    /// Username/password sub-negotiation from RFC 1929
+    async fn userpass(&mut self) -> Result<AuthState, MerinoError> {
+        let mut header = [0u8; 2];
+        self.stream.read_exact(&mut header).await?;
+        if header[0] != USERPASS_VERSION {
+            warn!("Unsupported username/password version {}", header[0]);
+            self.stream
+                .write_all(&[USERPASS_VERSION, ResponseCode::Failure as u8])
+                .await?;
+            let failure = AuthFailure::UnsupportedVersion(header[0]);
+            return Ok(AuthState::Done(AuthOutcome::Failed(failure)));
+        }
 
-            let user = User { username, password };
+        // Username parsing
+        let mut username = vec![0; header[1] as usize];
+        self.stream.read_exact(&mut username).await?;
 
-            // Authenticate passwords
-            if self.authed(&user) {
-                debug!("Access Granted. User: {}", user.username);
-                let response = [1, ResponseCode::Success as u8];
-                self.stream.write_all(&response).await?;
-            } else {
-                debug!("Access Denied. User: {}", user.username);
-                let response = [1, ResponseCode::Failure as u8];
-                self.stream.write_all(&response).await?;
+        // Password Parsing
+        let mut plen = [0u8; 1];
+        self.stream.read_exact(&mut plen).await?;
+        let mut password = vec![0; plen[0] as usize];
+        self.stream.read_exact(&mut password).await?;
 
-                // Shutdown
-                self.shutdown().await?;
-            }
+        let username = String::from_utf8_lossy(&username).to_string();
+        let password = String::from_utf8_lossy(&password).to_string();
 
-            Ok(())
-        } else if methods.contains(&(AuthMethods::NoAuth as u8)) {
-            // set the default auth method (no auth)
-            response[1] = AuthMethods::NoAuth as u8;
-            debug!("Sending NOAUTH packet");
-            self.stream.write_all(&response).await?;
-            debug!("NOAUTH sent");
-            Ok(())
+        // Authenticate passwords, banned users are refused without checking
+        let identity = if self.config.bans.is_banned(&Subject::User(username.clone())) {
+            warn!("User {} is banned, refusing {}", username, self.peer_addr);
+            None
         } else {
-            warn!("Client has no suitable Auth methods!");
-            response[1] = AuthMethods::NoMethods as u8;
-            self.stream.write_all(&response).await?;
-            self.shutdown().await?;
+            let identity = self
+                .config
+                .authenticator
+                .authenticate(&username, &password, self.peer_addr)
+                .await;
+            if identity.is_none() {
+                self.config.bans.failed(self.peer_addr.ip(), &username);
+            }
+            identity
+        };
+
+        let outcome = match identity.filter(|identity| self.admitted(identity)) {
+            Some(identity) => {
+                debug!("Access Granted. User: {}", identity.username);
+                self.stream
+                    .write_all(&[USERPASS_VERSION, ResponseCode::Success as u8])
+                    .await?;
+                AuthOutcome::Granted(Some(Box::new(identity)))
+            }
+            None => {
+                debug!("Access Denied. User: {}", username);
+                self.stream
+                    .write_all(&[USERPASS_VERSION, ResponseCode::Failure as u8])
+                    .await?;
+                AuthOutcome::Failed(AuthFailure::Denied(username))
+            }
+        };
+        Ok(AuthState::Done(outcome))
+    }
 
-            Err(MerinoError::Socks(ResponseCode::RuleFailure))
+    /// Send a reply in the format of the client's SOCKS version
+    pub(crate) async fn reply(
+        &mut self,
+        status: ResponseCode,
+        addr: Option<SocketAddr>,
+    ) -> io::Result<()> {
+        match (self.socks_version, addr) {
+            (SOCKS4_VERSION, Some(addr)) => {
+                Socks4Reply::with_addr(status.into(), addr)
+                    .send(&mut self.stream)
+                    .await
+            }
+            (SOCKS4_VERSION, None) => Socks4Reply::new(status.into()).send(&mut self.stream).await,
+            (_, Some(addr)) => {
+                SocksReply::with_addr(status, addr)
+                    .send(&mut self.stream)
+                    .await
+            }
+            (_, None) => SocksReply::new(status).send(&mut self.stream).await,
         }
     }
 
-    /// Handles a client
-    pub async fn handle_client(&mut self) -> Result<usize, MerinoError> {
-        debug!("Starting to relay data");
+    /// Read and check a SOCKS4 or SOCKS4a request, `command` is the already read CD byte
+    async fn socks4_request(&mut self, command: u8) -> Result<SOCKSReq, MerinoError> {
+        debug!("Handling SOCKS4 request");
+
+        let (req, user_id) = socks4::read_request(&mut self.stream, command).await?;
+
+        // SOCKS4 can't authenticate, so it's served where SOCKS5 wouldn't ask for credentials
+        let no_auth = self.whitelisted
+            || self
+                .config
+                .auth_methods
+                .contains(&(AuthMethods::NoAuth as u8));
+
+        if self.config.socks4_check_userid && !self.whitelisted {
+            let identity = self.config.authenticator.identify(&user_id).await;
+            let identity = match identity.filter(|identity| self.admitted(identity)) {
+                Some(identity) => identity,
+                None => {
+                    debug!("Access Denied. SOCKS4 USERID: {}", user_id);
+                    Socks4Reply::new(Socks4Code::UserIdMismatch)
+                        .send(&mut self.stream)
+                        .await?;
+                    self.shutdown().await?;
+
+                    return Err(MerinoError::Denied(AuthFailure::Denied(user_id)));
+                }
+            };
+            debug!("Access Granted. SOCKS4 USERID: {}", user_id);
+            self.identity = Some(identity);
+        } else if !no_auth {
+            warn!("SOCKS4 client can't authenticate, USERID: {}", user_id);
+            return Err(MerinoError::Socks(ResponseCode::RuleFailure));
+        }
 
-        let req = SOCKSReq::from_stream(&mut self.stream).await?;
+        Ok(req)
+    }
 
-        if req.addr_type == AddrType::V6 {}
+    /// Handles a client request
+    pub(crate) async fn handle_client(
+        &mut self,
+        req: SOCKSReq,
+    ) -> Result<SessionStats, MerinoError> {
+        debug!("Starting to relay data");
 
         // Log Request
         let displayed_addr = pretty_print_addr(&req.addr_type, &req.addr);
@@ -197,66 +333,424 @@ where
             req.command, displayed_addr, req.port
         );
 
+        let quota = match &self.identity {
+            Some(identity) => self
+                .config
+                .quotas
+                .session(&identity.username, identity.permissions.quota),
+            None => QuotaSession::none(),
+        };
+        if quota.is_exhausted() {
+            warn!(
+                "Quota of user {} is exhausted",
+                self.identity.as_ref().map_or("", |id| id.username.as_str())
+            );
+            return Err(MerinoError::Socks(ResponseCode::RuleFailure));
+        }
+
+        // Released when the session ends
+        let _user_guard = match &self.identity {
+            Some(identity) => match self.config.connections.user(&identity.username) {
+                Some(guard) => Some(guard),
+                None => {
+                    warn!(
+                        "Connection limit of user {} reached, refusing request",
+                        identity.username
+                    );
+                    return Err(MerinoError::Socks(ResponseCode::RuleFailure));
+                }
+            },
+            None => None,
+        };
+
+        let limits = relay::Limits {
+            // Traffic is shaped together with other sessions of the user and the client address
+            shaper: self.config.shaper.session(
+                self.identity.as_ref().map(|id| id.username.as_str()),
+                self.peer_addr.ip(),
+            ),
+            quota,
+        };
+
         // Respond
         match req.command {
             // Use the Proxy to connect to the specified addr/port
             SockCommand::Connect => {
                 debug!("Handling CONNECT Command");
 
-                let sock_addr = addr_to_socket(&req.addr_type, &req.addr, req.port)?;
+                // Routes see the destination as requested, domains are not resolved yet
+                let requested = match req.addr_type {
+                    AddrType::Domain => None,
+                    _ => addr_to_socket(&self.config.resolver, &req.addr_type, &req.addr, req.port)
+                        .await?
+                        .into_iter()
+                        .next(),
+                };
+                let domain = requested_domain(&req);
+                let route = rules::Request {
+                    command: rules::Command::Connect,
+                    user: self.identity.as_ref().map(|id| id.username.as_str()),
+                    source: self.peer_addr.ip(),
+                    domain: domain.as_deref(),
+                    ip: requested.map(|addr| addr.ip()),
+                    port: req.port,
+                };
+                let target = match (requested, domain.clone()) {
+                    (Some(addr), _) => Target::Addr(addr),
+                    (None, domain) => Target::Domain(domain.unwrap_or_default(), req.port),
+                };
+                let router = self.config.router.clone();
+                let direct = self.config.connector.clone();
 
-                trace!("Connecting to: {:?}", sock_addr);
+                let connector: &dyn Connector = match router.gateway(&route) {
+                    Some(gateway) => {
+                        debug!("Connecting to {} via {}", target, gateway.name());
+                        gateway
+                    }
+                    None => &*direct,
+                };
 
-                let time_out = if let Some(time_out) = self.timeout {
-                    time_out
+                let addrs = if connector.resolves_remotely() {
+                    self.permitted_remote(&req, &target)?;
+                    Vec::new()
                 } else {
-                    Duration::from_millis(50)
+                    let sock_addr =
+                        addr_to_socket(&self.config.resolver, &req.addr_type, &req.addr, req.port)
+                            .await?;
+                    let sock_addr = self.public_only(&req, sock_addr)?;
+                    self.permitted(rules::Command::Connect, &req, sock_addr)?
                 };
 
-                let mut target =
-                    timeout(
-                        time_out,
-                        async move { TcpStream::connect(&sock_addr[..]).await },
-                    )
-                    .await
-                    .map_err(|_| MerinoError::Socks(ResponseCode::AddrTypeNotSupported))
-                    .map_err(|_| MerinoError::Socks(ResponseCode::AddrTypeNotSupported))??;
+                let context = ConnectContext {
+                    client: self.peer_addr,
+                    user: self.identity.as_ref().map(|id| id.username.as_str()),
+                    addrs: &addrs,
+                    resolver: &self.config.resolver,
+                    ip_family: self.config.ip_family,
+                    egress: &self.config.egress,
+                };
+                let mut outbound = timeout(
+                    self.config.timeouts.connect,
+                    connector.connect(&target, &context),
+                )
+                .await
+                .map_err(|_| MerinoError::Socks(ResponseCode::TtlExpired))??;
 
                 trace!("Connected!");
 
-                SocksReply::new(ResponseCode::Success)
+                // BND.ADDR and BND.PORT are the address of the outbound socket, if there is one
+                let bound = outbound
+                    .local_addr
+                    .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+                self.reply(ResponseCode::Success, Some(bound)).await?;
+
+                Ok(relay::relay(
+                    &mut self.stream,
+                    &mut outbound.stream,
+                    self.config.timeouts,
+                    &limits,
+                )
+                .await)
+            }
+            // Wait for an inbound connection from the specified addr
+            SockCommand::Bind => {
+                debug!("Handling BIND Command");
+
+                let expected =
+                    addr_to_socket(&self.config.resolver, &req.addr_type, &req.addr, req.port)
+                        .await?;
+                // 0.0.0.0 says nothing about the peer, which is checked once it has connected
+                let expected = if expected.iter().all(|addr| addr.ip().is_unspecified()) {
+                    self.permitted_command(rules::Command::Bind)?;
+                    expected
+                } else {
+                    self.permitted(rules::Command::Bind, &req, expected)?
+                };
+
+                let listener = TcpListener::bind(SocketAddr::new(self.local_addr.ip(), 0)).await?;
+                let bound = listener.local_addr()?;
+
+                trace!("Listening for {:?} on {}", expected, bound);
+
+                // First reply: where the application server should connect to
+                self.reply(ResponseCode::Success, Some(bound)).await?;
+
+                let (mut inbound, peer) = timeout(self.config.timeouts.connect, listener.accept())
+                    .await
+                    .map_err(|_| MerinoError::Socks(ResponseCode::TtlExpired))??;
+                // Exactly one connection is accepted
+                drop(listener);
+
+                // Clients which do not know the address of the application server send 0.0.0.0
+                if !expected
+                    .iter()
+                    .any(|addr| addr.ip().is_unspecified() || addr.ip() == peer.ip())
+                {
+                    warn!(
+                        "BIND: unexpected connection from {}, expected {}",
+                        peer, displayed_addr
+                    );
+                    return Err(MerinoError::Socks(ResponseCode::RuleFailure));
+                }
+                self.permitted(rules::Command::Bind, &req, vec![peer])?;
+
+                trace!("Accepted inbound connection from {}", peer);
+
+                // Second reply: who has connected
+                self.reply(ResponseCode::Success, Some(peer)).await?;
+
+                Ok(relay::relay(
+                    &mut self.stream,
+                    &mut inbound,
+                    self.config.timeouts,
+                    &limits,
+                )
+                .await)
+            }
+            // Relay datagrams from the client's announced address
+            SockCommand::UdpAssosiate => {
+                debug!("Handling UDP ASSOCIATE Command");
+                self.permitted_command(rules::Command::Udp)?;
+
+                let announced =
+                    addr_to_socket(&self.config.resolver, &req.addr_type, &req.addr, req.port)
+                        .await?
+                        .into_iter()
+                        .next()
+                        .ok_or(MerinoError::Socks(ResponseCode::AddrTypeNotSupported))?;
+
+                // Clients which do not know their address yet send 0.0.0.0
+                let client_ip = if announced.ip().is_unspecified() {
+                    self.peer_addr.ip()
+                } else {
+                    announced.ip()
+                };
+                let client = SocketAddr::new(client_ip, announced.port());
+
+                let socket = UdpSocket::bind(SocketAddr::new(self.local_addr.ip(), 0)).await?;
+                let bound = socket.local_addr()?;
+
+                trace!("Relaying datagrams for {} on {}", client, bound);
+
+                SocksReply::with_addr(ResponseCode::Success, bound)
                     .send(&mut self.stream)
                     .await?;
 
-                trace!("copy bidirectional");
-                match tokio::io::copy_bidirectional(&mut self.stream, &mut target).await {
-                    // ignore not connected for shutdown error
-                    Err(e) if e.kind() == std::io::ErrorKind::NotConnected => {
-                        trace!("already closed");
-                        Ok(0)
-                    }
-                    Err(e) => Err(MerinoError::Io(e)),
-                    Ok((_s_to_t, t_to_s)) => Ok(t_to_s as usize),
-                }
+                // Rules are checked for the target of each datagram
+                let private_filter = self.config.private_filter.clone();
+                let rules = self.config.rules.clone();
+                let identity = self.identity.clone();
+                let source = self.peer_addr.ip();
+                let permit = move |domain: Option<&str>, target: SocketAddr| {
+                    let req = rules::Request {
+                        command: rules::Command::Udp,
+                        user: identity.as_ref().map(|id| id.username.as_str()),
+                        source,
+                        domain,
+                        ip: Some(target.ip()),
+                        port: target.port(),
+                    };
+                    !private_filter
+                        .as_ref()
+                        .is_some_and(|filter| filter.blocks(&target.ip()))
+                        && identity.as_ref().is_none_or(|id| {
+                            let allowed = id.permissions.allows(&req);
+                            if !allowed {
+                                debug!(
+                                    "UDP ASSOCIATE: {} is not permitted for user {}",
+                                    target, id.username
+                                );
+                            }
+                            allowed
+                        })
+                        && rules.allows(&req)
+                };
+
+                let context = ConnectContext {
+                    client: self.peer_addr,
+                    user: self.identity.as_ref().map(|id| id.username.as_str()),
+                    addrs: &[],
+                    resolver: &self.config.resolver,
+                    ip_family: self.config.ip_family,
+                    egress: &self.config.egress,
+                };
+                Ok(udp::relay(
+                    &mut self.stream,
+                    socket,
+                    client,
+                    &context,
+                    self.config.timeouts,
+                    permit,
+                    &limits,
+                )
+                .await)
             }
-            SockCommand::Bind => Err(MerinoError::Io(std::io::Error::new(
-                std::io::ErrorKind::Unsupported,
-                "Bind not supported",
-            ))),
-            SockCommand::UdpAssosiate => Err(MerinoError::Io(std::io::Error::new(
-                std::io::ErrorKind::Unsupported,
-                "UdpAssosiate not supported",
-            ))),
         }
     }
 
+    /// Drop blocked private destinations of `req`, failing if there are none left.
+    /// Resolved addresses are checked, so DNS rebinding can't get around it.
+    fn public_only(
+        &self,
+        req: &SOCKSReq,
+        addrs: Vec<SocketAddr>,
+    ) -> Result<Vec<SocketAddr>, MerinoError> {
+        let filter = match &self.config.private_filter {
+            Some(filter) => filter,
+            None => return Ok(addrs),
+        };
+
+        let public: Vec<SocketAddr> = addrs
+            .into_iter()
+            .filter(|addr| !filter.blocks(&addr.ip()))
+            .collect();
+
+        if public.is_empty() {
+            warn!(
+                "{}:{} is a private destination, blocked",
+                pretty_print_addr(&req.addr_type, &req.addr),
+                req.port
+            );
+            return Err(MerinoError::Socks(ResponseCode::RuleFailure));
+        }
+
+        Ok(public)
+    }
+
+    /// Keep the destinations of `req` allowed by the permissions of the user and the rules,
+    /// failing if there are none
+    fn permitted(
+        &self,
+        command: rules::Command,
+        req: &SOCKSReq,
+        addrs: Vec<SocketAddr>,
+    ) -> Result<Vec<SocketAddr>, MerinoError> {
+        self.permitted_by(command, req, addrs, |addr| (Some(addr.ip()), addr.port()))
+    }
+
+    /// Like `permitted`, for destinations which are IP addresses and ports as told by `dest`,
+    /// or only ports if they are not resolved here
+    fn permitted_by<D>(
+        &self,
+        command: rules::Command,
+        req: &SOCKSReq,
+        targets: Vec<D>,
+        dest: impl Fn(&D) -> (Option<IpAddr>, u16),
+    ) -> Result<Vec<D>, MerinoError> {
+        let domain = requested_domain(req);
+        let user = self.identity.as_ref().map(|id| id.username.as_str());
+        let request = |target: &D| {
+            let (ip, port) = dest(target);
+            rules::Request {
+                command,
+                user,
+                source: self.peer_addr.ip(),
+                domain: domain.as_deref(),
+                ip,
+                port,
+            }
+        };
+
+        let mut permitted = targets;
+        if let Some(identity) = &self.identity {
+            permitted.retain(|target| identity.permissions.allows(&request(target)));
+            if permitted.is_empty() {
+                warn!(
+                    "{} to {}:{} is not permitted for user {}",
+                    command,
+                    pretty_print_addr(&req.addr_type, &req.addr),
+                    req.port,
+                    identity.username
+                );
+                return Err(MerinoError::Socks(ResponseCode::RuleFailure));
+            }
+        }
+
+        permitted.retain(|target| self.config.rules.allows(&request(target)));
+        if permitted.is_empty() {
+            warn!(
+                "{} to {}:{} denied by rules, user: {:?}",
+                command,
+                pretty_print_addr(&req.addr_type, &req.addr),
+                req.port,
+                user
+            );
+            return Err(MerinoError::Socks(ResponseCode::RuleFailure));
+        }
+
+        Ok(permitted)
+    }
+
+    /// Check that `command` may be used at all, for commands which only learn their
+    /// destinations later
+    fn permitted_command(&self, command: rules::Command) -> Result<(), MerinoError> {
+        let user = self.identity.as_ref().map(|id| id.username.as_str());
+        let request = rules::Request {
+            command,
+            user,
+            source: self.peer_addr.ip(),
+            domain: None,
+            ip: None,
+            port: 0,
+        };
+
+        if let Some(identity) = &self.identity {
+            if !identity.permissions.allows_command(&request) {
+                warn!(
+                    "{} is not permitted for user {}",
+                    command, identity.username
+                );
+                return Err(MerinoError::Socks(ResponseCode::RuleFailure));
+            }
+        }
+
+        if !self.config.rules.allows_command(&request) {
+            warn!("{} denied by rules, user: {:?}", command, user);
+            return Err(MerinoError::Socks(ResponseCode::RuleFailure));
+        }
+
+        Ok(())
+    }
+
+    /// Check a destination for a connector which resolves domains itself.
+    /// The private filter doesn't apply, the destination is in the network of the connector.
+    fn permitted_remote(&self, req: &SOCKSReq, target: &Target) -> Result<(), MerinoError> {
+        self.permitted_by(
+            rules::Command::Connect,
+            req,
+            vec![target],
+            |target| match target {
+                Target::Addr(addr) => (Some(addr.ip()), addr.port()),
+                Target::Domain(_, port) => (None, *port),
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Whether `identity` may use the proxy from the address of the client
+    fn admitted(&self, identity: &Identity) -> bool {
+        if !identity.permissions.enabled {
+            warn!("User {} is disabled", identity.username);
+            return false;
+        }
+        if !identity.permissions.allows_source(&self.peer_addr.ip()) {
+            warn!(
+                "User {} is not allowed from {}",
+                identity.username,
+                self.peer_addr.ip()
+            );
+            return false;
+        }
+        true
+    }
+
     /// Return the avalible methods based on `self.auth_nmethods`
     async fn get_avalible_methods(&mut self) -> io::Result<Vec<u8>> {
         let mut methods: Vec<u8> = Vec::with_capacity(self.auth_nmethods as usize);
         for _ in 0..self.auth_nmethods {
             let mut method = [0u8; 1];
             self.stream.read_exact(&mut method).await?;
-            if self.auth_methods.contains(&method[0]) {
+            if self.config.auth_methods.contains(&method[0]) {
                 methods.append(&mut method.to_vec());
             }
         }
@@ -271,3 +765,11 @@ where
         Ok(methods)
     }
 }
+
+/// Domain of `req`, if the client sent one
+fn requested_domain(req: &SOCKSReq) -> Option<String> {
+    match req.addr_type {
+        AddrType::Domain => Some(String::from_utf8_lossy(&req.addr).to_string()),
+        _ => None,
+    }
+}
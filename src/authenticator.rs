@@ -0,0 +1,308 @@
+use crate::{Permissions, User};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, RwLock};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// Client which passed authentication
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity {
+    pub username: String,
+    /// What the client may do, checked for every request
+    pub permissions: Permissions,
+}
+
+impl Identity {
+    /// Identity of `username` with all permissions
+    pub fn new(username: &str) -> Self {
+        Identity {
+            username: username.to_string(),
+            permissions: Permissions::default(),
+        }
+    }
+}
+
+/// Source of credentials for USER/PASS authentication
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Check the credentials of `client`. `None` denies access.
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+        client: SocketAddr,
+    ) -> Option<Identity>;
+
+    /// Whether `username` exists, used to check SOCKS4 USERID.
+    /// Authenticators which can't tell deny all SOCKS4 clients.
+    async fn has_user(&self, _username: &str) -> bool {
+        false
+    }
+
+    /// Identity of `username` without checking credentials, used for SOCKS4 USERID.
+    /// By default it has all permissions if the user exists.
+    async fn identify(&self, username: &str) -> Option<Identity> {
+        self.has_user(username)
+            .await
+            .then(|| Identity::new(username))
+    }
+
+    /// Read the credentials again from their source.
+    /// On error the previous credentials must stay in use.
+    fn reload(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Files the credentials are read from, watched for changes
+    fn files(&self) -> Vec<PathBuf> {
+        Vec::new()
+    }
+}
+
+/// Users kept in memory, by username
+#[derive(Clone, Default)]
+pub struct MemoryAuthenticator {
+    users: Arc<HashMap<String, User>>,
+    /// Passwords of unknown users are checked against a dummy hash made like that of one of
+    /// the users, so they take as long as those of known users
+    dummy: Option<Arc<User>>,
+}
+
+impl MemoryAuthenticator {
+    pub fn new(users: impl IntoIterator<Item = User>) -> Self {
+        let users: HashMap<String, User> = users
+            .into_iter()
+            .map(|user| (user.username.clone(), user))
+            .collect();
+        let dummy = users
+            .values()
+            .find_map(User::dummy_hash)
+            .map(|hash| Arc::new(User::new("", &hash)));
+
+        MemoryAuthenticator {
+            users: Arc::new(users),
+            dummy,
+        }
+    }
+}
+
+#[async_trait]
+impl Authenticator for MemoryAuthenticator {
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+        _client: SocketAddr,
+    ) -> Option<Identity> {
+        let user = match self.users.get(username) {
+            Some(user) => user.clone(),
+            None => {
+                if let Some(dummy) = self.dummy.clone() {
+                    let password = password.to_string();
+                    let _ =
+                        tokio::task::spawn_blocking(move || dummy.verify_password(&password)).await;
+                }
+                return None;
+            }
+        };
+        let permissions = user.permissions().clone();
+        let password = password.to_string();
+
+        // Password hashing is slow on purpose, so it's kept off the runtime threads
+        let verified = tokio::task::spawn_blocking(move || user.verify_password(&password))
+            .await
+            .unwrap_or(false);
+
+        verified.then(|| Identity {
+            username: username.to_string(),
+            permissions,
+        })
+    }
+
+    async fn has_user(&self, username: &str) -> bool {
+        self.users.contains_key(username)
+    }
+
+    async fn identify(&self, username: &str) -> Option<Identity> {
+        self.users.get(username).map(|user| Identity {
+            username: username.to_string(),
+            permissions: user.permissions().clone(),
+        })
+    }
+}
+
+/// Users from a CSV file with `username,password` columns
+pub struct CsvAuthenticator {
+    path: PathBuf,
+    allow_insecure: bool,
+    /// Swapped as a whole on reload
+    users: RwLock<MemoryAuthenticator>,
+}
+
+impl CsvAuthenticator {
+    /// Read users from `path`. Plaintext passwords and a file accessible by others are refused
+    /// unless `allow_insecure` is set.
+    pub fn load(path: &Path, allow_insecure: bool) -> io::Result<Self> {
+        Ok(CsvAuthenticator {
+            path: path.to_path_buf(),
+            allow_insecure,
+            users: RwLock::new(read_users(path, allow_insecure)?),
+        })
+    }
+
+    /// Path to the users file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn users(&self) -> MemoryAuthenticator {
+        self.users.read().unwrap().clone()
+    }
+}
+
+fn read_users(path: &Path, allow_insecure: bool) -> io::Result<MemoryAuthenticator> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    let file = File::open(path)?;
+    #[cfg(not(target_os = "windows"))]
+    {
+        use std::os::unix::prelude::MetadataExt;
+
+        let mode = file.metadata()?.mode();
+        // 7 is (S_IROTH | S_IWOTH | S_IXOTH) or the "permisions for others" in unix
+        if (mode & 7) > 0 && !allow_insecure {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "Permissions {:o} for {:?} are too open. \
+                    It is recommended that your users file is NOT accessible by others. \
+                    To override this check, set --allow-insecure",
+                    mode & 0o777,
+                    path
+                ),
+            ));
+        }
+    }
+
+    let mut users: Vec<User> = Vec::new();
+    let mut rdr = csv::Reader::from_reader(file);
+    for result in rdr.deserialize() {
+        let user: User = result.map_err(|e| invalid(format!("{:?}: {}", path, e)))?;
+        trace!("Loaded user: {}", user.username);
+        users.push(user);
+    }
+
+    if users.is_empty() {
+        return Err(invalid(format!("No users loaded from {:?}", path)));
+    }
+
+    let plaintext: Vec<&str> = users
+        .iter()
+        .filter(|user| user.has_plaintext_password())
+        .map(|user| user.username.as_str())
+        .collect();
+    if !plaintext.is_empty() {
+        if !allow_insecure {
+            return Err(invalid(format!(
+                "Passwords of {:?} in {:?} are not hashed, hash them with `merino hash-password`",
+                plaintext, path
+            )));
+        }
+        warn!("Passwords of {:?} are not hashed", plaintext);
+    }
+
+    Ok(MemoryAuthenticator::new(users))
+}
+
+#[async_trait]
+impl Authenticator for CsvAuthenticator {
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+        client: SocketAddr,
+    ) -> Option<Identity> {
+        self.users().authenticate(username, password, client).await
+    }
+
+    async fn has_user(&self, username: &str) -> bool {
+        self.users().has_user(username).await
+    }
+
+    async fn identify(&self, username: &str) -> Option<Identity> {
+        self.users().identify(username).await
+    }
+
+    fn reload(&self) -> io::Result<()> {
+        let users = read_users(&self.path, self.allow_insecure)?;
+        *self.users.write().unwrap() = users;
+        Ok(())
+    }
+
+    fn files(&self) -> Vec<PathBuf> {
+        vec![self.path.clone()]
+    }
+}
+
+/// Credentials checked by an external program.
+///
+/// The program gets the username and the password as two lines on stdin and the address
+/// of the client in `MERINO_CLIENT_ADDR`. Exit status 0 grants access.
+pub struct CommandAuthenticator {
+    program: PathBuf,
+}
+
+impl CommandAuthenticator {
+    pub fn new(program: impl Into<PathBuf>) -> Self {
+        CommandAuthenticator {
+            program: program.into(),
+        }
+    }
+
+    async fn run(&self, username: &str, password: &str, client: SocketAddr) -> io::Result<bool> {
+        let mut child = Command::new(&self.program)
+            .env("MERINO_CLIENT_ADDR", client.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            // Killed when the handshake times out
+            .kill_on_drop(true)
+            .spawn()?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            let credentials = format!("{}\n{}\n", username, password);
+            stdin.write_all(credentials.as_bytes()).await?;
+        }
+
+        Ok(child.wait().await?.success())
+    }
+}
+
+#[async_trait]
+impl Authenticator for CommandAuthenticator {
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+        client: SocketAddr,
+    ) -> Option<Identity> {
+        // A newline would shift the password into the username line
+        if username.contains('\n') || password.contains('\n') {
+            debug!("Credentials with a newline are refused");
+            return None;
+        }
+
+        match self.run(username, password, client).await {
+            Ok(granted) => granted.then(|| Identity::new(username)),
+            Err(e) => {
+                error!("Can't run authentication command {:?}: {}", self.program, e);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Subjects and rejected addresses kept at most, as clients choose usernames freely
+const MAX_RECORDS: usize = 65536;
+
+/// When failed authentication gets a client address or a username banned
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BanPolicy {
+    /// Failures within `window` which get a ban, 0 never bans
+    pub max_failures: usize,
+    /// Failures older than this are forgotten, and so are rejected addresses
+    pub window: Duration,
+    /// Length of the first ban, doubled by every further one
+    pub ban: Duration,
+    /// Bans are never longer than this. Past bans are forgotten after as long without one.
+    pub max_ban: Duration,
+    /// Ban usernames as well. Anyone can then lock a user out by guessing their password.
+    pub ban_users: bool,
+}
+
+impl Default for BanPolicy {
+    fn default() -> Self {
+        BanPolicy {
+            max_failures: 5,
+            window: Duration::from_secs(600),
+            ban: Duration::from_secs(60),
+            max_ban: Duration::from_secs(24 * 60 * 60),
+            ban_users: false,
+        }
+    }
+}
+
+/// What failed authentication is counted against
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Subject {
+    /// Client address, banned clients are dropped right after accept
+    Address(IpAddr),
+    /// Username, which can't log in from anywhere while banned. Only with `ban_users`.
+    User(String),
+}
+
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Subject::Address(ip) => write!(f, "{}", ip),
+            Subject::User(username) => write!(f, "user {}", username),
+        }
+    }
+}
+
+/// Running ban
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ban {
+    pub subject: Subject,
+    /// Time left until the ban expires
+    pub remaining: Duration,
+    /// Bans of the subject in a row, including this one
+    pub strikes: u32,
+}
+
+#[derive(Default)]
+struct Record {
+    /// Recent failures, oldest first
+    failures: VecDeque<Instant>,
+    /// End of the latest ban
+    banned_until: Option<Instant>,
+    strikes: u32,
+}
+
+impl Record {
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+
+    /// Drop what is too old to matter, returns false once nothing is left
+    fn expire(&mut self, now: Instant, policy: &BanPolicy) -> bool {
+        while self
+            .failures
+            .front()
+            .is_some_and(|failure| now.duration_since(*failure) >= policy.window)
+        {
+            self.failures.pop_front();
+        }
+        if self
+            .banned_until
+            .is_some_and(|until| now.saturating_duration_since(until) >= policy.max_ban)
+        {
+            self.banned_until = None;
+            self.strikes = 0;
+        }
+        !self.failures.is_empty() || self.banned_until.is_some()
+    }
+}
+
+#[derive(Default)]
+struct State {
+    records: HashMap<Subject, Record>,
+    /// Addresses refused by rules or limits, with the time of the latest refusal
+    rejected: HashMap<IpAddr, Instant>,
+}
+
+/// Failed authentication by client address and username, with escalating temporary bans.
+///
+/// Failures are counted in a sliding window. Reaching the limit bans the subject for
+/// twice as long as its previous ban.
+#[derive(Default)]
+pub struct Bans {
+    policy: BanPolicy,
+    state: Mutex<State>,
+}
+
+impl Bans {
+    pub fn new(policy: BanPolicy) -> Self {
+        Bans {
+            policy,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// State stays usable even if a thread panicked while holding it
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Whether `subject` is banned now
+    pub fn is_banned(&self, subject: &Subject) -> bool {
+        let now = Instant::now();
+        self.state()
+            .records
+            .get(subject)
+            .is_some_and(|record| record.is_banned(now))
+    }
+
+    /// Count a failed login of `username` from `ip`
+    pub fn failed(&self, ip: IpAddr, username: &str) {
+        self.fail(Subject::Address(ip.to_canonical()));
+        if self.policy.ban_users {
+            self.fail(Subject::User(username.to_string()));
+        }
+    }
+
+    fn fail(&self, subject: Subject) {
+        if self.policy.max_failures == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut state = self.state();
+        // Records which expired are only dropped once they take up all the room
+        if state.records.len() >= MAX_RECORDS && !state.records.contains_key(&subject) {
+            state
+                .records
+                .retain(|_, record| record.expire(now, &self.policy));
+            if state.records.len() >= MAX_RECORDS {
+                warn!("Too many failed logins recorded, not counting {}", subject);
+                return;
+            }
+        }
+        let record = state.records.entry(subject.clone()).or_default();
+        record.expire(now, &self.policy);
+        if record.is_banned(now) {
+            return;
+        }
+
+        record.failures.push_back(now);
+        if record.failures.len() >= self.policy.max_failures {
+            record.failures.clear();
+            record.strikes += 1;
+            let ban = self
+                .policy
+                .ban
+                .checked_mul(1 << (record.strikes - 1).min(31))
+                .unwrap_or(self.policy.max_ban)
+                .min(self.policy.max_ban);
+            record.banned_until = Some(now + ban);
+            warn!(
+                "Banned {} for {:?} after {} failed logins",
+                subject, ban, self.policy.max_failures
+            );
+        }
+    }
+
+    /// Record that a connection from `ip` was refused by rules or limits
+    pub fn reject(&self, ip: IpAddr) {
+        let now = Instant::now();
+        let mut state = self.state();
+        if state.rejected.len() >= MAX_RECORDS && !state.rejected.contains_key(&ip) {
+            self.expire_rejected(&mut state, now);
+            if state.rejected.len() >= MAX_RECORDS {
+                return;
+            }
+        }
+        state.rejected.insert(ip, now);
+    }
+
+    fn expire_rejected(&self, state: &mut State, now: Instant) {
+        state
+            .rejected
+            .retain(|_, at| now.duration_since(*at) < self.policy.window);
+    }
+
+    /// Addresses refused within the window, in order
+    pub fn rejected(&self) -> Vec<IpAddr> {
+        let mut state = self.state();
+        self.expire_rejected(&mut state, Instant::now());
+        let mut rejected: Vec<IpAddr> = state.rejected.keys().copied().collect();
+        rejected.sort();
+        rejected
+    }
+
+    /// Running bans, by subject
+    pub fn bans(&self) -> Vec<Ban> {
+        let now = Instant::now();
+        let mut bans: Vec<Ban> = self
+            .state()
+            .records
+            .iter()
+            .filter(|(_, record)| record.is_banned(now))
+            .map(|(subject, record)| Ban {
+                subject: subject.clone(),
+                remaining: record.banned_until.unwrap_or(now) - now,
+                strikes: record.strikes,
+            })
+            .collect();
+        bans.sort_by(|a, b| a.subject.cmp(&b.subject));
+        bans
+    }
+
+    /// Lift the ban of `subject` and forget its failures, returns false if nothing was recorded
+    pub fn unban(&self, subject: &Subject) -> bool {
+        self.state().records.remove(subject).is_some()
+    }
+
+    /// Lift all bans and forget all failures and rejected addresses
+    pub fn clear(&self) {
+        let mut state = self.state();
+        state.records.clear();
+        state.rejected.clear();
+    }
+}
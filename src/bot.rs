@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use merino::{parse_net, Ban, Bans, CloseReason, IpNet, IpSet, QuotaUsage, Quotas, Stats, Subject};
 use std::error::Error;
 use std::fs::OpenOptions;
 use std::io::Write;
@@ -13,25 +13,85 @@ use tokio_stream::wrappers::UnboundedReceiverStream;
 enum Command {
     #[command(description = "display this text.")]
     Help,
-    #[command(description = "show all rejected addresses")]
+    #[command(description = "show recently rejected addresses")]
     Rejected,
+    #[command(description = "show banned addresses and users")]
+    Bans,
+    #[command(description = "lift the ban of an address or a user")]
+    Unban(String),
+    #[command(description = "lift all bans")]
+    ClearBans,
     #[command(description = "show all whitelisted addresses")]
     Whitelist,
-    #[command(description = "add ip to whitelist.")]
+    #[command(description = "add ip or network in CIDR notation to whitelist.")]
     Add(String),
+    #[command(description = "show statistics of finished sessions")]
+    Stats,
+    #[command(description = "show traffic and quotas of users")]
+    Quotas,
+    #[command(description = "start the quota period of a user over")]
+    ResetQuota(String),
+}
+
+fn format_stats(stats: &Stats) -> String {
+    let closed = |reason| stats.closed.get(&reason).copied().unwrap_or_default();
+    format!(
+        "Sessions: {}\nUp: {} bytes\nDown: {} bytes\nClosed by peer EOF: {}\nClosed as idle: {}\nClosed by lifetime: {}\nClosed by quota: {}\nClosed by error: {}",
+        stats.sessions,
+        stats.upload,
+        stats.download,
+        closed(CloseReason::Eof),
+        closed(CloseReason::Idle),
+        closed(CloseReason::Lifetime),
+        closed(CloseReason::Quota),
+        closed(CloseReason::Error),
+    )
+}
+
+fn format_quota_usage(usage: &QuotaUsage) -> String {
+    let quota = match usage.quota {
+        Some(quota) => format!("{} bytes", quota.bytes),
+        None => "unlimited".to_string(),
+    };
+    format!(
+        "{}: {} of {} bytes this {} (since {})\n",
+        usage.username, usage.bytes, quota, usage.period, usage.start
+    )
+}
+
+fn format_ban(ban: &Ban) -> String {
+    format!(
+        "{}: {}s left, ban {} in a row\n",
+        ban.subject,
+        ban.remaining.as_secs(),
+        ban.strikes
+    )
+}
+
+/// Addresses are banned as such, anything else is a username
+fn parse_subject(s: &str) -> Subject {
+    match s.parse::<IpAddr>() {
+        Ok(ip) => Subject::Address(ip.to_canonical()),
+        Err(_) => Subject::User(s.to_string()),
+    }
+}
+
+/// Single addresses are shown without the prefix length
+fn format_net(net: &IpNet) -> String {
+    if net.prefix_len() == net.max_prefix_len() {
+        net.addr().to_string()
+    } else {
+        net.to_string()
+    }
 }
 
 fn add_ip_to_whitelist(
-    ip: IpAddr,
-    whitelist: Arc<RwLock<HashSet<IpAddr>>>,
+    net: IpNet,
+    whitelist: Arc<RwLock<IpSet>>,
     whitelist_file: Arc<Path>,
 ) -> String {
-    let contains = {
-        let mut whitelist = whitelist.write().unwrap();
-        let contains = whitelist.contains(&ip);
-        whitelist.insert(ip);
-        contains
-    };
+    let ip = format_net(&net.trunc());
+    let contains = !whitelist.write().unwrap().insert(net);
 
     if contains {
         format!("IP {} is already in whitelist", ip)
@@ -42,10 +102,10 @@ fn add_ip_to_whitelist(
                     let message = if file.metadata().unwrap().len() > 0 {
                         format!("\n{}", ip)
                     } else {
-                        format!("{}", ip)
+                        ip.clone()
                     };
 
-                    match file.write(message.as_bytes()) {
+                    match file.write_all(message.as_bytes()) {
                         Ok(_) => format!("IP {} is added to whitelist", ip),
                         Err(e) => format!(
                             "IP {} is added to whitelist, but not saved to file, because: {:?}",
@@ -69,8 +129,10 @@ fn add_ip_to_whitelist(
 /// or not, then match the command.
 async fn message_handler(
     cx: UpdateWithCx<AutoSend<Bot>, Message>,
-    whitelist: Arc<RwLock<HashSet<IpAddr>>>,
-    rejected_addresses: Arc<RwLock<HashSet<IpAddr>>>,
+    whitelist: Arc<RwLock<IpSet>>,
+    bans: Arc<Bans>,
+    stats: Arc<RwLock<Stats>>,
+    quotas: Arc<Quotas>,
     whitelist_file: Arc<Path>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     if let Some(text) = cx.update.text() {
@@ -80,33 +142,75 @@ async fn message_handler(
                 Command::descriptions()
             }
             Ok(Command::Rejected) => {
+                let rejected = bans.rejected();
                 format!(
                     "There are {} rejected addresses:\n{}",
-                    rejected_addresses.read().unwrap().len(),
-                    rejected_addresses
-                        .read()
-                        .unwrap()
+                    rejected.len(),
+                    rejected
                         .iter()
                         .map(|a| format!("{}\n", a))
                         .collect::<String>()
                 )
             }
+            Ok(Command::Bans) => {
+                let running = bans.bans();
+                format!(
+                    "There are {} bans:\n{}",
+                    running.len(),
+                    running.iter().map(format_ban).collect::<String>()
+                )
+            }
+            Ok(Command::Unban(subject)) => {
+                let subject = parse_subject(subject.trim());
+                if bans.unban(&subject) {
+                    info!("Ban of {} is lifted", subject);
+                    format!("Ban of {} is lifted", subject)
+                } else {
+                    format!("{} is not banned", subject)
+                }
+            }
+            Ok(Command::ClearBans) => {
+                bans.clear();
+                info!("All bans are lifted");
+                "All bans are lifted".to_string()
+            }
             Ok(Command::Whitelist) => {
                 format!(
-                    "There are {} addresses in whitelist:\n{}",
+                    "There are {} entries in whitelist:\n{}",
                     whitelist.read().unwrap().len(),
                     whitelist
                         .read()
                         .unwrap()
                         .iter()
-                        .map(|a| format!("{}\n", a))
+                        .map(|net| format!("{}\n", format_net(net)))
                         .collect::<String>()
                 )
             }
-            Ok(Command::Add(ip)) => match ip.trim().parse::<IpAddr>() {
-                Ok(ip) => add_ip_to_whitelist(ip, whitelist, whitelist_file),
+            Ok(Command::Add(ip)) => match parse_net(ip.trim()) {
+                Ok(net) => add_ip_to_whitelist(net, whitelist, whitelist_file),
                 Err(e) => format!("IP cannot be parsed: {}", e),
             },
+            Ok(Command::Stats) => format_stats(&stats.read().unwrap()),
+            Ok(Command::Quotas) => {
+                let usage = quotas.usage();
+                format!(
+                    "There are {} users with traffic:\n{}",
+                    usage.len(),
+                    usage.iter().map(format_quota_usage).collect::<String>()
+                )
+            }
+            Ok(Command::ResetQuota(username)) => {
+                let username = username.trim();
+                if quotas.reset(username) {
+                    info!("Quota of user {} is reset", username);
+                    if let Err(e) = quotas.save() {
+                        error!("Can't save quotas: {}", e);
+                    }
+                    format!("Quota of user {} is reset", username)
+                } else {
+                    format!("User {} has no traffic", username)
+                }
+            }
 
             Err(_) => "Command not found!".to_string(),
         };
@@ -118,8 +222,10 @@ async fn message_handler(
 }
 
 pub async fn start_bot(
-    whitelist: Arc<RwLock<HashSet<IpAddr>>>,
-    rejected_addresses: Arc<RwLock<HashSet<IpAddr>>>,
+    whitelist: Arc<RwLock<IpSet>>,
+    bans: Arc<Bans>,
+    stats: Arc<RwLock<Stats>>,
+    quotas: Arc<Quotas>,
     whitelist_file: Arc<Path>,
 ) {
     let bot = Bot::from_env().auto_send();
@@ -130,10 +236,12 @@ pub async fn start_bot(
         .messages_handler(|rx: DispatcherHandlerRx<AutoSend<Bot>, Message>| {
             UnboundedReceiverStream::new(rx).for_each_concurrent(None, move |cx| {
                 let whitelist = whitelist.clone();
-                let rejected = rejected_addresses.clone();
+                let bans = bans.clone();
+                let stats = stats.clone();
+                let quotas = quotas.clone();
                 let whitelist_file = whitelist_file.clone();
                 async move {
-                    message_handler(cx, whitelist, rejected, whitelist_file)
+                    message_handler(cx, whitelist, bans, stats, quotas, whitelist_file)
                         .await
                         .log_on_error()
                         .await;
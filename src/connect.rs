@@ -0,0 +1,286 @@
+use crate::egress::Egress;
+use crate::resolver::Resolver;
+use async_trait::async_trait;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+/// Delay between starting connection attempts, recommended by rfc 8305 (S8)
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Destination of an outbound connection
+#[derive(Clone, Debug, PartialEq)]
+pub enum Target {
+    Addr(SocketAddr),
+    /// Domain resolved by whoever connects to it
+    Domain(String, u16),
+}
+
+impl Target {
+    /// Domains which are IP addresses are taken as such
+    pub(crate) fn new(host: &str, port: u16) -> Self {
+        match host.parse::<IpAddr>() {
+            Ok(ip) => Target::Addr(SocketAddr::new(ip, port)),
+            Err(_) => Target::Domain(host.to_string(), port),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            Target::Addr(addr) => addr.port(),
+            Target::Domain(_, port) => *port,
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Addr(addr) => write!(f, "{}", addr),
+            Target::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+        }
+    }
+}
+
+/// Parsed from `HOST:PORT`, IPv6 addresses in brackets
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("port of {} is missing", s))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("host of {} is missing", s));
+        }
+        let port = port
+            .parse()
+            .map_err(|e| format!("port of {} cannot be parsed: {}", s, e))?;
+        Ok(Target::new(host, port))
+    }
+}
+
+/// Byte stream of an outbound connection
+pub trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncStream for T {}
+
+/// Outbound connection made by a [`Connector`]
+pub struct Outbound {
+    pub stream: Box<dyn AsyncStream>,
+    /// Local address of the connection, sent to clients as BND.ADDR. Unspecified if `None`.
+    pub local_addr: Option<SocketAddr>,
+}
+
+impl Outbound {
+    /// Connection over any stream, like an in-memory pipe
+    pub fn new(stream: impl AsyncStream + 'static) -> Self {
+        Outbound {
+            stream: Box::new(stream),
+            local_addr: None,
+        }
+    }
+}
+
+impl From<TcpStream> for Outbound {
+    fn from(stream: TcpStream) -> Self {
+        let local_addr = stream.local_addr().ok();
+        Outbound {
+            stream: Box::new(stream),
+            local_addr,
+        }
+    }
+}
+
+/// What a [`Connector`] knows about the request besides its destination
+pub struct ConnectContext<'a> {
+    /// Client which sent the request
+    pub client: SocketAddr,
+    /// Authenticated user
+    pub user: Option<&'a str>,
+    /// Addresses of the target which passed the rules, the permissions of the user and
+    /// the private filter. Empty if the target was not resolved, because the connector
+    /// resolves domains remotely.
+    pub addrs: &'a [SocketAddr],
+    pub resolver: &'a Resolver,
+    pub ip_family: IpFamily,
+    /// Source addresses and interface for connections
+    pub egress: &'a Egress,
+}
+
+/// Makes the outbound connections of CONNECT requests.
+///
+/// Connections are limited by the connect timeout, errors are sent to the client as the
+/// closest reply code.
+#[async_trait]
+pub trait Connector: Send + Sync {
+    async fn connect(&self, target: &Target, context: &ConnectContext<'_>) -> io::Result<Outbound>;
+
+    /// Whether domains are resolved by the connector, like in another network. They are then
+    /// checked by name only, without the private filter, and passed on without addresses.
+    fn resolves_remotely(&self) -> bool {
+        false
+    }
+}
+
+/// Connects over TCP to the checked addresses, racing them as described in rfc 8305
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DirectConnector;
+
+#[async_trait]
+impl Connector for DirectConnector {
+    async fn connect(&self, target: &Target, context: &ConnectContext<'_>) -> io::Result<Outbound> {
+        let resolved;
+        let addrs = match target {
+            _ if !context.addrs.is_empty() => context.addrs,
+            Target::Addr(addr) => std::slice::from_ref(addr),
+            Target::Domain(domain, port) => {
+                resolved = context.resolver.lookup(domain, *port).await?;
+                &resolved
+            }
+        };
+        trace!("Connecting to: {:?}", addrs);
+        Ok(happy_eyeballs(addrs, context).await?.into())
+    }
+}
+
+/// Address families used for outbound connections
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum IpFamily {
+    /// Try IPv6 first, then IPv4
+    #[default]
+    PreferIpv6,
+    /// Try IPv4 first, then IPv6
+    PreferIpv4,
+    /// Only connect over IPv4
+    Ipv4,
+    /// Only connect over IPv6
+    Ipv6,
+}
+
+impl FromStr for IpFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prefer-ipv6" => Ok(IpFamily::PreferIpv6),
+            "prefer-ipv4" => Ok(IpFamily::PreferIpv4),
+            "ipv4" => Ok(IpFamily::Ipv4),
+            "ipv6" => Ok(IpFamily::Ipv6),
+            _ => Err(format!(
+                "{}: expected one of prefer-ipv6, prefer-ipv4, ipv4, ipv6",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for IpFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            IpFamily::PreferIpv6 => "prefer-ipv6",
+            IpFamily::PreferIpv4 => "prefer-ipv4",
+            IpFamily::Ipv4 => "ipv4",
+            IpFamily::Ipv6 => "ipv6",
+        };
+        f.write_str(name)
+    }
+}
+
+impl IpFamily {
+    /// Order addresses for connection attempts.
+    ///
+    /// Addresses of a forced family are dropped, the others are interleaved
+    /// starting with the preferred family, as described in rfc 8305 (S4).
+    pub fn sort(&self, addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+        let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+            addrs.iter().partition(|addr| addr.is_ipv6());
+
+        let (preferred, other) = match self {
+            IpFamily::PreferIpv6 => (v6, v4),
+            IpFamily::PreferIpv4 => (v4, v6),
+            IpFamily::Ipv4 => (v4, Vec::new()),
+            IpFamily::Ipv6 => (v6, Vec::new()),
+        };
+
+        let mut sorted = Vec::with_capacity(preferred.len() + other.len());
+        let mut preferred = preferred.into_iter();
+        let mut other = other.into_iter();
+        loop {
+            match (preferred.next(), other.next()) {
+                (None, None) => break,
+                (first, second) => sorted.extend(first.into_iter().chain(second)),
+            }
+        }
+        sorted
+    }
+}
+
+/// Connect to the first of `addrs` that answers, racing attempts as described in rfc 8305.
+///
+/// Attempts are started one after another, each after the previous one failed
+/// or after `CONNECTION_ATTEMPT_DELAY`. Attempts still running when one succeeds are cancelled.
+pub(crate) async fn happy_eyeballs(
+    addrs: &[SocketAddr],
+    context: &ConnectContext<'_>,
+) -> io::Result<TcpStream> {
+    let family = context.ip_family;
+    let mut pending: VecDeque<SocketAddr> = family.sort(addrs).into();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    loop {
+        if attempts.is_empty() {
+            match pending.pop_front() {
+                Some(addr) => attempts.push(attempt(addr, context)),
+                None => {
+                    return Err(last_error.unwrap_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::HostUnreachable,
+                            format!("no {} addresses in {:?}", family, addrs),
+                        )
+                    }))
+                }
+            }
+        }
+
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    debug!("Connection attempt failed: {}", e);
+                    last_error = Some(e);
+                    // Don't wait for the delay after a failure
+                    if let Some(addr) = pending.pop_front() {
+                        attempts.push(attempt(addr, context));
+                    }
+                }
+            },
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if !pending.is_empty() => {
+                if let Some(addr) = pending.pop_front() {
+                    trace!("Starting next connection attempt to {}", addr);
+                    attempts.push(attempt(addr, context));
+                }
+            }
+        }
+    }
+}
+
+/// Single connection attempt, from the source chosen by the egress of `context`
+async fn attempt(addr: SocketAddr, context: &ConnectContext<'_>) -> io::Result<TcpStream> {
+    trace!("Connecting to {}", addr);
+    let socket = context
+        .egress
+        .socket(&addr, context.user, context.client.ip())?;
+    socket
+        .connect(addr)
+        .await
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", addr, e)))
+}
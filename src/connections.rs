@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Caps on concurrent connections, `None` is unlimited
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ConnectionLimits {
+    /// Connections of all clients
+    pub total: Option<usize>,
+    /// Connections from a client address
+    pub per_ip: Option<usize>,
+    /// Sessions of a user, counted once the user is known
+    pub per_user: Option<usize>,
+}
+
+/// Open connections by client address and by user
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    ips: HashMap<IpAddr, usize>,
+    users: HashMap<String, usize>,
+}
+
+/// Add one to the count of `key` if it's below `limit`
+fn increment<K: Eq + Hash>(counts: &mut HashMap<K, usize>, key: K, limit: Option<usize>) -> bool {
+    let count = counts.get(&key).copied().unwrap_or_default();
+    if limit.is_some_and(|limit| count >= limit) {
+        return false;
+    }
+    counts.insert(key, count + 1);
+    true
+}
+
+fn decrement<K: Eq + Hash>(counts: &mut HashMap<K, usize>, key: &K) {
+    if let Some(count) = counts.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
+}
+
+/// Counts concurrent connections against the limits
+#[derive(Default)]
+pub(crate) struct Connections {
+    limits: ConnectionLimits,
+    counts: Mutex<Counts>,
+}
+
+impl Connections {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        Connections {
+            limits,
+            counts: Mutex::new(Counts::default()),
+        }
+    }
+
+    /// Counts stay usable even if a thread panicked while holding them
+    fn counts(&self) -> MutexGuard<'_, Counts> {
+        self.counts.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Count a connection from `ip`, `None` if the total or the per address limit is reached.
+    /// The connection is counted until the guard is dropped.
+    pub fn client(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionGuard> {
+        let ip = ip.to_canonical();
+        let mut counts = self.counts();
+        if self.limits.total.is_some_and(|total| counts.total >= total) {
+            return None;
+        }
+        if !increment(&mut counts.ips, ip, self.limits.per_ip) {
+            return None;
+        }
+        counts.total += 1;
+
+        Some(ConnectionGuard {
+            connections: self.clone(),
+            key: Key::Client(ip),
+        })
+    }
+
+    /// Count a session of `username`, `None` if the per user limit is reached
+    pub fn user(self: &Arc<Self>, username: &str) -> Option<ConnectionGuard> {
+        let mut counts = self.counts();
+        if !increment(
+            &mut counts.users,
+            username.to_string(),
+            self.limits.per_user,
+        ) {
+            return None;
+        }
+
+        Some(ConnectionGuard {
+            connections: self.clone(),
+            key: Key::User(username.to_string()),
+        })
+    }
+}
+
+enum Key {
+    Client(IpAddr),
+    User(String),
+}
+
+/// Releases a counted connection when dropped, also when the task panics or is aborted
+pub(crate) struct ConnectionGuard {
+    connections: Arc<Connections>,
+    key: Key,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut counts = self.connections.counts();
+        match &self.key {
+            Key::Client(ip) => {
+                counts.total -= 1;
+                decrement(&mut counts.ips, ip);
+            }
+            Key::User(username) => decrement(&mut counts.users, username),
+        }
+    }
+}
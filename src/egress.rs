@@ -0,0 +1,204 @@
+use rand::Rng;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::net::{TcpSocket, UdpSocket};
+
+/// How the source address of a connection is taken from several
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Rotation {
+    /// Each address in turn
+    #[default]
+    RoundRobin,
+    Random,
+    /// The same address for all connections of a user, clients without a user are sticky
+    /// by their address
+    StickyUser,
+    /// The same address for all connections of a client address
+    StickyClient,
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Rotation::RoundRobin),
+            "random" => Ok(Rotation::Random),
+            "sticky-user" => Ok(Rotation::StickyUser),
+            "sticky-client" => Ok(Rotation::StickyClient),
+            _ => Err(format!(
+                "{}: expected one of round-robin, random, sticky-user, sticky-client",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Rotation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Rotation::RoundRobin => "round-robin",
+            Rotation::Random => "random",
+            Rotation::StickyUser => "sticky-user",
+            Rotation::StickyClient => "sticky-client",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Source of outbound connections and relayed datagrams
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EgressConfig {
+    /// Local addresses to connect from. IPv4 and IPv6 addresses are separate pools,
+    /// connections of a family without addresses get theirs from the kernel.
+    pub addrs: Vec<IpAddr>,
+    /// Interface to send connections through with `SO_BINDTODEVICE`, Linux only
+    pub device: Option<String>,
+    pub rotation: Rotation,
+}
+
+/// Source addresses of one family
+#[derive(Debug, Default)]
+struct SourcePool {
+    addrs: Vec<IpAddr>,
+    /// Turn of round robin
+    next: AtomicUsize,
+}
+
+impl SourcePool {
+    fn new(addrs: Vec<IpAddr>) -> Self {
+        SourcePool {
+            addrs,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn select(&self, rotation: Rotation, user: Option<&str>, client: IpAddr) -> Option<IpAddr> {
+        let len = self.addrs.len();
+        let index = match (rotation, user) {
+            _ if len <= 1 => 0,
+            (Rotation::RoundRobin, _) => self.next.fetch_add(1, Ordering::Relaxed) % len,
+            (Rotation::Random, _) => rand::thread_rng().gen_range(0..len),
+            (Rotation::StickyUser, Some(user)) => sticky(user, len),
+            (Rotation::StickyUser, None) | (Rotation::StickyClient, _) => sticky(client, len),
+        };
+        self.addrs.get(index).copied()
+    }
+}
+
+/// Index of `key` among `len` addresses, the same across restarts
+fn sticky(key: impl Hash, len: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize % len
+}
+
+/// Binds outbound connections and datagram sockets to their source address and interface
+#[derive(Debug, Default)]
+pub struct Egress {
+    v4: SourcePool,
+    v6: SourcePool,
+    device: Option<String>,
+    rotation: Rotation,
+}
+
+impl Egress {
+    pub fn new(config: EgressConfig) -> Self {
+        let (v6, v4) = config.addrs.into_iter().partition(IpAddr::is_ipv6);
+        Egress {
+            v4: SourcePool::new(v4),
+            v6: SourcePool::new(v6),
+            device: config.device,
+            rotation: config.rotation,
+        }
+    }
+
+    /// Source address for a connection of `client` and `user` to `dest`.
+    /// `None` leaves it to the kernel.
+    pub fn source(&self, dest: &SocketAddr, user: Option<&str>, client: IpAddr) -> Option<IpAddr> {
+        let pool = match dest {
+            SocketAddr::V4(_) => &self.v4,
+            SocketAddr::V6(_) => &self.v6,
+        };
+        pool.select(self.rotation, user, client)
+    }
+
+    /// Socket for a connection to `dest`, bound to its source
+    pub(crate) fn socket(
+        &self,
+        dest: &SocketAddr,
+        user: Option<&str>,
+        client: IpAddr,
+    ) -> io::Result<TcpSocket> {
+        let socket = match dest {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        if let Some(device) = &self.device {
+            bind_device(&socket, device)?;
+        }
+        if let Some(source) = self.source(dest, user, client) {
+            socket
+                .bind(SocketAddr::new(source, 0))
+                .map_err(|e| io::Error::new(e.kind(), format!("binding to {}: {}", source, e)))?;
+        }
+        Ok(socket)
+    }
+
+    /// Socket for datagrams to targets of the family of the `unspecified` address,
+    /// bound to their source
+    pub(crate) async fn udp_socket(
+        &self,
+        unspecified: IpAddr,
+        user: Option<&str>,
+        client: IpAddr,
+    ) -> io::Result<UdpSocket> {
+        let source = self
+            .source(&SocketAddr::new(unspecified, 0), user, client)
+            .unwrap_or(unspecified);
+        let socket = UdpSocket::bind(SocketAddr::new(source, 0))
+            .await
+            .map_err(|e| io::Error::new(e.kind(), format!("binding to {}: {}", source, e)))?;
+        if let Some(device) = &self.device {
+            bind_udp_device(&socket, device)?;
+        }
+        Ok(socket)
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &TcpSocket, device: &str) -> io::Result<()> {
+    socket
+        .bind_device(Some(device.as_bytes()))
+        .map_err(|e| io::Error::new(e.kind(), format!("binding to {}: {}", device, e)))
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_udp_device(socket: &UdpSocket, device: &str) -> io::Result<()> {
+    socket
+        .bind_device(Some(device.as_bytes()))
+        .map_err(|e| io::Error::new(e.kind(), format!("binding to {}: {}", device, e)))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_socket: &TcpSocket, device: &str) -> io::Result<()> {
+    Err(unsupported(device))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_udp_device(_socket: &UdpSocket, device: &str) -> io::Result<()> {
+    Err(unsupported(device))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn unsupported(device: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("can't bind to {} on this platform", device),
+    )
+}
@@ -0,0 +1,160 @@
+use ipnet::{IpNet, Ipv4Net};
+use std::collections::BTreeSet;
+use std::iter::FromIterator;
+use std::net::IpAddr;
+
+/// Binary trie of network prefixes
+#[derive(Clone, Debug, Default)]
+struct Trie {
+    nodes: Vec<Node>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Node {
+    children: [Option<usize>; 2],
+    /// A prefix ends here, so everything below matches
+    terminal: bool,
+}
+
+/// Bit `i` of an address `width` bits wide, counting from the most significant one
+fn bit(bits: u128, width: u8, i: u8) -> usize {
+    ((bits >> (width - 1 - i)) & 1) as usize
+}
+
+impl Trie {
+    fn insert(&mut self, bits: u128, prefix_len: u8, width: u8) {
+        if self.nodes.is_empty() {
+            self.nodes.push(Node::default());
+        }
+
+        let mut node = 0;
+        for i in 0..prefix_len {
+            // Already covered by a shorter prefix
+            if self.nodes[node].terminal {
+                return;
+            }
+            let bit = bit(bits, width, i);
+            node = match self.nodes[node].children[bit] {
+                Some(child) => child,
+                None => {
+                    self.nodes.push(Node::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children[bit] = Some(child);
+                    child
+                }
+            };
+        }
+        self.nodes[node].terminal = true;
+    }
+
+    fn contains(&self, bits: u128, width: u8) -> bool {
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let mut node = 0;
+        for i in 0..width {
+            if self.nodes[node].terminal {
+                return true;
+            }
+            match self.nodes[node].children[bit(bits, width, i)] {
+                Some(child) => node = child,
+                None => return false,
+            }
+        }
+        self.nodes[node].terminal
+    }
+}
+
+/// Set of IPv4 and IPv6 networks.
+///
+/// Lookups walk a prefix trie, so they take at most 32 or 128 steps regardless of the size.
+/// IPv4-mapped IPv6 addresses match IPv4 networks.
+#[derive(Clone, Debug, Default)]
+pub struct IpSet {
+    v4: Trie,
+    v6: Trie,
+    nets: BTreeSet<IpNet>,
+}
+
+impl IpSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a network, host bits are ignored. Returns false if it was already added.
+    pub fn insert(&mut self, net: IpNet) -> bool {
+        let net = canonical(net.trunc());
+        if !self.nets.insert(net) {
+            return false;
+        }
+
+        match net {
+            IpNet::V4(net) => self
+                .v4
+                .insert(u32::from(net.addr()) as u128, net.prefix_len(), 32),
+            IpNet::V6(net) => self
+                .v6
+                .insert(u128::from(net.addr()), net.prefix_len(), 128),
+        }
+        true
+    }
+
+    /// Whether `ip` belongs to any of the networks
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => self.v4.contains(u32::from(ip) as u128, 32),
+            IpAddr::V6(ip) => self.v6.contains(u128::from(ip), 128),
+        }
+    }
+
+    /// Number of added networks
+    pub fn len(&self) -> usize {
+        self.nets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nets.is_empty()
+    }
+
+    /// Added networks, in order
+    pub fn iter(&self) -> impl Iterator<Item = &IpNet> {
+        self.nets.iter()
+    }
+}
+
+impl PartialEq for IpSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.nets == other.nets
+    }
+}
+
+impl FromIterator<IpNet> for IpSet {
+    fn from_iter<I: IntoIterator<Item = IpNet>>(iter: I) -> Self {
+        let mut set = IpSet::new();
+        for net in iter {
+            set.insert(net);
+        }
+        set
+    }
+}
+
+/// IPv4-mapped IPv6 networks are stored as IPv4
+fn canonical(net: IpNet) -> IpNet {
+    match net {
+        IpNet::V6(v6) if v6.prefix_len() >= 96 => match v6.addr().to_ipv4_mapped() {
+            Some(v4) => Ipv4Net::new(v4, v6.prefix_len() - 96)
+                .map(IpNet::V4)
+                .unwrap_or(net),
+            None => net,
+        },
+        _ => net,
+    }
+}
+
+/// Parse a network in CIDR notation or a single address
+pub fn parse_net(s: &str) -> Result<IpNet, String> {
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("{} is not an IP address or network", s))
+}
@@ -4,10 +4,10 @@ extern crate serde_derive;
 extern crate log;
 use snafu::Snafu;
 
-use std::collections::HashSet;
+use std::convert::TryFrom;
 use std::fs::OpenOptions;
 use std::io::{self, BufRead, BufReader};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::RwLock;
@@ -15,18 +15,146 @@ use std::time::Duration;
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpListener;
+use tokio::time::timeout;
 
 mod auth;
+mod authenticator;
+mod bans;
+mod connect;
+mod connections;
+mod egress;
+mod ipset;
+mod password;
+mod permissions;
+mod pool;
+mod private;
+mod quota;
+mod relay;
+mod reload;
+mod resolver;
+mod routes;
+pub mod rules;
+mod shaper;
+mod socks4;
+mod udp;
+mod upstream;
+
+pub use authenticator::{
+    Authenticator, CommandAuthenticator, CsvAuthenticator, Identity, MemoryAuthenticator,
+};
+pub use bans::{Ban, BanPolicy, Bans, Subject};
+pub use connect::{
+    AsyncStream, ConnectContext, Connector, DirectConnector, IpFamily, Outbound, Target,
+    CONNECTION_ATTEMPT_DELAY,
+};
+pub use connections::ConnectionLimits;
+pub use egress::{Egress, EgressConfig, Rotation};
+pub use ipnet::IpNet;
+pub use ipset::{parse_net, IpSet};
+pub use password::{hash_password, HashAlgorithm};
+pub use permissions::Permissions;
+pub use pool::{HealthCheck, MemberStatus, Pool, PoolConfig, PoolOptions, Strategy};
+pub use private::PrivateFilter;
+pub use quota::{Period, Quota, QuotaSession, QuotaUsage, Quotas};
+pub use relay::{CloseReason, SessionStats, Stats};
+pub use reload::Reloader;
+pub use resolver::{Resolver, DEFAULT_DNS_TIMEOUT, DEFAULT_HOSTS_FILE};
+pub use routes::{Gateway, Route, Router, Routes};
+pub use rules::Rules;
+pub use shaper::{parse_rate, Bandwidth, BandwidthLimits, SessionShaper, Shaper, TokenBucket};
+pub use socks4::{Socks4Code, Socks4Reply};
+pub use upstream::{Protocol, Proxy, Upstream};
 
 /// Version of socks
 pub const SOCKS_VERSION: u8 = 0x05;
 
+/// Version of socks4 and socks4a
+pub const SOCKS4_VERSION: u8 = 0x04;
+
 const RESERVED: u8 = 0x00;
 
 #[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "UserRecord")]
 pub struct User {
     pub username: String,
+    /// Hash in PHC format (argon2, scrypt), bcrypt hash or plaintext password
+    password: String,
+    permissions: Permissions,
+}
+
+impl User {
+    /// Create a new user, `password` is either a hash or plaintext
+    pub fn new(username: &str, password: &str) -> Self {
+        User {
+            username: username.to_string(),
+            password: password.to_string(),
+            permissions: Permissions::default(),
+        }
+    }
+
+    /// Check `password` against the stored hash or plaintext password
+    pub fn verify_password(&self, password: &str) -> bool {
+        password::verify(&self.password, password)
+    }
+
+    /// Hash of a fixed password with the algorithm and parameters of the stored hash,
+    /// `None` if the password is stored as plaintext
+    pub(crate) fn dummy_hash(&self) -> Option<String> {
+        password::dummy_hash(&self.password)
+    }
+
+    /// The password is stored as plaintext instead of a hash
+    pub fn has_plaintext_password(&self) -> bool {
+        !password::is_hash(&self.password)
+    }
+
+    pub fn permissions(&self) -> &Permissions {
+        &self.permissions
+    }
+
+    /// Restrict what the user may do. By default everything is allowed.
+    pub fn set_permissions(&mut self, permissions: Permissions) {
+        self.permissions = permissions;
+    }
+}
+
+/// Row of the users file. Permission columns are optional, empty fields allow everything.
+#[derive(Deserialize)]
+struct UserRecord {
+    username: String,
     password: String,
+    enabled: Option<bool>,
+    destinations: Option<String>,
+    commands: Option<String>,
+    sources: Option<String>,
+    quota: Option<String>,
+}
+
+impl TryFrom<UserRecord> for User {
+    type Error = String;
+
+    fn try_from(record: UserRecord) -> Result<Self, Self::Error> {
+        let mut permissions = Permissions::default();
+        if let Some(enabled) = record.enabled {
+            permissions.enabled = enabled;
+        }
+        if let Some(destinations) = &record.destinations {
+            permissions.set_destinations(destinations);
+        }
+        if let Some(commands) = &record.commands {
+            permissions.set_commands(commands)?;
+        }
+        if let Some(sources) = &record.sources {
+            permissions.set_sources(sources)?;
+        }
+        if let Some(quota) = &record.quota {
+            permissions.quota = Some(quota.parse()?);
+        }
+
+        let mut user = User::new(&record.username, &record.password);
+        user.set_permissions(permissions);
+        Ok(user)
+    }
 }
 
 pub struct SocksReply {
@@ -61,10 +189,11 @@ pub struct SocksReply {
     //      o  BND.ADDR       server bound address
     //      o  BND.PORT       server bound port in network octet order
     //
-    buf: [u8; 10],
+    buf: Vec<u8>,
 }
 
 impl SocksReply {
+    /// Create a reply with all-zero IPv4 BND.ADDR and BND.PORT, used when there is no bound socket
     pub fn new(status: ResponseCode) -> Self {
         let buf = [
             // VER
@@ -84,9 +213,22 @@ impl SocksReply {
             0,
             0,
         ];
+        Self { buf: buf.to_vec() }
+    }
+
+    /// Create a reply carrying `addr` as BND.ADDR and BND.PORT.
+    /// ATYP and the length of BND.ADDR follow the address family.
+    pub fn with_addr(status: ResponseCode, addr: SocketAddr) -> Self {
+        let mut buf = vec![SOCKS_VERSION, status as u8, RESERVED];
+        encode_addr(&mut buf, addr);
         Self { buf }
     }
 
+    /// Reply as sent on the wire
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
     pub async fn send<T>(&self, stream: &mut T) -> io::Result<()>
     where
         T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
@@ -103,9 +245,24 @@ pub enum MerinoError {
 
     #[error("Socks error: {0}")]
     Socks(#[from] ResponseCode),
+
+    /// The client was not authenticated and already got its reply
+    #[error("Authentication failed: {0}")]
+    Denied(AuthFailure),
+}
+
+/// Why a client was not authenticated
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum AuthFailure {
+    #[error("invalid credentials of user {0}")]
+    Denied(String),
+    #[error("no acceptable method")]
+    NoAcceptableMethod,
+    #[error("unsupported username/password version {0}")]
+    UnsupportedVersion(u8),
 }
 
-#[derive(Debug, Snafu, PartialEq)]
+#[derive(Debug, Snafu, PartialEq, Clone, Copy)]
 /// Possible SOCKS5 Response Codes
 pub enum ResponseCode {
     Success = 0x00,
@@ -127,18 +284,38 @@ pub enum ResponseCode {
     AddrTypeNotSupported = 0x08,
 }
 
-impl From<MerinoError> for ResponseCode {
-    fn from(e: MerinoError) -> Self {
+impl From<&io::Error> for ResponseCode {
+    fn from(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied => ResponseCode::RuleFailure,
+            io::ErrorKind::NetworkUnreachable => ResponseCode::NetworkUnreachable,
+            io::ErrorKind::HostUnreachable => ResponseCode::HostUnreachable,
+            io::ErrorKind::ConnectionRefused => ResponseCode::ConnectionRefused,
+            io::ErrorKind::TimedOut => ResponseCode::TtlExpired,
+            _ => ResponseCode::Failure,
+        }
+    }
+}
+
+impl From<&MerinoError> for ResponseCode {
+    fn from(e: &MerinoError) -> Self {
         match e {
-            MerinoError::Socks(e) => e,
-            MerinoError::Io(_) => ResponseCode::Failure,
+            MerinoError::Socks(e) => *e,
+            MerinoError::Io(e) => e.into(),
+            MerinoError::Denied(_) => ResponseCode::RuleFailure,
         }
     }
 }
 
+impl From<MerinoError> for ResponseCode {
+    fn from(e: MerinoError) -> Self {
+        (&e).into()
+    }
+}
+
 /// DST.addr variant types
 #[derive(PartialEq)]
-enum AddrType {
+pub(crate) enum AddrType {
     /// IP V4 address: X'01'
     V4 = 0x01,
     /// DOMAINNAME: X'03'
@@ -170,7 +347,7 @@ impl AddrType {
 
 /// SOCK5 CMD Type
 #[derive(Debug)]
-enum SockCommand {
+pub(crate) enum SockCommand {
     Connect = 0x01,
     Bind = 0x02,
     UdpAssosiate = 0x3,
@@ -199,69 +376,302 @@ pub enum AuthMethods {
     NoMethods = 0xFF,
 }
 
+/// Default time limit for version negotiation, authentication and the request
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time a client over the connection limit gets to send its greeting, so it can be refused
+/// the way its SOCKS version expects. Silent clients are closed afterwards.
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Default time limit for outbound connections
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default time after which sessions without traffic are closed
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Time limits for each stage of a session.
+///
+/// DNS lookups are limited by the `Resolver`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timeouts {
+    /// Version negotiation, authentication and reading the request
+    pub handshake: Duration,
+    /// Outbound connections, and the inbound connection of BIND
+    pub connect: Duration,
+    /// Relayed sessions without traffic in either direction are closed. `None` keeps them open.
+    pub idle: Option<Duration>,
+    /// Relayed sessions are closed after this time, regardless of traffic. `None` keeps them open.
+    pub lifetime: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            handshake: DEFAULT_HANDSHAKE_TIMEOUT,
+            connect: DEFAULT_CONNECT_TIMEOUT,
+            idle: Some(DEFAULT_IDLE_TIMEOUT),
+            lifetime: None,
+        }
+    }
+}
+
+/// Settings shared by all client connections
+#[derive(Clone)]
+pub(crate) struct Config {
+    /// Authentication methods offered to clients
+    auth_methods: Vec<u8>,
+    /// Time limits for each stage of a session
+    timeouts: Timeouts,
+    /// Accept SOCKS4 and SOCKS4a clients
+    socks4: bool,
+    /// Check SOCKS4 USERID against the users list
+    socks4_check_userid: bool,
+    /// Resolver for DOMAINNAME requests
+    resolver: Arc<Resolver>,
+    /// Address families for outbound connections
+    ip_family: IpFamily,
+    /// Source addresses and interface of outbound connections
+    egress: Arc<Egress>,
+    /// Credentials for USER/PASS authentication
+    authenticator: Arc<dyn Authenticator>,
+    /// Destination access rules
+    rules: Arc<Rules>,
+    /// Block private and other special-purpose destinations
+    private_filter: Option<Arc<PrivateFilter>>,
+    /// Bandwidth limits shared by sessions
+    shaper: Arc<Shaper>,
+    /// Traffic of users
+    quotas: Arc<Quotas>,
+    /// Concurrent connections by client address and by user
+    connections: Arc<connections::Connections>,
+    /// Failed logins, bans and rejected addresses
+    bans: Arc<Bans>,
+    /// Upstream proxies and pools for CONNECT requests
+    router: Arc<Router>,
+    /// Makes connections which are not routed through an upstream
+    connector: Arc<dyn Connector>,
+}
+
 pub struct Merino {
     listener: TcpListener,
-    users: Arc<Vec<User>>,
-    auth_methods: Arc<Vec<u8>>,
-    /// All addresses, which merino rejected connections
-    rejected_addresses: Arc<RwLock<HashSet<IpAddr>>>,
+    config: Config,
     /// List of addresses, which would always have access to proxy
-    whitelist: Arc<RwLock<HashSet<IpAddr>>>,
+    whitelist: Arc<RwLock<IpSet>>,
     /// Path to the whitelist file
     whitelist_file: Option<PathBuf>,
-    /// Timeout for connections
-    timeout: Option<Duration>,
+    /// Counters of finished sessions
+    stats: Arc<RwLock<Stats>>,
 }
 
 impl Merino {
-    /// Create a new Merino instance
+    /// Create a new Merino instance, resolving domains with the system configuration.
+    ///
+    /// Fails if the system resolver configuration can't be read.
     pub async fn new(
         port: u16,
         ip: &str,
         auth_methods: Vec<u8>,
-        users: Vec<User>,
-        timeout: Option<Duration>,
+        authenticator: Box<dyn Authenticator>,
+        timeouts: Timeouts,
     ) -> io::Result<Self> {
         info!("Listening on {}:{}", ip, port);
+        let listener = TcpListener::bind((ip, port)).await?;
+        // Only local clients can reach private destinations through a loopback listener
+        let private_filter = if listener.local_addr()?.ip().is_loopback() {
+            None
+        } else {
+            Some(Arc::new(PrivateFilter::default()))
+        };
+
         Ok(Merino {
-            listener: TcpListener::bind((ip, port)).await?,
-            auth_methods: Arc::new(auth_methods),
-            rejected_addresses: Arc::new(RwLock::new(HashSet::new())),
-            whitelist: Arc::new(RwLock::new(HashSet::new())),
+            listener,
+            config: Config {
+                auth_methods,
+                timeouts,
+                socks4: true,
+                socks4_check_userid: false,
+                resolver: Arc::new(Resolver::system()?),
+                ip_family: IpFamily::default(),
+                egress: Arc::new(Egress::default()),
+                authenticator: Arc::from(authenticator),
+                rules: Arc::new(Rules::default()),
+                private_filter,
+                shaper: Arc::new(Shaper::default()),
+                quotas: Arc::new(Quotas::new()),
+                connections: Arc::new(connections::Connections::default()),
+                bans: Arc::new(Bans::default()),
+                router: Arc::new(Router::default()),
+                connector: Arc::new(DirectConnector),
+            },
+            whitelist: Arc::new(RwLock::new(IpSet::new())),
             whitelist_file: None,
-            users: Arc::new(users),
-            timeout,
+            stats: Arc::new(RwLock::new(Stats::default())),
         })
     }
 
+    /// Use `resolver` for DOMAINNAME requests instead of the system configuration
+    pub fn set_resolver(&mut self, resolver: Resolver) {
+        self.config.resolver = Arc::new(resolver);
+    }
+
+    /// Select address families for outbound connections
+    pub fn set_ip_family(&mut self, ip_family: IpFamily) {
+        self.config.ip_family = ip_family;
+    }
+
+    /// Connect from the source addresses and interface of `egress`.
+    /// By default the kernel chooses them.
+    pub fn set_egress(&mut self, egress: EgressConfig) {
+        self.config.egress = Arc::new(Egress::new(egress));
+    }
+
+    /// Check destinations of requests against `rules`. By default everything is allowed.
+    pub fn set_rules(&mut self, rules: Rules) {
+        self.config.rules = Arc::new(rules);
+    }
+
+    /// Block private, loopback, link-local and other special-purpose destinations with `filter`,
+    /// `None` allows them. By default they are blocked unless merino listens on a loopback address.
+    pub fn set_private_filter(&mut self, filter: Option<PrivateFilter>) {
+        self.config.private_filter = filter.map(Arc::new);
+    }
+
+    /// Limit the rates of relayed traffic. By default it's unlimited.
+    pub fn set_bandwidth_limits(&mut self, limits: BandwidthLimits) {
+        self.config.shaper = Arc::new(Shaper::new(limits));
+    }
+
+    /// Cap concurrent connections. By default they are unlimited.
+    pub fn set_connection_limits(&mut self, limits: ConnectionLimits) {
+        self.config.connections = Arc::new(connections::Connections::new(limits));
+    }
+
+    /// Make outbound connections of CONNECT requests with `connector` instead of directly.
+    /// Requests routed through an upstream still go there.
+    pub fn set_connector(&mut self, connector: Box<dyn Connector>) {
+        self.config.connector = Arc::from(connector);
+    }
+
+    /// Connect through upstream proxies chosen by `router`. By default all connections are direct.
+    pub fn set_router(&mut self, router: Router) {
+        self.config.router = Arc::new(router);
+    }
+
+    /// Ban clients and usernames after failed logins according to `policy`
+    pub fn set_ban_policy(&mut self, policy: BanPolicy) {
+        self.config.bans = Arc::new(Bans::new(policy));
+    }
+
+    /// Account traffic of users with `quotas`. By default it's only kept in memory.
+    pub fn set_quotas(&mut self, quotas: Quotas) {
+        self.config.quotas = Arc::new(quotas);
+    }
+
+    /// Accept SOCKS4 and SOCKS4a clients on the same listener. Enabled by default.
+    pub fn set_socks4(&mut self, enabled: bool) {
+        self.config.socks4 = enabled;
+    }
+
+    /// Only accept SOCKS4 requests with USERID matching one of the users.
+    ///
+    /// Without this check, SOCKS4 is only served when SOCKS5 would not ask for credentials.
+    pub fn set_socks4_check_userid(&mut self, check: bool) {
+        self.config.socks4_check_userid = check;
+    }
+
+    /// Address the proxy is listening on
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
     pub async fn serve(&mut self) {
         info!("Serving Connections...");
+        let config = Arc::new(self.config.clone());
+        for pool in config.router.pools() {
+            tokio::spawn(pool.clone().check_health(
+                config.resolver.clone(),
+                config.ip_family,
+                config.egress.clone(),
+            ));
+        }
         while let Ok((stream, client_addr)) = self.listener.accept().await {
-            let users = self.users.clone();
-            let auth_methods = self.auth_methods.clone();
-            let timeout = self.timeout;
-            let rejected_addresses = self.rejected_addresses.clone();
+            let config = config.clone();
+            let stats = self.stats.clone();
             let peer_ip = &stream.peer_addr().unwrap().ip();
+            let local_addr = match stream.local_addr() {
+                Ok(addr) => addr,
+                Err(e) => {
+                    warn!("Can't get local address of {}: {}", client_addr, e);
+                    continue;
+                }
+            };
             // On Linux readeres preferred before writers. This shouls also immidiately release the lock.
             // TODO: measure the delay
             let whitelisted = self.whitelist.read().unwrap().contains(peer_ip);
+            if !whitelisted
+                && config
+                    .bans
+                    .is_banned(&Subject::Address(peer_ip.to_canonical()))
+            {
+                debug!("Dropping banned client {}", client_addr);
+                continue;
+            }
+
+            // Released when the task ends, however it ends
+            let guard = config.connections.client(client_addr.ip());
 
             tokio::spawn(async move {
+                let handshake = config.timeouts.handshake;
+                let bans = config.bans.clone();
                 let mut client =
-                    auth::SOCKClient::new(stream, users, auth_methods, whitelisted, timeout);
+                    auth::SOCKClient::new(stream, client_addr, local_addr, config, whitelisted);
+
+                let _guard = match guard {
+                    Some(guard) => guard,
+                    None => {
+                        warn!("Connection limit reached, refusing client {}", client_addr);
+                        bans.reject(client_addr.ip());
+                        let limit = handshake.min(REFUSAL_TIMEOUT);
+                        if let Ok(Err(e)) = timeout(limit, client.refuse()).await {
+                            debug!("Failed to refuse client: {:?}", e);
+                        }
+                        return;
+                    }
+                };
+
                 match client.init().await {
-                    Ok(_) => {}
+                    Ok(Some(session)) => {
+                        info!(
+                            "Session closed: {}, up: {} bytes, down: {} bytes, duration: {:?}, client: {}",
+                            session.reason,
+                            session.upload,
+                            session.download,
+                            session.duration,
+                            client_addr
+                        );
+                        stats.write().unwrap().record(&session);
+                    }
+                    Ok(None) => {}
+                    // The client already got its reply and the stream is shut down
+                    Err(MerinoError::Denied(failure)) => {
+                        warn!(
+                            "Authentication failed: {}, client: {}",
+                            failure, client_addr
+                        );
+                        bans.reject(client_addr.ip());
+                    }
                     Err(error) => {
-                        error!("Error! {:?}, client: {:?}", error, client_addr);
-
-                        if let MerinoError::Socks(e) = &error {
-                            if e == &ResponseCode::RuleFailure {
-                                rejected_addresses.write().unwrap().insert(client_addr.ip());
-                            }
+                        let code = ResponseCode::from(&error);
+                        error!(
+                            "Error! {:?}, reply: {}, client: {:?}",
+                            error, code, client_addr
+                        );
+
+                        if let MerinoError::Socks(ResponseCode::RuleFailure) = &error {
+                            bans.reject(client_addr.ip());
                         }
 
-                        if let Err(e) = SocksReply::new(error.into()).send(&mut client.stream).await
-                        {
+                        if let Err(e) = client.reply(code, None).await {
                             warn!("Failed to send error code: {:?}", e);
                         }
 
@@ -274,13 +684,14 @@ impl Merino {
         }
     }
 
-    pub fn get_whitelist(&self) -> Arc<RwLock<HashSet<IpAddr>>> {
+    pub fn get_whitelist(&self) -> Arc<RwLock<IpSet>> {
         self.whitelist.clone()
     }
 
     pub fn load_whitelist(&mut self, path: &Path) {
         let file = OpenOptions::new()
             .create(true)
+            .truncate(false)
             .read(true)
             .write(true)
             .open(path)
@@ -289,42 +700,82 @@ impl Merino {
                 std::process::exit(1);
             });
 
-        let reader = BufReader::new(file);
-        let mut whitelist = self.whitelist.write().unwrap();
+        let (nets, errors) = parse_whitelist(BufReader::new(file));
+        for error in errors {
+            warn!("{}", error);
+        }
 
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                match line.parse::<IpAddr>() {
-                    Ok(ip) => {
-                        if !whitelist.contains(&ip) {
-                            whitelist.insert(ip);
-                            info!("IP loaded from whitelist file: {}", ip);
-                        }
-                    }
-                    Err(e) => {
-                        warn!("IP {} cannot be parsed: {}", line, e);
-                        continue;
-                    }
-                };
+        let mut whitelist = self.whitelist.write().unwrap();
+        for net in nets.iter() {
+            if whitelist.insert(*net) {
+                info!("Network loaded from whitelist file: {}", net);
             }
         }
 
         self.whitelist_file = Some(path.to_path_buf());
     }
 
-    pub fn get_rejected_addresses(&self) -> Arc<RwLock<HashSet<IpAddr>>> {
-        self.rejected_addresses.clone()
+    /// Bans and rejected addresses, to list and clear them
+    pub fn get_bans(&self) -> Arc<Bans> {
+        self.config.bans.clone()
+    }
+
+    /// Traffic of users, to list and reset quotas
+    pub fn get_quotas(&self) -> Arc<Quotas> {
+        self.config.quotas.clone()
+    }
+
+    /// Counters of finished sessions, by close reason
+    pub fn get_stats(&self) -> Arc<RwLock<Stats>> {
+        self.stats.clone()
     }
+
+    /// Handle to reload the users and the whitelist file while serving.
+    /// Call after `load_whitelist`.
+    pub fn get_reloader(&self) -> Reloader {
+        Reloader::new(
+            self.config.authenticator.clone(),
+            self.whitelist.clone(),
+            self.whitelist_file.clone(),
+        )
+    }
+}
+
+/// Parse a whitelist with an IP or a network in CIDR notation per line.
+/// Returns the networks and errors of other lines. `#` starts a comment.
+pub(crate) fn parse_whitelist(reader: impl BufRead) -> (IpSet, Vec<String>) {
+    let mut nets = IpSet::new();
+    let mut errors = Vec::new();
+
+    for line in reader.lines().map_while(Result::ok) {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        match ipset::parse_net(line) {
+            Ok(net) => {
+                nets.insert(net);
+            }
+            Err(e) => errors.push(e),
+        };
+    }
+
+    (nets, errors)
 }
 
 /// Convert an address and AddrType to a SocketAddr
-fn addr_to_socket(addr_type: &AddrType, addr: &[u8], port: u16) -> io::Result<Vec<SocketAddr>> {
+async fn addr_to_socket(
+    resolver: &Resolver,
+    addr_type: &AddrType,
+    addr: &[u8],
+    port: u16,
+) -> io::Result<Vec<SocketAddr>> {
     match addr_type {
         AddrType::V6 => {
             let new_addr = (0..8)
                 .map(|x| {
                     trace!("{} and {}", x * 2, (x * 2) + 1);
-                    (u16::from(addr[(x * 2)]) << 8) | u16::from(addr[(x * 2) + 1])
+                    (u16::from(addr[x * 2]) << 8) | u16::from(addr[(x * 2) + 1])
                 })
                 .collect::<Vec<u16>>();
 
@@ -349,13 +800,25 @@ fn addr_to_socket(addr_type: &AddrType, addr: &[u8], port: u16) -> io::Result<Ve
             port,
         ))]),
         AddrType::Domain => {
-            let mut domain = String::from_utf8_lossy(addr).to_string();
-            domain.push(':');
-            domain.push_str(&port.to_string());
+            let domain = String::from_utf8_lossy(addr);
+            resolver.lookup(&domain, port).await
+        }
+    }
+}
 
-            Ok(domain.to_socket_addrs()?.collect())
+/// Append ATYP, ADDR and PORT fields for `addr`
+fn encode_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(AddrType::V4 as u8);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(AddrType::V6 as u8);
+            buf.extend_from_slice(&ip.octets());
         }
     }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
 }
 
 /// Convert an AddrType and address to String
@@ -369,7 +832,7 @@ fn pretty_print_addr(addr_type: &AddrType, addr: &[u8]) -> String {
             .join("."),
         AddrType::V6 => {
             let addr_16 = (0..8)
-                .map(|x| (u16::from(addr[(x * 2)]) << 8) | u16::from(addr[(x * 2) + 1]))
+                .map(|x| (u16::from(addr[x * 2]) << 8) | u16::from(addr[(x * 2) + 1]))
                 .collect::<Vec<u16>>();
 
             addr_16
@@ -383,7 +846,7 @@ fn pretty_print_addr(addr_type: &AddrType, addr: &[u8]) -> String {
 
 /// Proxy User Request
 #[allow(dead_code)]
-struct SOCKSReq {
+pub(crate) struct SOCKSReq {
     pub version: u8,
     pub command: SockCommand,
     pub addr_type: AddrType,
@@ -0,0 +1,150 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use scrypt::Scrypt;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use subtle::ConstantTimeEq;
+
+/// Algorithm for new password hashes
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HashAlgorithm {
+    #[default]
+    Argon2id,
+    Bcrypt,
+    Scrypt,
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "argon2id" => Ok(HashAlgorithm::Argon2id),
+            "bcrypt" => Ok(HashAlgorithm::Bcrypt),
+            "scrypt" => Ok(HashAlgorithm::Scrypt),
+            _ => Err(format!(
+                "unknown hash algorithm {}, expected argon2id, bcrypt or scrypt",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HashAlgorithm::Argon2id => "argon2id",
+            HashAlgorithm::Bcrypt => "bcrypt",
+            HashAlgorithm::Scrypt => "scrypt",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Format of a stored password
+#[derive(Debug, PartialEq)]
+enum Stored {
+    /// PHC string of argon2 or scrypt
+    Phc,
+    /// Modular crypt format of bcrypt
+    Bcrypt,
+    Plaintext,
+}
+
+impl Stored {
+    fn detect(stored: &str) -> Self {
+        if stored.starts_with("$argon2") || stored.starts_with("$scrypt$") {
+            Stored::Phc
+        } else if ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| stored.starts_with(prefix))
+        {
+            Stored::Bcrypt
+        } else {
+            Stored::Plaintext
+        }
+    }
+}
+
+/// Hash `password` with a random salt
+pub fn hash_password(password: &str, algorithm: HashAlgorithm) -> io::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    let hash = match algorithm {
+        HashAlgorithm::Argon2id => Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(io::Error::other)?
+            .to_string(),
+        HashAlgorithm::Scrypt => Scrypt
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(io::Error::other)?
+            .to_string(),
+        HashAlgorithm::Bcrypt => {
+            bcrypt::hash(password, bcrypt::DEFAULT_COST).map_err(io::Error::other)?
+        }
+    };
+
+    Ok(hash)
+}
+
+/// Hash of a fixed password with the algorithm and parameters of `stored`, `None` for
+/// plaintext passwords or hashes which can't be parsed
+pub(crate) fn dummy_hash(stored: &str) -> Option<String> {
+    const PASSWORD: &[u8] = b"merino-dummy-password";
+
+    match Stored::detect(stored) {
+        Stored::Phc => {
+            let hash = PasswordHash::new(stored).ok()?;
+            let salt = SaltString::encode_b64(b"merino-dummy-salt").ok()?;
+            let dummy = if hash.algorithm == scrypt::ALG_ID {
+                let params = scrypt::Params::try_from(&hash).ok()?;
+                Scrypt.hash_password_customized(PASSWORD, None, None, params, &salt)
+            } else {
+                let params = argon2::Params::try_from(&hash).ok()?;
+                Argon2::default().hash_password_customized(
+                    PASSWORD,
+                    Some(hash.algorithm),
+                    hash.version,
+                    params,
+                    &salt,
+                )
+            };
+            dummy.ok().map(|dummy| dummy.to_string())
+        }
+        Stored::Bcrypt => {
+            let cost = stored.get(4..6)?.parse().ok()?;
+            bcrypt::hash(PASSWORD, cost).ok()
+        }
+        Stored::Plaintext => None,
+    }
+}
+
+/// Whether `stored` is a password hash rather than a plaintext password
+pub(crate) fn is_hash(stored: &str) -> bool {
+    Stored::detect(stored) != Stored::Plaintext
+}
+
+/// Check `password` against a stored hash or plaintext password in constant time
+pub(crate) fn verify(stored: &str, password: &str) -> bool {
+    match Stored::detect(stored) {
+        Stored::Phc => {
+            let hash = match PasswordHash::new(stored) {
+                Ok(hash) => hash,
+                Err(e) => {
+                    warn!("Password hash cannot be parsed: {}", e);
+                    return false;
+                }
+            };
+            let verifiers: [&dyn PasswordVerifier; 2] = [&Argon2::default(), &Scrypt];
+            hash.verify_password(&verifiers, password).is_ok()
+        }
+        Stored::Bcrypt => bcrypt::verify(password, stored).unwrap_or_else(|e| {
+            warn!("Password hash cannot be parsed: {}", e);
+            false
+        }),
+        Stored::Plaintext => stored.as_bytes().ct_eq(password.as_bytes()).into(),
+    }
+}
@@ -0,0 +1,80 @@
+use crate::ipset::{parse_net, IpSet};
+use crate::quota::Quota;
+use crate::rules::{Command, Destinations, Request};
+use std::net::IpAddr;
+
+/// What an authenticated user may do. Permissions which are not set allow everything.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Permissions {
+    /// Disabled users can't use the proxy at all
+    pub enabled: bool,
+    /// Traffic allowed per period, overrides the default quota
+    pub quota: Option<Quota>,
+    destinations: Option<Destinations>,
+    commands: Option<Vec<Command>>,
+    sources: Option<IpSet>,
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        Permissions {
+            enabled: true,
+            quota: None,
+            destinations: None,
+            commands: None,
+            sources: None,
+        }
+    }
+}
+
+impl Permissions {
+    /// Only allow destinations from a comma separated list of networks, addresses, domains,
+    /// `.suffix` domains and `*` globs
+    pub fn set_destinations(&mut self, list: &str) {
+        self.destinations = Some(Destinations::parse(split(list)));
+    }
+
+    /// Only allow commands from a comma separated list of connect, bind and udp
+    pub fn set_commands(&mut self, list: &str) -> Result<(), String> {
+        self.commands = Some(split(list).map(str::parse).collect::<Result<_, _>>()?);
+        Ok(())
+    }
+
+    /// Only allow clients from a comma separated list of networks and addresses
+    pub fn set_sources(&mut self, list: &str) -> Result<(), String> {
+        self.sources = Some(split(list).map(parse_net).collect::<Result<_, _>>()?);
+        Ok(())
+    }
+
+    /// Whether the user may connect from `ip`
+    pub fn allows_source(&self, ip: &IpAddr) -> bool {
+        self.sources
+            .as_ref()
+            .is_none_or(|sources| sources.contains(ip))
+    }
+
+    /// Whether the user may use the command of `req` at all
+    pub fn allows_command(&self, req: &Request<'_>) -> bool {
+        self.enabled
+            && self.allows_source(&req.source)
+            && self
+                .commands
+                .as_ref()
+                .is_none_or(|commands| commands.contains(&req.command))
+    }
+
+    /// Whether the user may make `req`
+    pub fn allows(&self, req: &Request<'_>) -> bool {
+        self.allows_command(req)
+            && self
+                .destinations
+                .as_ref()
+                .is_none_or(|dest| dest.matches(req.domain, req.ip))
+    }
+}
+
+fn split(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
@@ -0,0 +1,474 @@
+use crate::connect::{AsyncStream, ConnectContext, Connector, IpFamily, Outbound, Target};
+use crate::egress::Egress;
+use crate::resolver::Resolver;
+use crate::upstream::{is_refused, Upstream};
+use async_trait::async_trait;
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{timeout, Instant, MissedTickBehavior};
+
+/// How a pool chooses the upstream for a connection
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Strategy {
+    /// Each upstream in turn
+    #[default]
+    RoundRobin,
+    /// The upstream with the fewest open connections
+    LeastConnections,
+    /// The same upstream for the same destination, as long as it's available
+    ConsistentHash,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "least-connections" => Ok(Strategy::LeastConnections),
+            "consistent-hash" => Ok(Strategy::ConsistentHash),
+            _ => Err(format!(
+                "{}: expected one of round-robin, least-connections, consistent-hash",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Strategy::RoundRobin => "round-robin",
+            Strategy::LeastConnections => "least-connections",
+            Strategy::ConsistentHash => "consistent-hash",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Periodic checks of the upstreams of a pool
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthCheck {
+    /// Time between checks
+    pub interval: Duration,
+    /// Connect through each upstream to this target, greeting every proxy of the chain.
+    /// Only a TCP connection to the first proxy is opened if `None`.
+    pub probe: Option<Target>,
+}
+
+/// Failover settings of pools
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoolOptions {
+    /// Consecutive failed connections after which an upstream is ejected, 0 never ejects
+    pub max_failures: u32,
+    /// How long an ejected upstream is skipped
+    pub eject_time: Duration,
+    /// Time limit for connecting through one upstream, before the next one is tried,
+    /// and for health checks. The connect timeout still limits all attempts together.
+    pub timeout: Duration,
+    /// `None` disables health checks
+    pub health_check: Option<HealthCheck>,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        PoolOptions {
+            max_failures: 3,
+            eject_time: Duration::from_secs(30),
+            timeout: Duration::from_secs(5),
+            health_check: Some(HealthCheck {
+                interval: Duration::from_secs(10),
+                probe: None,
+            }),
+        }
+    }
+}
+
+/// Pool of upstreams by their names, parsed from `NAME=STRATEGY:UPSTREAM[,UPSTREAM...]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoolConfig {
+    pub name: String,
+    pub strategy: Strategy,
+    pub upstreams: Vec<String>,
+}
+
+impl FromStr for PoolConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, rest) = s
+            .split_once('=')
+            .ok_or_else(|| format!("{} is not like NAME=round-robin:UPSTREAM,UPSTREAM", s))?;
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(format!("name of pool {} is missing", s));
+        }
+        let (strategy, upstreams) = rest
+            .split_once(':')
+            .ok_or_else(|| format!("strategy of pool {} is missing", name))?;
+        let upstreams: Vec<String> = upstreams
+            .split(',')
+            .map(str::trim)
+            .filter(|upstream| !upstream.is_empty())
+            .map(str::to_string)
+            .collect();
+        if upstreams.is_empty() {
+            return Err(format!("pool {} has no upstreams", name));
+        }
+
+        Ok(PoolConfig {
+            name: name.to_string(),
+            strategy: strategy.trim().parse()?,
+            upstreams,
+        })
+    }
+}
+
+/// State of an upstream in a pool
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemberStatus {
+    pub name: String,
+    /// Passed the latest health check, or wasn't checked yet
+    pub healthy: bool,
+    /// Time the upstream is still skipped after failed connections
+    pub ejected: Option<Duration>,
+    /// Open connections through the upstream
+    pub connections: usize,
+}
+
+/// Consecutive failed connections of an upstream
+#[derive(Debug, Default)]
+struct Failures {
+    count: u32,
+    ejected_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Member {
+    upstream: Upstream,
+    healthy: AtomicBool,
+    connections: AtomicUsize,
+    failures: Mutex<Failures>,
+}
+
+impl Member {
+    fn new(upstream: Upstream) -> Self {
+        Member {
+            upstream,
+            healthy: AtomicBool::new(true),
+            connections: AtomicUsize::new(0),
+            failures: Mutex::new(Failures::default()),
+        }
+    }
+
+    fn ejected(&self) -> Option<Duration> {
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        failures
+            .ejected_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    fn available(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed) && self.ejected().is_none()
+    }
+
+    fn succeeded(&self) {
+        self.failures
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .count = 0;
+    }
+
+    /// Count a failed connection, true if the upstream is ejected by it
+    fn failed(&self, options: &PoolOptions) -> bool {
+        let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        failures.count += 1;
+        if options.max_failures == 0 || failures.count < options.max_failures {
+            return false;
+        }
+        failures.count = 0;
+        failures.ejected_until = Some(Instant::now() + options.eject_time);
+        true
+    }
+
+    /// Count `outbound` as open through the upstream until it's dropped
+    fn track(self: &Arc<Self>, outbound: Outbound) -> Outbound {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        Outbound {
+            stream: Box::new(Tracked {
+                stream: outbound.stream,
+                member: self.clone(),
+            }),
+            local_addr: outbound.local_addr,
+        }
+    }
+
+    /// Health check through the upstream
+    async fn probe(&self, probe: Option<&Target>, context: &ConnectContext<'_>) -> io::Result<()> {
+        match probe {
+            Some(target) => match self.upstream.connect(target, context).await {
+                Ok(_) => Ok(()),
+                // The chain works, only the target is unavailable
+                Err(e) if is_refused(&e) => Ok(()),
+                Err(e) => Err(e),
+            },
+            None => self.upstream.dial(context).await.map(drop),
+        }
+    }
+}
+
+/// Stream of a connection through a pool member
+struct Tracked {
+    stream: Box<dyn AsyncStream>,
+    member: Arc<Member>,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.member.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl AsyncRead for Tracked {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Tracked {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Weight of `upstream` for `destination` in rendezvous hashing
+fn score(upstream: &str, destination: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (upstream, destination).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Upstreams sharing the connections of a route.
+///
+/// Upstreams are skipped while they fail health checks, or for a while after failing
+/// connections. A failed connection is retried through the next upstream, in the order
+/// of the strategy.
+#[derive(Debug)]
+pub struct Pool {
+    name: String,
+    strategy: Strategy,
+    options: PoolOptions,
+    members: Vec<Arc<Member>>,
+    /// Turn of round robin
+    next: AtomicUsize,
+}
+
+impl Pool {
+    /// Fails if an upstream of `config` is not in `upstreams`
+    pub fn new(
+        config: PoolConfig,
+        upstreams: &[Upstream],
+        options: PoolOptions,
+    ) -> Result<Self, String> {
+        if config.upstreams.is_empty() {
+            return Err(format!("pool {} has no upstreams", config.name));
+        }
+        let members = config
+            .upstreams
+            .iter()
+            .map(|name| {
+                upstreams
+                    .iter()
+                    .find(|upstream| &upstream.name == name)
+                    .map(|upstream| Arc::new(Member::new(upstream.clone())))
+                    .ok_or_else(|| format!("pool {} has unknown upstream {}", config.name, name))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Pool {
+            name: config.name,
+            strategy: config.strategy,
+            options,
+            members,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// State of the upstreams, in the order they were given
+    pub fn status(&self) -> Vec<MemberStatus> {
+        self.members
+            .iter()
+            .map(|member| MemberStatus {
+                name: member.upstream.name.clone(),
+                healthy: member.healthy.load(Ordering::Relaxed),
+                ejected: member.ejected(),
+                connections: member.connections.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Upstreams in the order they are tried for `target`
+    fn candidates(&self, target: &Target) -> Vec<Arc<Member>> {
+        let mut order: Vec<&Arc<Member>> = self.members.iter().collect();
+        let turn = || self.next.fetch_add(1, Ordering::Relaxed) % self.members.len();
+        match self.strategy {
+            Strategy::RoundRobin => order.rotate_left(turn()),
+            Strategy::LeastConnections => {
+                // Ties are taken in turn
+                order.rotate_left(turn());
+                order.sort_by_key(|member| member.connections.load(Ordering::Relaxed));
+            }
+            Strategy::ConsistentHash => {
+                // Rendezvous hashing, destinations only move when their upstream fails
+                let destination = target.to_string();
+                order.sort_by_key(|member| Reverse(score(&member.upstream.name, &destination)));
+            }
+        }
+
+        let (available, unavailable): (Vec<_>, Vec<_>) =
+            order.into_iter().partition(|member| member.available());
+        // Better try the unavailable upstreams than give up
+        let order = if available.is_empty() {
+            unavailable
+        } else {
+            available
+        };
+        order.into_iter().cloned().collect()
+    }
+
+    /// Run the health checks of the pool forever, if they are enabled
+    pub async fn check_health(
+        self: Arc<Self>,
+        resolver: Arc<Resolver>,
+        ip_family: IpFamily,
+        egress: Arc<Egress>,
+    ) {
+        let check = match &self.options.health_check {
+            Some(check) => check.clone(),
+            None => return,
+        };
+        let mut interval = tokio::time::interval(check.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let context = ConnectContext {
+            client: SocketAddr::from(([0, 0, 0, 0], 0)),
+            user: None,
+            addrs: &[],
+            resolver: &resolver,
+            ip_family,
+            egress: &egress,
+        };
+
+        loop {
+            interval.tick().await;
+            let (probe, context, limit) = (check.probe.as_ref(), &context, self.options.timeout);
+            let checks = self.members.iter().map(|member| async move {
+                let result = timeout(limit, member.probe(probe, context)).await;
+                (member, result)
+            });
+            for (member, result) in futures::future::join_all(checks).await {
+                let name = &member.upstream.name;
+                let healthy = match result {
+                    Ok(Ok(())) => true,
+                    Ok(Err(e)) => {
+                        debug!("Health check of upstream {} failed: {}", name, e);
+                        false
+                    }
+                    Err(_) => {
+                        debug!("Health check of upstream {} timed out", name);
+                        false
+                    }
+                };
+                if member.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+                    if healthy {
+                        info!("Upstream {} of pool {} is healthy again", name, self.name);
+                    } else {
+                        warn!("Upstream {} of pool {} is unhealthy", name, self.name);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Connector for Pool {
+    async fn connect(&self, target: &Target, context: &ConnectContext<'_>) -> io::Result<Outbound> {
+        let mut last_error = None;
+        for member in self.candidates(target) {
+            let name = &member.upstream.name;
+            let result = timeout(
+                self.options.timeout,
+                member.upstream.connect(target, context),
+            )
+            .await
+            .unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no connection within {:?}", self.options.timeout),
+                ))
+            });
+            match result {
+                Ok(outbound) => {
+                    member.succeeded();
+                    return Ok(member.track(outbound));
+                }
+                // Other upstreams would most likely be refused as well
+                Err(e) if is_refused(&e) => {
+                    member.succeeded();
+                    return Err(e);
+                }
+                Err(e) => {
+                    warn!(
+                        "Connecting to {} via upstream {} of pool {} failed: {}",
+                        target, name, self.name, e
+                    );
+                    if member.failed(&self.options) {
+                        warn!(
+                            "Ejecting upstream {} from pool {} for {:?}",
+                            name, self.name, self.options.eject_time
+                        );
+                    }
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.expect("pools have upstreams"))
+    }
+
+    fn resolves_remotely(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,86 @@
+use crate::ipset::IpSet;
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Special-purpose ranges which are not reachable on the public internet
+const PRIVATE_NETS: &[&str] = &[
+    // IPv4
+    "0.0.0.0/8",       // "this network"
+    "10.0.0.0/8",      // private use
+    "100.64.0.0/10",   // shared address space (CGNAT)
+    "127.0.0.0/8",     // loopback
+    "169.254.0.0/16",  // link-local, cloud metadata
+    "172.16.0.0/12",   // private use
+    "192.0.0.0/24",    // IETF protocol assignments
+    "192.0.2.0/24",    // TEST-NET-1
+    "192.88.99.0/24",  // 6to4 relay anycast
+    "192.168.0.0/16",  // private use
+    "198.18.0.0/15",   // benchmarking
+    "198.51.100.0/24", // TEST-NET-2
+    "203.0.113.0/24",  // TEST-NET-3
+    "224.0.0.0/4",     // multicast
+    "240.0.0.0/4",     // reserved, broadcast
+    // IPv6
+    "::/96",          // unspecified, loopback, IPv4-compatible
+    "64:ff9b:1::/48", // local-use IPv4/IPv6 translation
+    "100::/64",       // discard-only
+    "2001::/23",      // IETF protocol assignments, Teredo
+    "2001:db8::/32",  // documentation
+    "fc00::/7",       // unique local
+    "fe80::/10",      // link-local
+    "fec0::/10",      // site-local
+    "ff00::/8",       // multicast
+];
+
+/// Blocks destinations in private, loopback, link-local and other special-purpose ranges.
+///
+/// IPv4 addresses embedded in IPv4-mapped, NAT64 (`64:ff9b::/96`) and 6to4 (`2002::/16`)
+/// addresses are checked as IPv4. Networks from the allowlist are never blocked.
+#[derive(Clone, Debug)]
+pub struct PrivateFilter {
+    blocked: IpSet,
+    allowed: IpSet,
+}
+
+impl PrivateFilter {
+    /// Block special-purpose ranges except networks in `allowed`
+    pub fn new(allowed: IpSet) -> Self {
+        let blocked = PRIVATE_NETS
+            .iter()
+            .map(|net| net.parse::<IpNet>().unwrap())
+            .collect();
+
+        PrivateFilter { blocked, allowed }
+    }
+
+    /// Whether connections to `ip` are blocked
+    pub fn blocks(&self, ip: &IpAddr) -> bool {
+        let ip = embedded_ipv4(ip).map(IpAddr::V4).unwrap_or(*ip);
+        self.blocked.contains(&ip) && !self.allowed.contains(&ip)
+    }
+}
+
+impl Default for PrivateFilter {
+    fn default() -> Self {
+        Self::new(IpSet::new())
+    }
+}
+
+/// IPv4 address which `ip` translates to
+fn embedded_ipv4(ip: &IpAddr) -> Option<Ipv4Addr> {
+    let ip = match ip {
+        IpAddr::V4(_) => return None,
+        IpAddr::V6(ip) => ip,
+    };
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return Some(v4);
+    }
+
+    let bits = u128::from(*ip);
+    let nat64 = u128::from(Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0));
+    match ip.segments() {
+        _ if bits >> 32 == nat64 >> 32 => Some(Ipv4Addr::from(bits as u32)),
+        [0x2002, high, low, ..] => Some(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low))),
+        _ => None,
+    }
+}
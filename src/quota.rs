@@ -0,0 +1,422 @@
+use crate::shaper::parse_rate;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+
+/// Period after which quotas start over
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Period {
+    Day,
+    Month,
+}
+
+impl Period {
+    /// First day of the period containing `date`
+    fn start(self, date: Date) -> Date {
+        match self {
+            Period::Day => date,
+            Period::Month => Date { day: 1, ..date },
+        }
+    }
+}
+
+impl FromStr for Period {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "day" => Ok(Period::Day),
+            "month" => Ok(Period::Month),
+            _ => Err(format!("unknown quota period {}, expected day or month", s)),
+        }
+    }
+}
+
+impl fmt::Display for Period {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Period::Day => f.write_str("day"),
+            Period::Month => f.write_str("month"),
+        }
+    }
+}
+
+/// Bytes a user may transfer in both directions per period
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+    pub bytes: u64,
+    pub period: Period,
+}
+
+impl FromStr for Quota {
+    type Err = String;
+
+    /// `SIZE/day` or `SIZE/month`, like `50G/month`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (bytes, period) = s
+            .split_once('/')
+            .ok_or_else(|| format!("quota {} is not like 50G/month", s))?;
+        Ok(Quota {
+            bytes: parse_rate(bytes)?,
+            period: period.parse()?,
+        })
+    }
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.bytes, self.period)
+    }
+}
+
+/// Calendar date in UTC
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Date {
+    year: i64,
+    month: u32,
+    day: u32,
+}
+
+impl Date {
+    fn today() -> Self {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or_default();
+        Date::from_days((secs / 86400) as i64)
+    }
+
+    /// Date `days` after 1970-01-01, from http://howardhinnant.github.io/date_algorithms.html
+    fn from_days(days: i64) -> Self {
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        Date { year, month, day }
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+impl FromStr for Date {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{} is not a date like 2024-01-31", s);
+        let mut parts = s.splitn(3, '-');
+        let mut next = || parts.next().ok_or_else(invalid);
+        let (year, month, day) = (next()?, next()?, next()?);
+
+        Ok(Date {
+            year: year.parse().map_err(|_| invalid())?,
+            month: month.parse().map_err(|_| invalid())?,
+            day: day.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Traffic of a user in the current period
+struct Counter {
+    usage: Mutex<Usage>,
+    /// Woken when the quota is exhausted
+    exhausted: Notify,
+}
+
+#[derive(Clone, Copy)]
+struct Usage {
+    period: Period,
+    /// First day of the period
+    start: Date,
+    bytes: u64,
+    /// Quota of the latest session
+    quota: Option<Quota>,
+}
+
+impl Usage {
+    /// Nothing used yet in the current `period`
+    fn new(period: Period) -> Self {
+        Usage {
+            period,
+            start: period.start(Date::today()),
+            bytes: 0,
+            quota: None,
+        }
+    }
+
+    /// Start over if a new period began
+    fn roll_over(&mut self) {
+        let start = self.period.start(Date::today());
+        if self.start != start {
+            self.start = start;
+            self.bytes = 0;
+        }
+    }
+}
+
+impl Counter {
+    fn new(usage: Usage) -> Self {
+        Counter {
+            usage: Mutex::new(usage),
+            exhausted: Notify::new(),
+        }
+    }
+
+    /// Usage in the current period by a session with `quota`
+    fn current(&self, quota: Option<Quota>) -> MutexGuard<'_, Usage> {
+        let mut usage = self.usage.lock().unwrap();
+        if let Some(quota) = quota {
+            usage.period = quota.period;
+        }
+        usage.quota = quota;
+        usage.roll_over();
+        usage
+    }
+}
+
+/// Row of the state file
+#[derive(Serialize, Deserialize)]
+struct Record {
+    username: String,
+    period: String,
+    start: String,
+    bytes: u64,
+}
+
+/// Traffic used by a user
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuotaUsage {
+    pub username: String,
+    pub period: Period,
+    /// First day of the period, `YYYY-MM-DD`
+    pub start: String,
+    pub bytes: u64,
+    /// Quota of the latest session, `None` if unlimited
+    pub quota: Option<Quota>,
+}
+
+/// Traffic of authenticated users, by period.
+///
+/// Counters are kept in memory and written to the state file by [`Quotas::save`].
+pub struct Quotas {
+    state_file: Option<PathBuf>,
+    /// Quota of users which have none of their own
+    default: Option<Quota>,
+    /// Close running sessions when the quota is exhausted
+    close_sessions: bool,
+    counters: Mutex<HashMap<String, Arc<Counter>>>,
+    /// Held while saving, as saves share the temporary file
+    saving: Mutex<()>,
+}
+
+impl Quotas {
+    /// Counters kept only in memory
+    pub fn new() -> Self {
+        Quotas {
+            state_file: None,
+            default: None,
+            close_sessions: false,
+            counters: Mutex::new(HashMap::new()),
+            saving: Mutex::new(()),
+        }
+    }
+
+    /// Read counters from `state_file` if it exists, and save them there later
+    pub fn load(state_file: &Path) -> io::Result<Self> {
+        let mut quotas = Quotas::new();
+        quotas.state_file = Some(state_file.to_path_buf());
+        if !state_file.exists() {
+            return Ok(quotas);
+        }
+
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+        let mut rdr = csv::Reader::from_reader(File::open(state_file)?);
+        let counters = quotas.counters.get_mut().unwrap();
+        for result in rdr.deserialize() {
+            let record: Record = result.map_err(|e| invalid(format!("{:?}: {}", state_file, e)))?;
+            let usage = Usage {
+                period: record.period.parse().map_err(invalid)?,
+                start: record.start.parse().map_err(invalid)?,
+                bytes: record.bytes,
+                quota: None,
+            };
+            counters.insert(record.username, Arc::new(Counter::new(usage)));
+        }
+
+        Ok(quotas)
+    }
+
+    /// Quota for users which have none of their own
+    pub fn set_default(&mut self, quota: Option<Quota>) {
+        self.default = quota;
+    }
+
+    /// Close running sessions of users who exhausted their quota.
+    /// By default only new sessions are refused.
+    pub fn set_close_sessions(&mut self, close: bool) {
+        self.close_sessions = close;
+    }
+
+    /// Start accounting a session of `username`, whose own quota is `quota`
+    pub fn session(&self, username: &str, quota: Option<Quota>) -> QuotaSession {
+        // Users without a quota are accounted by month
+        let counter = self
+            .counters
+            .lock()
+            .unwrap()
+            .entry(username.to_string())
+            .or_insert_with(|| Arc::new(Counter::new(Usage::new(Period::Month))))
+            .clone();
+
+        QuotaSession {
+            counter: Some(counter),
+            quota: quota.or(self.default),
+            close: self.close_sessions,
+        }
+    }
+
+    /// Usage of all users, by username
+    pub fn usage(&self) -> Vec<QuotaUsage> {
+        let counters = self.counters.lock().unwrap();
+        let mut usage: Vec<QuotaUsage> = counters
+            .iter()
+            .map(|(username, counter)| {
+                let mut usage = counter.usage.lock().unwrap();
+                usage.roll_over();
+                QuotaUsage {
+                    username: username.clone(),
+                    period: usage.period,
+                    start: usage.start.to_string(),
+                    bytes: usage.bytes,
+                    quota: usage.quota,
+                }
+            })
+            .collect();
+        usage.sort_by(|a, b| a.username.cmp(&b.username));
+        usage
+    }
+
+    /// Start the period of `username` over, returns false if there is no such user
+    pub fn reset(&self, username: &str) -> bool {
+        match self.counters.lock().unwrap().get(username) {
+            Some(counter) => {
+                counter.usage.lock().unwrap().bytes = 0;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Write counters to the state file, if there is one.
+    /// The file is replaced at once, so it's never left half written.
+    pub fn save(&self) -> io::Result<()> {
+        let state_file = match &self.state_file {
+            Some(state_file) => state_file,
+            None => return Ok(()),
+        };
+
+        let _saving = self.saving.lock().unwrap();
+        let mut tmp = state_file.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut wtr = csv::Writer::from_path(&tmp)?;
+        for usage in self.usage() {
+            wtr.serialize(Record {
+                username: usage.username,
+                period: usage.period.to_string(),
+                start: usage.start,
+                bytes: usage.bytes,
+            })?;
+        }
+        wtr.flush()?;
+        drop(wtr);
+
+        fs::rename(&tmp, state_file)
+    }
+
+    /// Save the counters every `interval`
+    pub async fn save_every(self: Arc<Self>, interval: std::time::Duration) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.save() {
+                error!("Can't save quotas to {:?}: {}", self.state_file, e);
+            }
+        }
+    }
+}
+
+impl Default for Quotas {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Accounting of a single session
+pub struct QuotaSession {
+    /// `None` for clients which did not authenticate
+    counter: Option<Arc<Counter>>,
+    quota: Option<Quota>,
+    close: bool,
+}
+
+impl QuotaSession {
+    /// Session which is not accounted
+    pub fn none() -> Self {
+        QuotaSession {
+            counter: None,
+            quota: None,
+            close: false,
+        }
+    }
+
+    /// Whether the quota is used up
+    pub fn is_exhausted(&self) -> bool {
+        match (&self.counter, self.quota) {
+            (Some(counter), Some(quota)) => counter.current(Some(quota)).bytes >= quota.bytes,
+            _ => false,
+        }
+    }
+
+    /// Account `n` bytes in either direction
+    pub fn transferred(&self, n: usize) {
+        if let Some(counter) = &self.counter {
+            let mut usage = counter.current(self.quota);
+            usage.bytes += n as u64;
+            if self.quota.is_some_and(|quota| usage.bytes >= quota.bytes) {
+                counter.exhausted.notify_waiters();
+            }
+        }
+    }
+
+    /// Complete once the quota is used up, if sessions are closed then
+    pub async fn exhausted(&self) {
+        let counter = match &self.counter {
+            Some(counter) if self.close && self.quota.is_some() => counter,
+            _ => return std::future::pending().await,
+        };
+
+        loop {
+            // Created before the check, so a notification in between isn't lost
+            let notified = counter.exhausted.notified();
+            if self.is_exhausted() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use crate::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+
+/// Size of the buffer for each direction
+const BUF_SIZE: usize = 8 * 1024;
+
+/// Why a relayed session was closed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CloseReason {
+    /// Both sides closed the connection
+    Eof,
+    /// No traffic in either direction for the idle timeout
+    Idle,
+    /// Maximum session lifetime reached
+    Lifetime,
+    /// Traffic quota of the user exhausted
+    Quota,
+    /// Reading or writing failed
+    Error,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            CloseReason::Eof => "peer EOF",
+            CloseReason::Idle => "idle",
+            CloseReason::Lifetime => "lifetime",
+            CloseReason::Quota => "quota",
+            CloseReason::Error => "error",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// Summary of a finished session
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SessionStats {
+    /// Bytes from the client to the target
+    pub upload: u64,
+    /// Bytes from the target to the client
+    pub download: u64,
+    /// Time spent relaying
+    pub duration: Duration,
+    pub reason: CloseReason,
+}
+
+/// Counters of all finished sessions
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    pub sessions: u64,
+    pub upload: u64,
+    pub download: u64,
+    /// Number of sessions by close reason
+    pub closed: HashMap<CloseReason, u64>,
+}
+
+impl Stats {
+    /// Add a finished session
+    pub fn record(&mut self, session: &SessionStats) {
+        self.sessions += 1;
+        self.upload += session.upload;
+        self.download += session.download;
+        *self.closed.entry(session.reason).or_default() += 1;
+    }
+}
+
+/// Traffic in one direction
+pub(crate) struct Direction {
+    start: Instant,
+    /// Time of the last activity, in milliseconds since `start`
+    last: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Direction {
+    pub fn new(start: Instant) -> Self {
+        Direction {
+            start,
+            last: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Account `n` transferred bytes
+    pub fn transferred(&self, n: usize) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last.fetch_max(now, Ordering::Relaxed);
+        self.bytes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn last(&self) -> Instant {
+        self.start + Duration::from_millis(self.last.load(Ordering::Relaxed))
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+/// Bandwidth limits and quota of a session
+pub(crate) struct Limits {
+    pub shaper: SessionShaper,
+    pub quota: QuotaSession,
+}
+
+impl Limits {
+    /// Wait until `n` bytes may be sent from the client to the target and account them
+    pub async fn upload(&self, n: usize) {
+        self.quota.transferred(n);
+        self.shaper.upload(n).await;
+    }
+
+    /// Wait until `n` bytes may be sent from the target to the client and account them
+    pub async fn download(&self, n: usize) {
+        self.quota.transferred(n);
+        self.shaper.download(n).await;
+    }
+}
+
+/// Copy from `reader` to `writer` until EOF, then shut `writer` down.
+/// Each chunk waits for `throttle` before it's written.
+async fn copy<R, W, F, Fut>(
+    reader: &mut R,
+    writer: &mut W,
+    direction: &Direction,
+    throttle: F,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: Fn(usize) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut buf = vec![0u8; BUF_SIZE];
+
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return writer.shutdown().await;
+        }
+
+        throttle(n).await;
+        writer.write_all(&buf[..n]).await?;
+        direction.transferred(n);
+    }
+}
+
+/// Complete once there was no activity in either direction for `idle`
+pub(crate) async fn idle_watchdog(idle: Option<Duration>, up: &Direction, down: &Direction) {
+    let idle = match idle {
+        Some(idle) => idle,
+        None => return std::future::pending().await,
+    };
+
+    loop {
+        let deadline = up.last().max(down.last()) + idle;
+        if Instant::now() >= deadline {
+            return;
+        }
+        tokio::time::sleep_until(deadline).await;
+    }
+}
+
+/// Complete once the session is `lifetime` old
+pub(crate) async fn lifetime_watchdog(start: Instant, lifetime: Option<Duration>) {
+    match lifetime {
+        Some(lifetime) => tokio::time::sleep_until(start + lifetime).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Relay data between the client and the target until both sides are closed,
+/// the session is idle, reaches its maximum lifetime or exhausts the quota.
+pub(crate) async fn relay<C, T>(
+    client: &mut C,
+    target: &mut T,
+    timeouts: Timeouts,
+    limits: &Limits,
+) -> SessionStats
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    trace!("copy bidirectional");
+    let (mut client_reader, mut client_writer) = tokio::io::split(client);
+    let (mut target_reader, mut target_writer) = tokio::io::split(target);
+
+    let start = Instant::now();
+    let up = Direction::new(start);
+    let down = Direction::new(start);
+
+    let copy_both = futures::future::try_join(
+        copy(&mut client_reader, &mut target_writer, &up, |n| {
+            limits.upload(n)
+        }),
+        copy(&mut target_reader, &mut client_writer, &down, |n| {
+            limits.download(n)
+        }),
+    );
+
+    let reason = tokio::select! {
+        result = copy_both => match result {
+            Ok(_) => CloseReason::Eof,
+            // ignore not connected for shutdown error
+            Err(e) if e.kind() == std::io::ErrorKind::NotConnected => {
+                trace!("already closed");
+                CloseReason::Eof
+            }
+            Err(e) => {
+                debug!("Relay failed: {}", e);
+                CloseReason::Error
+            }
+        },
+        _ = idle_watchdog(timeouts.idle, &up, &down) => CloseReason::Idle,
+        _ = lifetime_watchdog(start, timeouts.lifetime) => CloseReason::Lifetime,
+        _ = limits.quota.exhausted() => CloseReason::Quota,
+    };
+
+    SessionStats {
+        upload: up.bytes(),
+        download: down.bytes(),
+        duration: start.elapsed(),
+        reason,
+    }
+}
@@ -0,0 +1,140 @@
+use crate::*;
+use notify::{RecursiveMode, Watcher};
+use std::fs::File;
+
+/// Time for editors to finish writing a file before it's read again
+const SETTLE_DELAY: Duration = Duration::from_millis(200);
+
+/// Reloads the users and the whitelist file while merino is serving.
+///
+/// Each source is swapped as a whole, so clients see either the old or the new contents.
+/// A source which can't be read keeps its previous contents.
+#[derive(Clone)]
+pub struct Reloader {
+    authenticator: Arc<dyn Authenticator>,
+    whitelist: Arc<RwLock<IpSet>>,
+    whitelist_file: Option<PathBuf>,
+}
+
+impl Reloader {
+    pub(crate) fn new(
+        authenticator: Arc<dyn Authenticator>,
+        whitelist: Arc<RwLock<IpSet>>,
+        whitelist_file: Option<PathBuf>,
+    ) -> Self {
+        Reloader {
+            authenticator,
+            whitelist,
+            whitelist_file,
+        }
+    }
+
+    /// Reload all sources, returns false if any of them failed
+    pub async fn reload(&self) -> bool {
+        let mut reloaded = true;
+
+        // Reading files and hashing passwords would block the runtime threads
+        let authenticator = self.authenticator.clone();
+        let users = tokio::task::spawn_blocking(move || authenticator.reload())
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e)));
+        match users {
+            Ok(()) => info!("Users reloaded"),
+            Err(e) => {
+                error!("Can't reload users, keeping the previous ones: {}", e);
+                reloaded = false;
+            }
+        }
+
+        if let Some(path) = &self.whitelist_file {
+            let file = path.clone();
+            let whitelist = tokio::task::spawn_blocking(move || read_whitelist(&file))
+                .await
+                .unwrap_or_else(|e| Err(vec![e.to_string()]));
+            match whitelist {
+                Ok(nets) => {
+                    info!("Whitelist reloaded, {} networks", nets.len());
+                    *self.whitelist.write().unwrap() = nets;
+                }
+                Err(errors) => {
+                    error!(
+                        "Can't reload whitelist {:?}, keeping the previous one",
+                        path
+                    );
+                    for error in errors {
+                        error!("{}", error);
+                    }
+                    reloaded = false;
+                }
+            }
+        }
+
+        reloaded
+    }
+
+    /// Reload on every SIGHUP
+    #[cfg(unix)]
+    pub async fn watch_sighup(self) -> io::Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP received, reloading");
+            self.reload().await;
+        }
+
+        Ok(())
+    }
+
+    /// Reload when the users or the whitelist file change
+    pub async fn watch_files(self) -> notify::Result<()> {
+        let mut files: Vec<PathBuf> = self.authenticator.files();
+        files.extend(self.whitelist_file.clone());
+        // Events carry absolute paths
+        let files = files
+            .iter()
+            .map(std::fs::canonicalize)
+            .collect::<io::Result<Vec<PathBuf>>>()?;
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        })?;
+
+        // Directories are watched, as editors often replace files instead of writing them
+        for file in &files {
+            if let Some(dir) = file.parent() {
+                watcher.watch(dir, RecursiveMode::NonRecursive)?;
+            }
+        }
+
+        while let Some(event) = rx.recv().await {
+            let event: notify::Event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!("File watcher: {}", e);
+                    continue;
+                }
+            };
+            if event.kind.is_access() || !event.paths.iter().any(|path| files.contains(path)) {
+                continue;
+            }
+
+            tokio::time::sleep(SETTLE_DELAY).await;
+            while rx.try_recv().is_ok() {}
+
+            info!("{:?} changed, reloading", event.paths);
+            self.reload().await;
+        }
+
+        Ok(())
+    }
+}
+
+fn read_whitelist(path: &Path) -> Result<IpSet, Vec<String>> {
+    let file = File::open(path).map_err(|e| vec![e.to_string()])?;
+    match parse_whitelist(BufReader::new(file)) {
+        (nets, errors) if errors.is_empty() => Ok(nets),
+        (_, errors) => Err(errors),
+    }
+}
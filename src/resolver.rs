@@ -0,0 +1,154 @@
+use hickory_resolver::config::{
+    LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts,
+};
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::TokioAsyncResolver;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+
+/// Default location of the hosts file
+pub const DEFAULT_HOSTS_FILE: &str = "/etc/hosts";
+
+/// Default time limit for resolving a domain
+pub const DEFAULT_DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Asynchronous resolver for DOMAINNAME requests.
+///
+/// Lookups never block the runtime: names from the hosts file are answered from memory,
+/// everything else is sent to the nameservers by a pure-Rust stub resolver.
+pub struct Resolver {
+    resolver: TokioAsyncResolver,
+    /// Addresses from the hosts file, by lowercase name
+    hosts: HashMap<String, Vec<IpAddr>>,
+    /// Time limit for a whole lookup
+    timeout: Duration,
+}
+
+impl Resolver {
+    /// Create a new Resolver.
+    ///
+    /// Uses `nameservers` or, if none are given, the system configuration (`/etc/resolv.conf`).
+    /// Names from `hosts_file` take precedence over DNS.
+    pub fn new(
+        nameservers: &[SocketAddr],
+        hosts_file: Option<&Path>,
+        timeout: Duration,
+    ) -> io::Result<Self> {
+        let (config, mut opts) = if nameservers.is_empty() {
+            hickory_resolver::system_conf::read_system_conf().map_err(io::Error::other)?
+        } else {
+            let mut config = ResolverConfig::new();
+            for nameserver in nameservers {
+                config.add_name_server(NameServerConfig::new(*nameserver, Protocol::Udp));
+                config.add_name_server(NameServerConfig::new(*nameserver, Protocol::Tcp));
+            }
+            (config, ResolverOpts::default())
+        };
+
+        opts.timeout = timeout;
+        // Both families are needed to pick the best connection
+        opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        // Hosts file is handled here, so it can be located anywhere
+        opts.use_hosts_file = false;
+
+        let hosts = match hosts_file {
+            Some(path) => read_hosts(path)?,
+            None => HashMap::new(),
+        };
+
+        Ok(Resolver {
+            resolver: TokioAsyncResolver::tokio(config, opts),
+            hosts,
+            timeout,
+        })
+    }
+
+    /// Create a Resolver with the system configuration and default settings
+    pub fn system() -> io::Result<Self> {
+        let hosts_file = Path::new(DEFAULT_HOSTS_FILE);
+        let hosts_file = if hosts_file.exists() {
+            Some(hosts_file)
+        } else {
+            None
+        };
+
+        Self::new(&[], hosts_file, DEFAULT_DNS_TIMEOUT)
+    }
+
+    /// Resolve `domain` to a list of socket addresses with `port`
+    pub async fn lookup(&self, domain: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let name = domain.trim_end_matches('.').to_lowercase();
+
+        if let Some(ips) = self.hosts.get(&name) {
+            trace!("{} found in hosts file: {:?}", domain, ips);
+            return Ok(ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect());
+        }
+
+        let lookup = tokio::time::timeout(self.timeout, self.resolver.lookup_ip(name.as_str()))
+            .await
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("DNS lookup for {} timed out", domain),
+                )
+            })?;
+
+        // Failed lookups are reported as "Host unreachable"
+        let addrs: Vec<SocketAddr> = match lookup {
+            Ok(lookup) => lookup.iter().map(|ip| SocketAddr::new(ip, port)).collect(),
+            Err(e) => {
+                let kind = match e.kind() {
+                    ResolveErrorKind::Timeout => io::ErrorKind::TimedOut,
+                    _ => io::ErrorKind::HostUnreachable,
+                };
+                return Err(io::Error::new(
+                    kind,
+                    format!("can't resolve {}: {}", domain, e),
+                ));
+            }
+        };
+
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::HostUnreachable,
+                format!("no addresses for {}", domain),
+            ));
+        }
+
+        Ok(addrs)
+    }
+}
+
+/// Parse a hosts file: an IP followed by names on each line, `#` starts a comment
+fn read_hosts(path: &Path) -> io::Result<HashMap<String, Vec<IpAddr>>> {
+    let content = fs::read_to_string(path)?;
+    let mut hosts: HashMap<String, Vec<IpAddr>> = HashMap::new();
+
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+
+        let ip = match fields.next() {
+            Some(ip) => ip,
+            None => continue,
+        };
+        let ip = match ip.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(e) => {
+                warn!("Hosts file {:?}: {} cannot be parsed: {}", path, ip, e);
+                continue;
+            }
+        };
+
+        for name in fields {
+            hosts.entry(name.to_lowercase()).or_default().push(ip);
+        }
+    }
+
+    debug!("Loaded {} names from hosts file {:?}", hosts.len(), path);
+    Ok(hosts)
+}
@@ -0,0 +1,195 @@
+use crate::connect::{ConnectContext, Connector, Outbound, Target};
+use crate::pool::Pool;
+use crate::rules::{Conditions, Request};
+use crate::upstream::Upstream;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Where connections matched by a route go
+#[derive(Clone, Debug, PartialEq)]
+pub enum Route {
+    Direct,
+    /// Through the upstream or pool with this name
+    Via(String),
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Route::Direct => f.write_str("direct"),
+            Route::Via(name) => write!(f, "via {}", name),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct RouteLine {
+    route: Route,
+    conditions: Conditions,
+}
+
+/// Ordered routes for CONNECT requests.
+///
+/// Each line of a routes file is `direct` or `via NAME`, followed by the conditions of
+/// the rules file. Destinations are not resolved for routing, so networks only match
+/// requests made by address. The first matching route decides, requests matching
+/// no route are connected directly. `#` starts a comment.
+///
+/// Domains sent via an upstream are resolved by its last proxy. Rules only match them by
+/// domain and port, `dest=` networks never do, and the private destination filter is skipped.
+#[derive(Clone, Debug, Default)]
+pub struct Routes {
+    routes: Vec<RouteLine>,
+}
+
+impl Routes {
+    /// Read routes from a file
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        content
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: {}", path, e)))
+    }
+
+    /// Route of `req`
+    pub fn route(&self, req: &Request<'_>) -> &Route {
+        self.routes
+            .iter()
+            .find(|line| line.conditions.matches(req))
+            .map_or(&Route::Direct, |line| &line.route)
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+}
+
+impl FromStr for Routes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut routes = Vec::new();
+
+        for (i, line) in s.lines().enumerate() {
+            let text = line.split('#').next().unwrap_or_default().trim();
+            if text.is_empty() {
+                continue;
+            }
+
+            let mut fields = text.split_whitespace();
+            let route = match fields.next() {
+                Some("direct") => Route::Direct,
+                Some("via") => match fields.next() {
+                    Some(name) => Route::Via(name.to_string()),
+                    None => return Err(format!("line {}: upstream name is missing", i + 1)),
+                },
+                Some(route) => return Err(format!("line {}: unknown route {}", i + 1, route)),
+                None => unreachable!("empty lines are skipped"),
+            };
+            let conditions =
+                Conditions::parse(fields).map_err(|e| format!("line {}: {}", i + 1, e))?;
+            routes.push(RouteLine { route, conditions });
+        }
+
+        Ok(Routes { routes })
+    }
+}
+
+/// Upstream or pool which routed connections go through
+#[derive(Clone, Debug)]
+pub enum Gateway {
+    Upstream(Upstream),
+    Pool(Arc<Pool>),
+}
+
+impl Gateway {
+    pub fn name(&self) -> &str {
+        match self {
+            Gateway::Upstream(upstream) => &upstream.name,
+            Gateway::Pool(pool) => pool.name(),
+        }
+    }
+}
+
+#[async_trait]
+impl Connector for Gateway {
+    async fn connect(&self, target: &Target, context: &ConnectContext<'_>) -> io::Result<Outbound> {
+        match self {
+            Gateway::Upstream(upstream) => upstream.connect(target, context).await,
+            Gateway::Pool(pool) => pool.connect(target, context).await,
+        }
+    }
+
+    fn resolves_remotely(&self) -> bool {
+        match self {
+            Gateway::Upstream(upstream) => upstream.resolves_remotely(),
+            Gateway::Pool(pool) => pool.resolves_remotely(),
+        }
+    }
+}
+
+/// Upstreams and pools by name and the routes choosing between them
+#[derive(Clone, Debug, Default)]
+pub struct Router {
+    gateways: HashMap<String, Gateway>,
+    routes: Routes,
+}
+
+impl Router {
+    /// Fails if names are taken twice, or a route goes via an upstream or pool which is not given
+    pub fn new(upstreams: Vec<Upstream>, pools: Vec<Pool>, routes: Routes) -> Result<Self, String> {
+        let mut gateways = HashMap::new();
+        let all = upstreams
+            .into_iter()
+            .map(Gateway::Upstream)
+            .chain(pools.into_iter().map(|pool| Gateway::Pool(Arc::new(pool))));
+        for gateway in all {
+            let name = gateway.name().to_string();
+            if gateways.insert(name.clone(), gateway).is_some() {
+                return Err(format!("{} is defined twice", name));
+            }
+        }
+        for line in &routes.routes {
+            if let Route::Via(name) = &line.route {
+                if !gateways.contains_key(name) {
+                    return Err(format!("route via unknown upstream {}", name));
+                }
+            }
+        }
+
+        Ok(Router { gateways, routes })
+    }
+
+    /// Upstream or pool for `req`, `None` to connect directly
+    pub fn gateway(&self, req: &Request<'_>) -> Option<&Gateway> {
+        match self.routes.route(req) {
+            Route::Direct => None,
+            Route::Via(name) => self.gateways.get(name),
+        }
+    }
+
+    /// Pool with this name
+    pub fn pool(&self, name: &str) -> Option<&Arc<Pool>> {
+        match self.gateways.get(name) {
+            Some(Gateway::Pool(pool)) => Some(pool),
+            _ => None,
+        }
+    }
+
+    pub fn pools(&self) -> impl Iterator<Item = &Arc<Pool>> {
+        self.gateways.values().filter_map(|gateway| match gateway {
+            Gateway::Pool(pool) => Some(pool),
+            Gateway::Upstream(_) => None,
+        })
+    }
+}
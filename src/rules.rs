@@ -0,0 +1,386 @@
+use crate::ipset::{parse_net, IpSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+/// What happens to requests matched by a rule
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Allow => f.write_str("allow"),
+            Action::Deny => f.write_str("deny"),
+        }
+    }
+}
+
+/// SOCKS command of a request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    Connect,
+    Bind,
+    Udp,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "connect" => Ok(Command::Connect),
+            "bind" => Ok(Command::Bind),
+            "udp" => Ok(Command::Udp),
+            _ => Err(format!(
+                "unknown command {}, expected connect, bind or udp",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Connect => f.write_str("connect"),
+            Command::Bind => f.write_str("bind"),
+            Command::Udp => f.write_str("udp"),
+        }
+    }
+}
+
+/// Request checked against the rules
+#[derive(Clone, Copy, Debug)]
+pub struct Request<'a> {
+    pub command: Command,
+    /// Authenticated user
+    pub user: Option<&'a str>,
+    /// Address of the client
+    pub source: IpAddr,
+    /// Destination domain, if the client sent one
+    pub domain: Option<&'a str>,
+    /// Destination address, after resolving the domain
+    pub ip: Option<IpAddr>,
+    pub port: u16,
+}
+
+/// Pattern for destination domains
+#[derive(Clone, Debug, PartialEq)]
+enum DomainPattern {
+    /// `.example.com` matches example.com and its subdomains
+    Suffix(String),
+    /// `*` matches any run of characters
+    Glob(String),
+    Exact(String),
+}
+
+impl DomainPattern {
+    fn parse(s: &str) -> Self {
+        let s = s.trim_end_matches('.').to_lowercase();
+        if let Some(suffix) = s.strip_prefix('.') {
+            DomainPattern::Suffix(suffix.to_string())
+        } else if s.contains('*') {
+            DomainPattern::Glob(s)
+        } else {
+            DomainPattern::Exact(s)
+        }
+    }
+
+    /// `domain` must be lowercase without the trailing dot
+    fn matches(&self, domain: &str) -> bool {
+        match self {
+            DomainPattern::Suffix(suffix) => {
+                domain == suffix
+                    || (domain.ends_with(suffix.as_str())
+                        && domain[..domain.len() - suffix.len()].ends_with('.'))
+            }
+            DomainPattern::Glob(glob) => glob_matches(glob.as_bytes(), domain.as_bytes()),
+            DomainPattern::Exact(name) => domain == name,
+        }
+    }
+}
+
+/// Match `text` against `pattern` where `*` matches any run of bytes
+fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text it's matched up to
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // Let the last `*` take one more byte
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, t));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Destinations of a rule: networks and domain patterns
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Destinations {
+    nets: IpSet,
+    domains: Vec<DomainPattern>,
+}
+
+impl Destinations {
+    /// Values which are not networks or addresses are domain patterns
+    pub(crate) fn parse<'a>(values: impl Iterator<Item = &'a str>) -> Self {
+        let mut dest = Destinations::default();
+        for value in values {
+            match parse_net(value) {
+                Ok(net) => {
+                    dest.nets.insert(net);
+                }
+                Err(_) => dest.domains.push(DomainPattern::parse(value)),
+            }
+        }
+        dest
+    }
+
+    pub(crate) fn matches(&self, domain: Option<&str>, ip: Option<IpAddr>) -> bool {
+        let domain = domain.map(|domain| domain.trim_end_matches('.').to_lowercase());
+        ip.is_some_and(|ip| self.nets.contains(&ip))
+            || domain
+                .is_some_and(|domain| self.domains.iter().any(|pattern| pattern.matches(&domain)))
+    }
+}
+
+/// Conditions of a rule. Conditions which are not given match everything.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Conditions {
+    dest: Option<Destinations>,
+    ports: Option<Vec<(u16, u16)>>,
+    commands: Option<Vec<Command>>,
+    users: Option<Vec<String>>,
+    sources: Option<IpSet>,
+}
+
+impl Conditions {
+    /// Parse `key=value,value` fields
+    pub(crate) fn parse<'a>(fields: impl Iterator<Item = &'a str>) -> Result<Self, String> {
+        let mut conditions = Conditions::default();
+
+        for field in fields {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("{} is not key=value", field))?;
+            let values = value.split(',').filter(|value| !value.is_empty());
+
+            match key {
+                "dest" => conditions.dest = Some(Destinations::parse(values)),
+                "port" => {
+                    conditions.ports = Some(values.map(parse_port_range).collect::<Result<_, _>>()?)
+                }
+                "command" => {
+                    conditions.commands = Some(values.map(str::parse).collect::<Result<_, _>>()?)
+                }
+                "user" => conditions.users = Some(values.map(str::to_string).collect()),
+                "source" => {
+                    conditions.sources = Some(values.map(parse_net).collect::<Result<_, _>>()?)
+                }
+                _ => return Err(format!("unknown condition {}", key)),
+            }
+        }
+
+        Ok(conditions)
+    }
+
+    /// Whether all conditions match `req`
+    pub(crate) fn matches(&self, req: &Request<'_>) -> bool {
+        self.dest
+            .as_ref()
+            .is_none_or(|dest| dest.matches(req.domain, req.ip))
+            && self.ports.as_ref().is_none_or(|ports| {
+                ports
+                    .iter()
+                    .any(|(first, last)| (*first..=*last).contains(&req.port))
+            })
+            && self.matches_client(req)
+    }
+
+    /// Whether the conditions on the destination are given
+    fn has_destination(&self) -> bool {
+        self.dest.is_some() || self.ports.is_some()
+    }
+
+    /// Whether the conditions on the command, user and source match `req`
+    fn matches_client(&self, req: &Request<'_>) -> bool {
+        self.commands
+            .as_ref()
+            .is_none_or(|commands| commands.contains(&req.command))
+            && self.users.as_ref().is_none_or(|users| {
+                req.user
+                    .is_some_and(|user| users.iter().any(|name| name == user))
+            })
+            && self
+                .sources
+                .as_ref()
+                .is_none_or(|sources| sources.contains(&req.source))
+    }
+}
+
+/// A line of the rules file
+#[derive(Clone, Debug)]
+pub struct Rule {
+    /// Line in the rules file
+    pub line: usize,
+    pub action: Action,
+    text: String,
+    conditions: Conditions,
+}
+
+impl Rule {
+    fn parse(line: usize, text: &str) -> Result<Self, String> {
+        let mut fields = text.split_whitespace();
+        let action = match fields.next() {
+            Some("allow") => Action::Allow,
+            Some("deny") => Action::Deny,
+            Some(action) => return Err(format!("unknown action {}", action)),
+            None => return Err("empty rule".to_string()),
+        };
+
+        Ok(Rule {
+            line,
+            action,
+            text: text.to_string(),
+            conditions: Conditions::parse(fields)?,
+        })
+    }
+
+    /// Whether all conditions of the rule match `req`
+    pub fn matches(&self, req: &Request<'_>) -> bool {
+        self.conditions.matches(req)
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.text)
+    }
+}
+
+/// `PORT` or `FIRST-LAST`
+fn parse_port_range(s: &str) -> Result<(u16, u16), String> {
+    let parse = |port: &str| {
+        port.parse::<u16>()
+            .map_err(|e| format!("port {} cannot be parsed: {}", port, e))
+    };
+
+    match s.split_once('-') {
+        Some((first, last)) => {
+            let (first, last) = (parse(first)?, parse(last)?);
+            if first > last {
+                return Err(format!("port range {} is empty", s));
+            }
+            Ok((first, last))
+        }
+        None => parse(s).map(|port| (port, port)),
+    }
+}
+
+/// Ordered destination access rules.
+///
+/// Each line of a rules file is `allow` or `deny`, followed by conditions:
+/// `dest=` networks, domains, `.suffix` or `*` globs, `port=` ports or ranges,
+/// `command=` connect, bind or udp, `user=` usernames and `source=` client networks.
+/// Values are separated by commas. The first matching rule decides,
+/// requests matching no rule are allowed. `#` starts a comment.
+///
+/// Domains routed via an upstream are not resolved, so only domain patterns match them.
+/// Deny such destinations by domain, `deny dest=10.0.0.0/8` won't stop `intranet.corp`.
+#[derive(Clone, Debug, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    /// Read rules from a file
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        content
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: {}", path, e)))
+    }
+
+    /// First rule matching `req`
+    pub fn check(&self, req: &Request<'_>) -> Option<&Rule> {
+        self.rules.iter().find(|rule| rule.matches(req))
+    }
+
+    /// Whether `req` is allowed
+    pub fn allows(&self, req: &Request<'_>) -> bool {
+        match self.check(req) {
+            Some(rule) if rule.action == Action::Deny => {
+                debug!("Denied by rule {}", rule);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Whether `req` may be allowed for some destination, before its destinations are known.
+    ///
+    /// Rules without destination or port conditions decide as usual. Rules with them can't
+    /// deny the whole command, but allow it for the destinations they match.
+    pub fn allows_command(&self, req: &Request<'_>) -> bool {
+        for rule in &self.rules {
+            if !rule.conditions.matches_client(req) {
+                continue;
+            }
+            match rule.action {
+                Action::Deny if !rule.conditions.has_destination() => {
+                    debug!("Denied by rule {}", rule);
+                    return false;
+                }
+                Action::Deny => {}
+                Action::Allow => return true,
+            }
+        }
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+impl FromStr for Rules {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rules = Vec::new();
+
+        for (i, line) in s.lines().enumerate() {
+            let text = line.split('#').next().unwrap_or_default().trim();
+            if text.is_empty() {
+                continue;
+            }
+            let rule = Rule::parse(i + 1, text).map_err(|e| format!("line {}: {}", i + 1, e))?;
+            rules.push(rule);
+        }
+
+        Ok(Rules { rules })
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Upload and download rates in bytes per second, `None` is unlimited
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Bandwidth {
+    /// From clients to targets
+    pub upload: Option<u64>,
+    /// From targets to clients
+    pub download: Option<u64>,
+}
+
+impl Bandwidth {
+    fn is_unlimited(&self) -> bool {
+        self.upload.is_none() && self.download.is_none()
+    }
+}
+
+/// Rates for all sessions together, for all sessions of a user and of a client address
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BandwidthLimits {
+    pub global: Bandwidth,
+    pub per_user: Bandwidth,
+    pub per_ip: Bandwidth,
+}
+
+/// Token bucket holding up to one second of traffic.
+///
+/// Consumers may overdraw it and then wait until the debt is paid back,
+/// so traffic of any chunk size averages out to the rate.
+pub struct TokenBucket {
+    /// Bytes per second
+    rate: f64,
+    burst: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Full bucket refilled with `rate` bytes per second
+    pub fn new(rate: u64) -> Self {
+        let rate = rate.max(1) as f64;
+        TokenBucket {
+            rate,
+            burst: rate,
+            state: Mutex::new(BucketState {
+                tokens: rate,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take `n` bytes, returning how long to wait before sending them
+    fn reserve(&self, n: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(state.updated).as_secs_f64() * self.rate;
+        state.tokens = (state.tokens + refill).min(self.burst) - n as f64;
+        state.updated = now;
+
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / self.rate)
+        }
+    }
+
+    /// Wait until `n` bytes may be sent
+    pub async fn consume(&self, n: usize) {
+        throttle([self], n).await
+    }
+}
+
+/// Take `n` bytes from all `buckets` and wait for the slowest one
+async fn throttle<'a>(buckets: impl IntoIterator<Item = &'a TokenBucket>, n: usize) {
+    let wait = buckets
+        .into_iter()
+        .map(|bucket| bucket.reserve(n))
+        .max()
+        .unwrap_or_default();
+    if !wait.is_zero() {
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Buckets for both directions of a principal
+struct Buckets {
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+}
+
+impl Buckets {
+    fn new(bandwidth: Bandwidth) -> Self {
+        Buckets {
+            upload: bandwidth.upload.map(TokenBucket::new),
+            download: bandwidth.download.map(TokenBucket::new),
+        }
+    }
+}
+
+/// Buckets of principals with running sessions, dropped with their last session
+struct Registry<K> {
+    bandwidth: Bandwidth,
+    buckets: Mutex<HashMap<K, Weak<Buckets>>>,
+}
+
+impl<K: Eq + Hash> Registry<K> {
+    fn new(bandwidth: Bandwidth) -> Self {
+        Registry {
+            bandwidth,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, key: K) -> Option<Arc<Buckets>> {
+        if self.bandwidth.is_unlimited() {
+            return None;
+        }
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if let Some(shared) = buckets.get(&key).and_then(Weak::upgrade) {
+            return Some(shared);
+        }
+
+        buckets.retain(|_, shared| shared.strong_count() > 0);
+        let shared = Arc::new(Buckets::new(self.bandwidth));
+        buckets.insert(key, Arc::downgrade(&shared));
+        Some(shared)
+    }
+}
+
+/// Token buckets shared by all sessions of the same principal
+pub struct Shaper {
+    global: Arc<Buckets>,
+    users: Registry<String>,
+    ips: Registry<IpAddr>,
+}
+
+impl Shaper {
+    pub fn new(limits: BandwidthLimits) -> Self {
+        Shaper {
+            global: Arc::new(Buckets::new(limits.global)),
+            users: Registry::new(limits.per_user),
+            ips: Registry::new(limits.per_ip),
+        }
+    }
+
+    /// Shaping for a session of `user` from `ip`
+    pub fn session(&self, user: Option<&str>, ip: IpAddr) -> SessionShaper {
+        let mut buckets = vec![self.global.clone()];
+        buckets.extend(user.and_then(|user| self.users.get(user.to_string())));
+        buckets.extend(self.ips.get(ip.to_canonical()));
+
+        SessionShaper { buckets }
+    }
+}
+
+impl Default for Shaper {
+    fn default() -> Self {
+        Self::new(BandwidthLimits::default())
+    }
+}
+
+/// Limits of a single session
+pub struct SessionShaper {
+    buckets: Vec<Arc<Buckets>>,
+}
+
+impl SessionShaper {
+    /// Wait until `n` bytes may be sent from the client to the target
+    pub async fn upload(&self, n: usize) {
+        throttle(self.buckets.iter().filter_map(|b| b.upload.as_ref()), n).await
+    }
+
+    /// Wait until `n` bytes may be sent from the target to the client
+    pub async fn download(&self, n: usize) {
+        throttle(self.buckets.iter().filter_map(|b| b.download.as_ref()), n).await
+    }
+}
+
+/// Parse a rate in bytes per second, with an optional K, M or G suffix (powers of 1024)
+pub fn parse_rate(s: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match s.trim().to_uppercase() {
+        s if s.ends_with('K') => (s[..s.len() - 1].to_string(), 1 << 10),
+        s if s.ends_with('M') => (s[..s.len() - 1].to_string(), 1 << 20),
+        s if s.ends_with('G') => (s[..s.len() - 1].to_string(), 1 << 30),
+        s => (s, 1),
+    };
+
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|rate| rate.checked_mul(multiplier))
+        .filter(|rate| *rate > 0)
+        .ok_or_else(|| format!("{} is not a rate like 512K or 10M", s))
+}
@@ -0,0 +1,152 @@
+use crate::*;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Longest USERID or domain name accepted in a SOCKS4 request
+const MAX_FIELD_LEN: usize = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// Possible SOCKS4 Reply Codes
+pub enum Socks4Code {
+    /// Request granted
+    Granted = 0x5A,
+    /// Request rejected or failed
+    Rejected = 0x5B,
+    /// Request rejected because SOCKS server cannot connect to identd on the client
+    IdentdUnreachable = 0x5C,
+    /// Request rejected because the client program and identd report different user-ids
+    UserIdMismatch = 0x5D,
+}
+
+impl From<ResponseCode> for Socks4Code {
+    fn from(code: ResponseCode) -> Self {
+        match code {
+            ResponseCode::Success => Socks4Code::Granted,
+            _ => Socks4Code::Rejected,
+        }
+    }
+}
+
+pub struct Socks4Reply {
+    // From the SOCKS4 protocol, the server replies with:
+    //
+    //    +----+----+----+----+----+----+----+----+
+    //    | VN | CD | DSTPORT |      DSTIP        |
+    //    +----+----+----+----+----+----+----+----+
+    //       1    1      2              4
+    //
+    // Where:
+    //
+    //      o  VN     version of the reply code, X'00'
+    //      o  CD     result code (see Socks4Code)
+    //      o  DSTPORT, DSTIP   bound address for BIND, ignored otherwise
+    //
+    buf: [u8; 8],
+}
+
+impl Socks4Reply {
+    pub fn new(status: Socks4Code) -> Self {
+        Self {
+            buf: [0, status as u8, 0, 0, 0, 0, 0, 0],
+        }
+    }
+
+    /// Create a reply carrying `addr` as DSTIP and DSTPORT.
+    /// SOCKS4 can't express IPv6 addresses, those are sent as zeros.
+    pub fn with_addr(status: Socks4Code, addr: SocketAddr) -> Self {
+        let mut reply = Self::new(status);
+        if let SocketAddr::V4(addr) = addr {
+            reply.buf[2..4].copy_from_slice(&addr.port().to_be_bytes());
+            reply.buf[4..8].copy_from_slice(&addr.ip().octets());
+        }
+        reply
+    }
+
+    pub async fn send<T>(&self, stream: &mut T) -> io::Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        stream.write_all(&self.buf[..]).await?;
+        Ok(())
+    }
+}
+
+/// Read a NULL terminated field
+async fn read_field<T>(stream: &mut T) -> Result<Vec<u8>, MerinoError>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut field = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            0 => return Ok(field),
+            _ if field.len() == MAX_FIELD_LEN => {
+                warn!("SOCKS4 field is too long");
+                return Err(MerinoError::Socks(ResponseCode::Failure));
+            }
+            byte => field.push(byte),
+        }
+    }
+}
+
+/// Parse the rest of a SOCKS4 request, after the VN and CD bytes.
+/// Returns the request and the USERID.
+pub(crate) async fn read_request<T>(
+    stream: &mut T,
+    command: u8,
+) -> Result<(SOCKSReq, String), MerinoError>
+where
+    T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    // From the SOCKS4 protocol, the request is formed as follows:
+    //
+    //    +----+----+----+----+----+----+----+----+----+----+....+----+
+    //    | VN | CD | DSTPORT |      DSTIP        | USERID       |NULL|
+    //    +----+----+----+----+----+----+----+----+----+----+....+----+
+    //       1    1      2              4           variable       1
+    //
+    // Where:
+    //
+    //      o  VN     protocol version: X'04'
+    //      o  CD
+    //         o  CONNECT X'01'
+    //         o  BIND X'02'
+    //
+    // SOCKS4a clients, which can't resolve the destination, set DSTIP to 0.0.0.x
+    // with nonzero x, and send the domain name after USERID, terminated by NULL
+    let command = match command {
+        1 => SockCommand::Connect,
+        2 => SockCommand::Bind,
+        _ => {
+            warn!("Invalid SOCKS4 Command");
+            return Err(MerinoError::Socks(ResponseCode::CommandNotSupported));
+        }
+    };
+
+    let mut packet = [0u8; 6];
+    stream.read_exact(&mut packet).await?;
+    trace!("Server received {:?}", packet);
+
+    let port = u16::from_be_bytes([packet[0], packet[1]]);
+    let ip = &packet[2..6];
+
+    let user_id = read_field(stream).await?;
+    let user_id = String::from_utf8_lossy(&user_id).to_string();
+
+    let (addr_type, addr) = if ip[..3] == [0, 0, 0] && ip[3] != 0 {
+        (AddrType::Domain, read_field(stream).await?)
+    } else {
+        (AddrType::V4, ip.to_vec())
+    };
+
+    Ok((
+        SOCKSReq {
+            version: SOCKS4_VERSION,
+            command,
+            addr_type,
+            addr,
+            port,
+        },
+        user_id,
+    ))
+}
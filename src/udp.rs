@@ -0,0 +1,405 @@
+use crate::relay::{idle_watchdog, lifetime_watchdog, Direction, Limits};
+use crate::*;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+/// Maximum size of a UDP datagram
+const MAX_DATAGRAM: usize = 65_535;
+
+/// Maximum number of domains resolved at once for an association, datagrams
+/// to further domains are dropped
+const MAX_LOOKUPS: usize = 64;
+
+/// Targets an association remembers, the least recently used are forgotten beyond this
+const MAX_TARGETS: usize = 1024;
+
+/// Parsed UDP request header
+pub(crate) struct UdpHeader {
+    pub frag: u8,
+    pub addr_type: AddrType,
+    pub addr: Vec<u8>,
+    pub port: u16,
+}
+
+impl UdpHeader {
+    /// Parse a UDP request header, returning it with the offset of DATA
+    pub fn parse(packet: &[u8]) -> Option<(Self, usize)> {
+        // From rfc 1928 (S7), each UDP datagram carries a UDP request header with it:
+        //
+        //    +----+------+------+----------+----------+----------+
+        //    |RSV | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
+        //    +----+------+------+----------+----------+----------+
+        //    | 2  |  1   |  1   | Variable |    2     | Variable |
+        //    +----+------+------+----------+----------+----------+
+        //
+        // Where:
+        //
+        //      o  RSV  Reserved X'0000'
+        //      o  FRAG    Current fragment number
+        //      o  ATYP    address type of following addresses:
+        //         o  IP V4 address: X'01'
+        //         o  DOMAINNAME: X'03'
+        //         o  IP V6 address: X'04'
+        //      o  DST.ADDR       desired destination address
+        //      o  DST.PORT       desired destination port
+        //      o  DATA     user data
+        if packet.len() < 4 {
+            return None;
+        }
+
+        let frag = packet[2];
+        let addr_type = AddrType::from(packet[3] as usize)?;
+
+        let (addr_start, addr_len) = match addr_type {
+            AddrType::V4 => (4, 4),
+            AddrType::V6 => (4, 16),
+            AddrType::Domain => (5, *packet.get(4)? as usize),
+        };
+        let port_start = addr_start + addr_len;
+        let data_start = port_start + 2;
+        if packet.len() < data_start {
+            return None;
+        }
+
+        let header = UdpHeader {
+            frag,
+            addr_type,
+            addr: packet[addr_start..port_start].to_vec(),
+            port: u16::from_be_bytes([packet[port_start], packet[port_start + 1]]),
+        };
+
+        Some((header, data_start))
+    }
+
+    /// Build a UDP request header for a datagram received from `addr`
+    pub fn encode(addr: SocketAddr) -> Vec<u8> {
+        // RSV, FRAG
+        let mut buf = vec![RESERVED, RESERVED, 0];
+        encode_addr(&mut buf, addr);
+        buf
+    }
+}
+
+/// Receive from an optional socket, never completing if there is none
+async fn recv_from(socket: &Option<UdpSocket>, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Addresses the client has sent to, which are allowed to answer.
+///
+/// Only the most recently used are kept, so an association which sends to ever new
+/// addresses doesn't grow without bound.
+#[derive(Default)]
+struct Targets {
+    /// Latest use of each target
+    used: HashMap<SocketAddr, u64>,
+    /// Targets by their latest use, least recent first
+    by_use: BTreeMap<u64, SocketAddr>,
+    uses: u64,
+}
+
+impl Targets {
+    /// Remember that the client sent to `target`
+    fn insert(&mut self, target: SocketAddr) {
+        self.uses += 1;
+        if let Some(previous) = self.used.insert(target, self.uses) {
+            self.by_use.remove(&previous);
+        }
+        self.by_use.insert(self.uses, target);
+
+        if self.used.len() > MAX_TARGETS {
+            if let Some((_, oldest)) = self.by_use.pop_first() {
+                trace!("UDP ASSOCIATE: forgetting target {}", oldest);
+                self.used.remove(&oldest);
+            }
+        }
+    }
+
+    fn contains(&self, addr: &SocketAddr) -> bool {
+        self.used.contains_key(addr)
+    }
+}
+
+/// Sockets reaching the targets of an association
+struct TargetSockets {
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+    targets: Targets,
+}
+
+impl TargetSockets {
+    /// Send a datagram of the client to the first permitted address of its destination.
+    ///
+    /// Datagrams which can't be delivered are dropped, UDP makes no promises anyway.
+    async fn send<P>(
+        &mut self,
+        header: &UdpHeader,
+        addrs: io::Result<Vec<SocketAddr>>,
+        data: &[u8],
+        permit: &P,
+        traffic: &Traffic<'_>,
+    ) where
+        P: Fn(Option<&str>, SocketAddr) -> bool,
+    {
+        let addrs = match addrs {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                warn!("UDP ASSOCIATE: {}", e);
+                return;
+            }
+        };
+        let domain = match header.addr_type {
+            AddrType::Domain => Some(String::from_utf8_lossy(&header.addr).to_string()),
+            _ => None,
+        };
+        let target = match addrs
+            .into_iter()
+            .find(|addr| permit(domain.as_deref(), *addr))
+        {
+            Some(target) => target,
+            None => {
+                warn!(
+                    "UDP ASSOCIATE: datagram to {}:{} denied",
+                    pretty_print_addr(&header.addr_type, &header.addr),
+                    header.port
+                );
+                return;
+            }
+        };
+
+        let socket = if target.is_ipv4() { &self.v4 } else { &self.v6 };
+        match socket {
+            Some(socket) => {
+                trace!("UDP ASSOCIATE: sending to {}", target);
+                self.targets.insert(target);
+                traffic.upload(data.len()).await;
+                if let Err(e) = socket.send_to(data, target).await {
+                    debug!("UDP ASSOCIATE: dropping datagram to {}: {}", target, e);
+                }
+            }
+            None => warn!("UDP ASSOCIATE: no socket available to reach {}", target),
+        }
+    }
+}
+
+/// Relay datagrams between the client and targets until the control connection is closed,
+/// the association is idle, reaches its maximum lifetime or exhausts the quota.
+///
+/// `client` is the address the client announced in the request. Port 0 means that
+/// the port is learned from the first datagram. Datagrams are only sent to targets
+/// for which `permit` returns true, from the sources chosen by the egress of `context`.
+pub(crate) async fn relay<T, P>(
+    control: &mut T,
+    socket: UdpSocket,
+    client: SocketAddr,
+    context: &ConnectContext<'_>,
+    timeouts: Timeouts,
+    permit: P,
+    limits: &Limits,
+) -> SessionStats
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    P: Fn(Option<&str>, SocketAddr) -> bool,
+{
+    let start = Instant::now();
+    let up = Direction::new(start);
+    let down = Direction::new(start);
+    let traffic = Traffic {
+        up: &up,
+        down: &down,
+        limits,
+    };
+
+    let reason = tokio::select! {
+        result = forward(control, &socket, client, context, &permit, traffic) => match result {
+            Ok(()) => CloseReason::Eof,
+            Err(e) => {
+                debug!("UDP ASSOCIATE: {}", e);
+                CloseReason::Error
+            }
+        },
+        _ = idle_watchdog(timeouts.idle, &up, &down) => {
+            debug!("UDP ASSOCIATE: closing idle association");
+            CloseReason::Idle
+        },
+        _ = lifetime_watchdog(start, timeouts.lifetime) => CloseReason::Lifetime,
+        _ = limits.quota.exhausted() => CloseReason::Quota,
+    };
+
+    SessionStats {
+        upload: up.bytes(),
+        download: down.bytes(),
+        duration: start.elapsed(),
+        reason,
+    }
+}
+
+/// Accounting and shaping of an association
+struct Traffic<'a> {
+    up: &'a Direction,
+    down: &'a Direction,
+    limits: &'a Limits,
+}
+
+impl Traffic<'_> {
+    /// Wait until `n` bytes may be sent to a target and account them
+    async fn upload(&self, n: usize) {
+        self.limits.upload(n).await;
+        self.up.transferred(n);
+    }
+
+    /// Account `n` bytes sent to the client and wait until more may be sent
+    async fn download(&self, n: usize) {
+        self.down.transferred(n);
+        self.limits.download(n).await;
+    }
+}
+
+/// Forward datagrams in both directions until the control connection is closed.
+///
+/// Only errors of the client facing socket end the association, datagrams which
+/// can't be sent or received on the way are dropped.
+async fn forward<T, P>(
+    control: &mut T,
+    socket: &UdpSocket,
+    mut client: SocketAddr,
+    context: &ConnectContext<'_>,
+    permit: &P,
+    traffic: Traffic<'_>,
+) -> io::Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    P: Fn(Option<&str>, SocketAddr) -> bool,
+{
+    // Targets are reached from separate sockets, so the client facing one
+    // only ever has to deal with the client
+    let bind = |unspecified: IpAddr| async move {
+        context
+            .egress
+            .udp_socket(unspecified, context.user, context.client.ip())
+            .await
+            .map_err(|e| debug!("UDP ASSOCIATE: no socket for targets: {}", e))
+            .ok()
+    };
+    let mut outbound = TargetSockets {
+        v4: bind(Ipv4Addr::UNSPECIFIED.into()).await,
+        v6: bind(Ipv6Addr::UNSPECIFIED.into()).await,
+        targets: Targets::default(),
+    };
+    let resolver = context.resolver;
+
+    // Domains are resolved while datagrams keep flowing
+    let mut lookups = FuturesUnordered::new();
+
+    let mut control_buf = [0u8; 1];
+    let mut client_buf = vec![0u8; MAX_DATAGRAM];
+    let mut target_buf_v4 = vec![0u8; MAX_DATAGRAM];
+    let mut target_buf_v6 = vec![0u8; MAX_DATAGRAM];
+
+    loop {
+        tokio::select! {
+            // The association terminates when the TCP connection terminates
+            read = control.read(&mut control_buf) => match read {
+                Ok(0) | Err(_) => {
+                    trace!("UDP ASSOCIATE: control connection closed");
+                    return Ok(());
+                }
+                Ok(_) => trace!("UDP ASSOCIATE: ignoring data on control connection"),
+            },
+            received = socket.recv_from(&mut client_buf) => {
+                let (len, src) = received?;
+
+                if src.ip() != client.ip() || (client.port() != 0 && src.port() != client.port()) {
+                    debug!("UDP ASSOCIATE: dropping datagram from unexpected source {}", src);
+                    continue;
+                }
+                if client.port() == 0 {
+                    trace!("UDP ASSOCIATE: client port is {}", src.port());
+                    client = src;
+                }
+
+                let (header, data_start) = match UdpHeader::parse(&client_buf[..len]) {
+                    Some(parsed) => parsed,
+                    None => {
+                        warn!("UDP ASSOCIATE: malformed datagram from {}", src);
+                        continue;
+                    }
+                };
+
+                if header.frag != 0 {
+                    warn!("UDP ASSOCIATE: dropping fragmented datagram from {}", src);
+                    continue;
+                }
+
+                let data = &client_buf[data_start..len];
+                if header.addr_type != AddrType::Domain {
+                    let addrs = addr_to_socket(resolver, &header.addr_type, &header.addr, header.port).await;
+                    outbound.send(&header, addrs, data, permit, &traffic).await;
+                } else if lookups.len() < MAX_LOOKUPS {
+                    let data = data.to_vec();
+                    lookups.push(async move {
+                        let addrs = addr_to_socket(resolver, &header.addr_type, &header.addr, header.port).await;
+                        (header, addrs, data)
+                    });
+                } else {
+                    warn!("UDP ASSOCIATE: too many pending lookups, dropping datagram from {}", src);
+                }
+            },
+            Some((header, addrs, data)) = lookups.next(), if !lookups.is_empty() => {
+                outbound.send(&header, addrs, &data, permit, &traffic).await;
+            },
+            received = recv_from(&outbound.v4, &mut target_buf_v4) => match received {
+                Ok((len, src)) => {
+                    let data = &target_buf_v4[..len];
+                    let sent = forward_to_client(socket, client, &outbound.targets, src, data).await;
+                    traffic.download(sent).await;
+                }
+                Err(e) => debug!("UDP ASSOCIATE: receiving from targets failed: {}", e),
+            },
+            received = recv_from(&outbound.v6, &mut target_buf_v6) => match received {
+                Ok((len, src)) => {
+                    let data = &target_buf_v6[..len];
+                    let sent = forward_to_client(socket, client, &outbound.targets, src, data).await;
+                    traffic.download(sent).await;
+                }
+                Err(e) => debug!("UDP ASSOCIATE: receiving from targets failed: {}", e),
+            },
+        }
+    }
+}
+
+/// Wrap a datagram from a target with a UDP request header and send it to the client,
+/// returning the number of bytes of data sent
+async fn forward_to_client(
+    socket: &UdpSocket,
+    client: SocketAddr,
+    targets: &Targets,
+    src: SocketAddr,
+    data: &[u8],
+) -> usize {
+    if !targets.contains(&src) || client.port() == 0 {
+        debug!(
+            "UDP ASSOCIATE: dropping datagram from unknown source {}",
+            src
+        );
+        return 0;
+    }
+
+    trace!("UDP ASSOCIATE: {} -> {}", src, client);
+    let mut packet = UdpHeader::encode(src);
+    packet.extend_from_slice(data);
+    match socket.send_to(&packet, client).await {
+        Ok(_) => data.len(),
+        Err(e) => {
+            debug!("UDP ASSOCIATE: dropping datagram to {}: {}", client, e);
+            0
+        }
+    }
+}
@@ -0,0 +1,461 @@
+use crate::connect::{happy_eyeballs, ConnectContext, Connector, Outbound, Target};
+use crate::{encode_addr, AddrType, AuthMethods, SOCKS4_VERSION, SOCKS_VERSION};
+use async_trait::async_trait;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Longest response header accepted from HTTP proxies
+const MAX_HTTP_HEADER: usize = 16 * 1024;
+
+/// Protocol spoken to an upstream proxy
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Socks5,
+    Socks4a,
+    /// HTTP CONNECT
+    Http,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "socks5" => Ok(Protocol::Socks5),
+            "socks4a" => Ok(Protocol::Socks4a),
+            "http" => Ok(Protocol::Http),
+            _ => Err(format!(
+                "unknown proxy protocol {}, expected socks5, socks4a or http",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Socks5 => f.write_str("socks5"),
+            Protocol::Socks4a => f.write_str("socks4a"),
+            Protocol::Http => f.write_str("http"),
+        }
+    }
+}
+
+/// Upstream proxy, parsed from `PROTOCOL://[USER[:PASSWORD]@]HOST:PORT`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Proxy {
+    pub protocol: Protocol,
+    pub host: String,
+    pub port: u16,
+    /// SOCKS5 and HTTP username, SOCKS4a user id
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl FromStr for Proxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (protocol, rest) = s
+            .split_once("://")
+            .ok_or_else(|| format!("{} is not like socks5://host:port", s))?;
+        let protocol = protocol.parse()?;
+
+        // Passwords may contain `@` and `:`, hosts don't
+        let (credentials, authority) = match rest.trim_end_matches('/').rsplit_once('@') {
+            Some((credentials, authority)) => (Some(credentials), authority),
+            None => (None, rest.trim_end_matches('/')),
+        };
+        let (username, password) = match credentials.map(|c| c.split_once(':')) {
+            Some(Some((username, password))) => {
+                (Some(username.to_string()), Some(password.to_string()))
+            }
+            Some(None) => (credentials.map(str::to_string), None),
+            None => (None, None),
+        };
+
+        let (host, port) = authority
+            .rsplit_once(':')
+            .ok_or_else(|| format!("port of proxy {} is missing", authority))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("host of proxy {} is missing", authority));
+        }
+        let port = port
+            .parse()
+            .map_err(|e| format!("port of proxy {} cannot be parsed: {}", authority, e))?;
+
+        Ok(Proxy {
+            protocol,
+            host: host.to_string(),
+            port,
+            username,
+            password,
+        })
+    }
+}
+
+/// Without the password
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}://", self.protocol)?;
+        if let Some(username) = &self.username {
+            write!(f, "{}@", username)?;
+        }
+        match self.host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => write!(f, "[{}]:{}", ip, self.port),
+            _ => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+impl Proxy {
+    fn target(&self) -> Target {
+        Target::new(&self.host, self.port)
+    }
+
+    /// Ask the proxy at the other end of `stream` to connect to `target`
+    async fn handshake(&self, stream: &mut TcpStream, target: &Target) -> io::Result<()> {
+        match self.protocol {
+            Protocol::Socks5 => self.socks5(stream, target).await,
+            Protocol::Socks4a => self.socks4a(stream, target).await,
+            Protocol::Http => self.http(stream, target).await,
+        }
+    }
+
+    async fn socks5(&self, stream: &mut TcpStream, target: &Target) -> io::Result<()> {
+        let mut greeting = vec![SOCKS_VERSION, 1, AuthMethods::NoAuth as u8];
+        if self.username.is_some() {
+            greeting[1] = 2;
+            greeting.push(AuthMethods::UserPass as u8);
+        }
+        stream.write_all(&greeting).await?;
+
+        let mut method = [0u8; 2];
+        stream.read_exact(&mut method).await?;
+        if method[0] != SOCKS_VERSION {
+            return Err(invalid(format!("unexpected SOCKS version {}", method[0])));
+        }
+        match method[1] {
+            m if m == AuthMethods::NoAuth as u8 => {}
+            m if m == AuthMethods::UserPass as u8 && self.username.is_some() => {
+                self.socks5_login(stream).await?
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "no acceptable authentication method",
+                ))
+            }
+        }
+
+        let mut request = vec![SOCKS_VERSION, 1, 0];
+        match target {
+            Target::Addr(addr) => encode_addr(&mut request, *addr),
+            Target::Domain(domain, port) => {
+                let len = u8::try_from(domain.len())
+                    .map_err(|_| invalid(format!("domain {} is too long", domain)))?;
+                request.extend_from_slice(&[AddrType::Domain as u8, len]);
+                request.extend_from_slice(domain.as_bytes());
+                request.extend_from_slice(&port.to_be_bytes());
+            }
+        }
+        stream.write_all(&request).await?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != SOCKS_VERSION {
+            return Err(invalid(format!("unexpected SOCKS version {}", reply[0])));
+        }
+        if reply[1] != 0 {
+            return Err(refused(
+                socks5_error_kind(reply[1]),
+                format!("upstream replied {}", reply[1]),
+            ));
+        }
+        // BND.ADDR and BND.PORT are not needed
+        let len = match reply[3] {
+            atyp if atyp == AddrType::V4 as u8 => 4,
+            atyp if atyp == AddrType::V6 as u8 => 16,
+            atyp if atyp == AddrType::Domain as u8 => stream.read_u8().await? as usize,
+            atyp => return Err(invalid(format!("unknown address type {}", atyp))),
+        };
+        let mut bound = vec![0u8; len + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(())
+    }
+
+    /// Username/password sub-negotiation from RFC 1929
+    async fn socks5_login(&self, stream: &mut TcpStream) -> io::Result<()> {
+        let username = self.username.as_deref().unwrap_or_default();
+        let password = self.password.as_deref().unwrap_or_default();
+        let too_long = || invalid("username or password is too long".to_string());
+
+        let mut login = vec![1, u8::try_from(username.len()).map_err(|_| too_long())?];
+        login.extend_from_slice(username.as_bytes());
+        login.push(u8::try_from(password.len()).map_err(|_| too_long())?);
+        login.extend_from_slice(password.as_bytes());
+        stream.write_all(&login).await?;
+
+        let mut status = [0u8; 2];
+        stream.read_exact(&mut status).await?;
+        if status[1] != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("login as {} failed", username),
+            ));
+        }
+        Ok(())
+    }
+
+    async fn socks4a(&self, stream: &mut TcpStream, target: &Target) -> io::Result<()> {
+        let mut request = vec![SOCKS4_VERSION, 1];
+        request.extend_from_slice(&target.port().to_be_bytes());
+        // 0.0.0.x with a non-zero x tells that a domain follows the user id
+        let (ip, domain) = match target {
+            Target::Addr(SocketAddr::V4(addr)) => (addr.ip().octets(), None),
+            Target::Addr(addr) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("SOCKS4a can't connect to {}", addr),
+                ))
+            }
+            Target::Domain(domain, _) => ([0, 0, 0, 1], Some(domain)),
+        };
+        request.extend_from_slice(&ip);
+        request.extend_from_slice(self.username.as_deref().unwrap_or_default().as_bytes());
+        request.push(0);
+        if let Some(domain) = domain {
+            request.extend_from_slice(domain.as_bytes());
+            request.push(0);
+        }
+        stream.write_all(&request).await?;
+
+        let mut reply = [0u8; 8];
+        stream.read_exact(&mut reply).await?;
+        match reply[1] {
+            0x5A => Ok(()),
+            code => Err(refused(
+                io::ErrorKind::ConnectionRefused,
+                format!("upstream rejected the request with {:#x}", code),
+            )),
+        }
+    }
+
+    async fn http(&self, stream: &mut TcpStream, target: &Target) -> io::Result<()> {
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+        if let Some(username) = &self.username {
+            let credentials = format!(
+                "{}:{}",
+                username,
+                self.password.as_deref().unwrap_or_default()
+            );
+            request.push_str(&format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64(credentials.as_bytes())
+            ));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        let header = read_http_header(stream).await?;
+
+        let header = String::from_utf8_lossy(&header);
+        let status_line = header.lines().next().unwrap_or_default();
+        let status: u16 = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| invalid(format!("unexpected response {}", status_line)))?;
+        let message = format!("upstream replied {}", status_line);
+        match status {
+            200..=299 => Ok(()),
+            // The proxy wants other credentials, the target is not to blame
+            407 => Err(io::Error::new(io::ErrorKind::PermissionDenied, message)),
+            403 => Err(refused(io::ErrorKind::PermissionDenied, message)),
+            504 => Err(refused(io::ErrorKind::TimedOut, message)),
+            _ => Err(refused(io::ErrorKind::Other, message)),
+        }
+    }
+}
+
+/// Read a response header up to the empty line. The tunnel starts right after it, so what
+/// follows is peeked at first and only the header is taken from the stream.
+async fn read_http_header(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut header = Vec::new();
+    let mut buf = vec![0u8; 1024];
+    loop {
+        let len = stream.peek(&mut buf).await?;
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        // The empty line may start in what was taken before
+        let start = header.len().saturating_sub(3);
+        let taken = header.len();
+        header.extend_from_slice(&buf[..len]);
+        let end = header[start..]
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map(|position| start + position + 4);
+
+        let header_end = end.unwrap_or(header.len());
+        if header_end > MAX_HTTP_HEADER {
+            return Err(invalid("response header is too long".to_string()));
+        }
+        header.truncate(header_end);
+        stream.read_exact(&mut buf[..header_end - taken]).await?;
+        if end.is_some() {
+            return Ok(header);
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A proxy answered that it can't or won't connect to the requested target
+#[derive(Debug)]
+struct Refused(String);
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Refused {}
+
+fn refused(kind: io::ErrorKind, message: String) -> io::Error {
+    io::Error::new(kind, Refused(message))
+}
+
+/// The last proxy of a chain refused to connect to the target, so the chain itself works
+pub(crate) fn is_refused(error: &io::Error) -> bool {
+    error.get_ref().is_some_and(|e| e.is::<Refused>())
+}
+
+/// Error kind for a REP field, so it is passed on to the client as it was
+fn socks5_error_kind(rep: u8) -> io::ErrorKind {
+    match rep {
+        2 => io::ErrorKind::PermissionDenied,
+        3 => io::ErrorKind::NetworkUnreachable,
+        4 => io::ErrorKind::HostUnreachable,
+        5 => io::ErrorKind::ConnectionRefused,
+        6 => io::ErrorKind::TimedOut,
+        _ => io::ErrorKind::Other,
+    }
+}
+
+/// Standard base64 with padding, for HTTP basic authentication
+fn base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
+
+/// Named chain of upstream proxies, parsed from `NAME=PROXY[,PROXY...]`.
+/// Connections go through the proxies in order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Upstream {
+    pub name: String,
+    pub hops: Vec<Proxy>,
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, hops) = s
+            .split_once('=')
+            .ok_or_else(|| format!("{} is not like NAME=socks5://host:port", s))?;
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(format!("name of upstream {} is missing", s));
+        }
+        let hops: Vec<Proxy> = hops
+            .split(',')
+            .map(str::trim)
+            .filter(|hop| !hop.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        if hops.is_empty() {
+            return Err(format!("upstream {} has no proxies", name));
+        }
+
+        Ok(Upstream {
+            name: name.to_string(),
+            hops,
+        })
+    }
+}
+
+impl Upstream {
+    /// Open a TCP connection to the first proxy of the chain
+    pub(crate) async fn dial(&self, context: &ConnectContext<'_>) -> io::Result<TcpStream> {
+        let first = &self.hops[0];
+        let addrs = match first.target() {
+            Target::Addr(addr) => vec![addr],
+            Target::Domain(domain, port) => context.resolver.lookup(&domain, port).await?,
+        };
+        happy_eyeballs(&addrs, context)
+            .await
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", first, e)))
+    }
+}
+
+#[async_trait]
+impl Connector for Upstream {
+    /// Connect to `target` through all proxies of the chain
+    async fn connect(&self, target: &Target, context: &ConnectContext<'_>) -> io::Result<Outbound> {
+        let mut stream = self.dial(context).await?;
+
+        // Every proxy connects to the next one, the last one to the target
+        let next_targets = self.hops[1..]
+            .iter()
+            .map(Proxy::target)
+            .chain(std::iter::once(target.clone()));
+        let last = self.hops.len() - 1;
+        for (i, (hop, next)) in self.hops.iter().zip(next_targets).enumerate() {
+            trace!("Asking {} to connect to {}", hop, next);
+            if let Err(e) = hop.handshake(&mut stream, &next).await {
+                let message = format!("{}: {}", hop, e);
+                // Refusals of hops before the last one are failures of the chain
+                return Err(if i == last && is_refused(&e) {
+                    refused(e.kind(), message)
+                } else {
+                    io::Error::new(e.kind(), message)
+                });
+            }
+        }
+
+        Ok(stream.into())
+    }
+
+    /// The last proxy resolves domains in its network
+    fn resolves_remotely(&self) -> bool {
+        true
+    }
+}
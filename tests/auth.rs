@@ -0,0 +1,100 @@
+mod support;
+
+use merino::*;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Start a USER/PASS proxy with alice, returning its bans
+async fn proxy_with_bans() -> (SocketAddr, std::sync::Arc<Bans>) {
+    let merino = support::proxy(
+        vec![AuthMethods::UserPass as u8],
+        vec![User::new("alice", "secret")],
+    )
+    .await;
+    let bans = merino.get_bans();
+    (support::serve(merino), bans)
+}
+
+/// Offer USER/PASS and send `request` as the sub-negotiation
+async fn userpass(proxy: SocketAddr, request: &[u8]) -> (TcpStream, [u8; 2]) {
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(&[5, 1, 2]).await.unwrap();
+    let mut method = [0u8; 2];
+    client.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [5, AuthMethods::UserPass as u8]);
+
+    client.write_all(request).await.unwrap();
+    let mut status = [0u8; 2];
+    client.read_exact(&mut status).await.unwrap();
+    (client, status)
+}
+
+/// Nothing but EOF follows
+async fn assert_closed(client: &mut TcpStream) {
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty(), "unexpected bytes {:?}", rest);
+    // The rejection is recorded once the handler returns
+    tokio::time::sleep(Duration::from_millis(50)).await;
+}
+
+fn localhost() -> Vec<IpAddr> {
+    vec!["127.0.0.1".parse().unwrap()]
+}
+
+#[tokio::test]
+async fn granted() {
+    let target = support::start_echo().await;
+    let (proxy, bans) = proxy_with_bans().await;
+
+    let (mut client, status) = userpass(proxy, b"\x01\x05alice\x06secret").await;
+    assert_eq!(status, [1, ResponseCode::Success as u8]);
+
+    let reply = support::request(&mut client, 1, &support::encode_addr(target)).await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+    assert!(bans.rejected().is_empty());
+}
+
+#[tokio::test]
+/// Denied clients get the failure status and the connection is closed without a SOCKS reply
+async fn denied() {
+    let (proxy, bans) = proxy_with_bans().await;
+
+    let (mut client, status) = userpass(proxy, b"\x01\x05alice\x05guess").await;
+    assert_eq!(status, [1, ResponseCode::Failure as u8]);
+    assert_closed(&mut client).await;
+    assert_eq!(bans.rejected(), localhost());
+}
+
+#[tokio::test]
+async fn no_acceptable_method() {
+    let (proxy, bans) = proxy_with_bans().await;
+
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut method = [0u8; 2];
+    client.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [5, AuthMethods::NoMethods as u8]);
+    assert_closed(&mut client).await;
+    assert_eq!(bans.rejected(), localhost());
+}
+
+#[tokio::test]
+/// Sub-negotiation versions other than 1 are refused before reading credentials
+async fn unsupported_version() {
+    let (proxy, bans) = proxy_with_bans().await;
+
+    let (mut client, status) = userpass(proxy, b"\x05\x05").await;
+    assert_eq!(status, [1, ResponseCode::Failure as u8]);
+    assert_closed(&mut client).await;
+    assert_eq!(bans.rejected(), localhost());
+    assert!(bans.bans().is_empty());
+}
+
+#[test]
+fn denied_is_rule_failure() {
+    let error = MerinoError::Denied(AuthFailure::NoAcceptableMethod);
+    assert_eq!(ResponseCode::from(error), ResponseCode::RuleFailure);
+}
@@ -0,0 +1,190 @@
+mod support;
+
+use async_trait::async_trait;
+use merino::*;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Hash of "secret" with cheap parameters
+const ARGON2: &str =
+    "$argon2id$v=19$m=64,t=1,p=1$bWVyaW5vLXRlc3Qtc2FsdA$Gz0H6PAQC5r/v21yM7nQWHJ2LAqb8LZzbJQoJuz/z6A";
+
+fn client() -> SocketAddr {
+    "127.0.0.1:4000".parse().unwrap()
+}
+
+#[tokio::test]
+async fn memory() {
+    let auth =
+        MemoryAuthenticator::new(vec![User::new("alice", ARGON2), User::new("bob", "plain")]);
+
+    let identity = auth.authenticate("alice", "secret", client()).await;
+    assert_eq!(identity.unwrap().username, "alice");
+    assert!(auth.authenticate("bob", "plain", client()).await.is_some());
+    assert!(auth
+        .authenticate("alice", "plain", client())
+        .await
+        .is_none());
+    assert!(auth
+        .authenticate("carol", "secret", client())
+        .await
+        .is_none());
+
+    assert!(auth.has_user("bob").await);
+    assert!(!auth.has_user("carol").await);
+}
+
+#[tokio::test]
+async fn csv() {
+    let path = support::temp_file(
+        "users",
+        &format!("username,password\nalice,\"{}\"\n", ARGON2),
+    );
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+    }
+    let auth = CsvAuthenticator::load(&path, false).unwrap();
+    assert_eq!(auth.path(), path);
+    assert!(auth
+        .authenticate("alice", "secret", client())
+        .await
+        .is_some());
+    assert!(auth
+        .authenticate("alice", "wrong", client())
+        .await
+        .is_none());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+/// Plaintext passwords need an explicit opt-in
+async fn csv_plaintext() {
+    let path = support::temp_file("plaintext", "username,password\nalice,secret\n");
+    assert!(CsvAuthenticator::load(&path, false).is_err());
+    let auth = CsvAuthenticator::load(&path, true).unwrap();
+    assert!(auth
+        .authenticate("alice", "secret", client())
+        .await
+        .is_some());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+/// Users files accessible by others need an explicit opt-in
+fn csv_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = support::temp_file(
+        "permissions",
+        &format!("username,password\nalice,\"{}\"\n", ARGON2),
+    );
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o604)).unwrap();
+    assert!(CsvAuthenticator::load(&path, false).is_err());
+    assert!(CsvAuthenticator::load(&path, true).is_ok());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn csv_invalid() {
+    let empty = support::temp_file("empty", "username,password\n");
+    assert!(CsvAuthenticator::load(&empty, true).is_err());
+    std::fs::remove_file(empty).unwrap();
+
+    let broken = support::temp_file("broken", "username,password\nalice\n");
+    assert!(CsvAuthenticator::load(&broken, true).is_err());
+    std::fs::remove_file(broken).unwrap();
+
+    assert!(CsvAuthenticator::load("/nonexistent/users.csv".as_ref(), true).is_err());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn command() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let script = support::temp_file(
+        "command",
+        "#!/bin/sh\n\
+         read -r username\n\
+         read -r password\n\
+         [ \"$username\" = alice ] && [ \"$password\" = secret ] && \
+         [ \"$MERINO_CLIENT_ADDR\" = 127.0.0.1:4000 ]\n",
+    );
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o700)).unwrap();
+
+    let auth = CommandAuthenticator::new(&script);
+    let identity = auth.authenticate("alice", "secret", client()).await;
+    assert_eq!(identity.unwrap().username, "alice");
+    assert!(auth
+        .authenticate("alice", "wrong", client())
+        .await
+        .is_none());
+    assert!(auth
+        .authenticate("alice", "secret", "127.0.0.2:4000".parse().unwrap())
+        .await
+        .is_none());
+    // Credentials can't be smuggled into other lines
+    assert!(auth
+        .authenticate("alice\nsecret", "x", client())
+        .await
+        .is_none());
+    // Usernames are unknown to the command
+    assert!(!auth.has_user("alice").await);
+
+    std::fs::remove_file(script).unwrap();
+
+    let missing = CommandAuthenticator::new("/nonexistent/merino-auth");
+    assert!(missing
+        .authenticate("alice", "secret", client())
+        .await
+        .is_none());
+}
+
+/// Accepts any username with the password "open sesame" from loopback clients
+struct Sesame;
+
+#[async_trait]
+impl Authenticator for Sesame {
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+        client: SocketAddr,
+    ) -> Option<Identity> {
+        (password == "open sesame" && client.ip().is_loopback()).then(|| Identity::new(username))
+    }
+}
+
+#[tokio::test]
+/// Embedders can plug in their own credential source
+async fn custom_authenticator() {
+    let merino = support::proxy_with(
+        vec![AuthMethods::UserPass as u8],
+        Box::new(Sesame),
+        Timeouts::default(),
+    )
+    .await;
+    let proxy = support::serve(merino);
+
+    for (password, status) in [("open sesame", 0), ("open barley", 1)] {
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(&[5, 1, 2]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [5, 2]);
+
+        let mut request = vec![1, 3];
+        request.extend_from_slice(b"ali");
+        request.push(password.len() as u8);
+        request.extend_from_slice(password.as_bytes());
+        client.write_all(&request).await.unwrap();
+
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [1, status]);
+    }
+}
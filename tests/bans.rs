@@ -0,0 +1,196 @@
+mod support;
+
+use merino::*;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+fn policy() -> BanPolicy {
+    BanPolicy {
+        max_failures: 3,
+        window: Duration::from_secs(60),
+        ban: Duration::from_secs(10),
+        max_ban: Duration::from_secs(30),
+        ban_users: true,
+    }
+}
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+fn address(s: &str) -> Subject {
+    Subject::Address(ip(s))
+}
+
+#[tokio::test(start_paused = true)]
+/// The address and the username are banned once failures reach the limit within the window
+async fn sliding_window() {
+    let bans = Bans::new(policy());
+    bans.failed(ip("10.0.0.1"), "alice");
+    tokio::time::advance(Duration::from_secs(61)).await;
+    bans.failed(ip("10.0.0.1"), "alice");
+    bans.failed(ip("10.0.0.1"), "alice");
+    assert!(!bans.is_banned(&address("10.0.0.1")));
+
+    bans.failed(ip("10.0.0.1"), "alice");
+    assert!(bans.is_banned(&address("10.0.0.1")));
+    assert!(bans.is_banned(&Subject::User("alice".to_string())));
+    assert!(!bans.is_banned(&address("10.0.0.2")));
+
+    let running = bans.bans();
+    assert_eq!(running.len(), 2);
+    assert_eq!(running[0].subject, address("10.0.0.1"));
+    assert_eq!(running[0].remaining, Duration::from_secs(10));
+    assert_eq!(running[0].strikes, 1);
+    assert_eq!(running[1].subject, Subject::User("alice".to_string()));
+
+    tokio::time::advance(Duration::from_secs(10)).await;
+    assert!(!bans.is_banned(&address("10.0.0.1")));
+}
+
+#[tokio::test(start_paused = true)]
+/// Every further ban is twice as long, up to the maximum, and forgotten after as long
+async fn escalation() {
+    let bans = Bans::new(policy());
+    let fail = |n| {
+        for _ in 0..n {
+            bans.failed(ip("::ffff:10.0.0.1"), "alice");
+        }
+    };
+
+    for remaining in [10, 20, 30, 30] {
+        fail(3);
+        let ban = bans.bans().remove(0);
+        assert_eq!(ban.subject, address("10.0.0.1"));
+        assert_eq!(ban.remaining, Duration::from_secs(remaining));
+        tokio::time::advance(ban.remaining).await;
+    }
+
+    tokio::time::advance(Duration::from_secs(30)).await;
+    fail(3);
+    assert_eq!(bans.bans()[0].strikes, 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn unban_and_clear() {
+    let bans = Bans::new(policy());
+    for _ in 0..3 {
+        bans.failed(ip("10.0.0.1"), "alice");
+    }
+    assert!(bans.unban(&address("10.0.0.1")));
+    assert!(!bans.unban(&address("10.0.0.1")));
+    assert!(!bans.is_banned(&address("10.0.0.1")));
+    assert!(bans.is_banned(&Subject::User("alice".to_string())));
+
+    bans.reject(ip("10.0.0.2"));
+    bans.clear();
+    assert!(bans.bans().is_empty());
+    assert!(bans.rejected().is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+/// Rejected addresses are forgotten after the window
+async fn rejected_expire() {
+    let bans = Bans::new(policy());
+    bans.reject(ip("10.0.0.2"));
+    bans.reject(ip("10.0.0.1"));
+    assert_eq!(bans.rejected(), vec![ip("10.0.0.1"), ip("10.0.0.2")]);
+
+    tokio::time::advance(Duration::from_secs(30)).await;
+    bans.reject(ip("10.0.0.1"));
+    tokio::time::advance(Duration::from_secs(30)).await;
+    assert_eq!(bans.rejected(), vec![ip("10.0.0.1")]);
+}
+
+#[tokio::test(start_paused = true)]
+/// Usernames are only banned when asked for, as anyone could lock a user out otherwise
+async fn users_opt_in() {
+    let bans = Bans::new(BanPolicy {
+        ban_users: false,
+        ..policy()
+    });
+    for _ in 0..3 {
+        bans.failed(ip("10.0.0.1"), "alice");
+    }
+    assert!(bans.is_banned(&address("10.0.0.1")));
+    assert!(!bans.is_banned(&Subject::User("alice".to_string())));
+    assert_eq!(bans.bans().len(), 1);
+}
+
+#[tokio::test(start_paused = true)]
+/// Once there are too many records, new subjects are only counted when old ones expire
+async fn records_capped() {
+    let bans = Bans::new(BanPolicy {
+        max_failures: 1,
+        ban_users: false,
+        ..policy()
+    });
+    let address = |n: u32| IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, (n >> 16) as u16, n as u16]);
+    for n in 0..65536 {
+        bans.failed(address(n), "alice");
+    }
+    assert_eq!(bans.bans().len(), 65536);
+
+    bans.failed(address(65536), "alice");
+    assert!(!bans.is_banned(&Subject::Address(address(65536))));
+
+    // Past bans are forgotten after the longest ban
+    tokio::time::advance(Duration::from_secs(40)).await;
+    bans.failed(address(65536), "alice");
+    assert!(bans.is_banned(&Subject::Address(address(65536))));
+}
+
+#[tokio::test]
+/// Failures never ban with a limit of 0
+async fn disabled() {
+    let bans = Bans::new(BanPolicy {
+        max_failures: 0,
+        ..policy()
+    });
+    for _ in 0..10 {
+        bans.failed(ip("10.0.0.1"), "alice");
+    }
+    assert!(bans.bans().is_empty());
+}
+
+/// Log in with `password`, returning the status of the sub-negotiation,
+/// `None` if the proxy closed the connection first
+async fn login(proxy: SocketAddr, username: &str, password: &str) -> Option<u8> {
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(&[5, 1, 2]).await.ok()?;
+    let mut method = [0u8; 2];
+    client.read_exact(&mut method).await.ok()?;
+
+    let mut auth = vec![1, username.len() as u8];
+    auth.extend_from_slice(username.as_bytes());
+    auth.push(password.len() as u8);
+    auth.extend_from_slice(password.as_bytes());
+    client.write_all(&auth).await.ok()?;
+    let mut status = [0u8; 2];
+    client.read_exact(&mut status).await.ok()?;
+    Some(status[1])
+}
+
+#[tokio::test]
+/// Guessing passwords gets the client dropped and the user locked out
+async fn brute_force() {
+    let users = vec![User::new("alice", "secret"), User::new("bob", "secret")];
+    let mut merino = support::proxy(vec![AuthMethods::UserPass as u8], users).await;
+    merino.set_ban_policy(policy());
+    let bans = merino.get_bans();
+    let proxy = support::serve(merino);
+
+    assert_eq!(login(proxy, "alice", "secret").await, Some(0));
+    for _ in 0..3 {
+        assert_eq!(login(proxy, "alice", "guess").await, Some(1));
+    }
+    assert!(bans.is_banned(&address("127.0.0.1")));
+    assert_eq!(login(proxy, "bob", "secret").await, None);
+
+    // Alice is still locked out from other addresses
+    assert!(bans.unban(&address("127.0.0.1")));
+    assert_eq!(login(proxy, "alice", "secret").await, Some(1));
+    assert_eq!(login(proxy, "bob", "secret").await, Some(0));
+}
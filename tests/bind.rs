@@ -0,0 +1,73 @@
+mod support;
+
+use merino::*;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Negotiate NoAuth and send a BIND request for the `expected` peer
+async fn request_bind(proxy: SocketAddr, expected: Ipv4Addr) -> TcpStream {
+    let mut client = support::no_auth(proxy).await;
+    let mut request = vec![5, 2, 0];
+    request.extend_from_slice(&support::encode_addr(SocketAddr::from((expected, 0))));
+    client.write_all(&request).await.unwrap();
+    client
+}
+
+#[tokio::test]
+/// Inbound connection is announced and relayed in both directions
+async fn bind_relays_inbound_connection() {
+    let proxy = support::start_proxy(|_| {}).await;
+    let mut client = request_bind(proxy, Ipv4Addr::LOCALHOST).await;
+
+    let reply = support::read_reply(&mut client).await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+    assert_eq!(reply.bound.ip(), proxy.ip());
+    assert_ne!(reply.bound.port(), 0);
+
+    let mut server = TcpStream::connect(reply.bound).await.unwrap();
+    let reply = support::read_reply(&mut client).await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+    assert_eq!(reply.bound, server.local_addr().unwrap());
+
+    let mut buf = [0u8; 4];
+    server.write_all(b"ping").await.unwrap();
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+
+    client.write_all(b"pong").await.unwrap();
+    server.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"pong");
+}
+
+#[tokio::test]
+/// Connections from other hosts than DST.ADDR are refused
+async fn bind_rejects_unexpected_peer() {
+    let proxy = support::start_proxy(|_| {}).await;
+    let mut client = request_bind(proxy, Ipv4Addr::new(10, 0, 0, 1)).await;
+
+    let reply = support::read_reply(&mut client).await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+
+    let _server = TcpStream::connect(reply.bound).await.unwrap();
+    let reply = support::read_reply(&mut client).await;
+    assert_eq!(reply.rep, ResponseCode::RuleFailure as u8);
+}
+
+#[tokio::test]
+/// Nobody connects within the timeout
+async fn bind_times_out() {
+    let timeouts = Timeouts {
+        connect: Duration::from_millis(100),
+        ..Timeouts::default()
+    };
+    let proxy = support::start_proxy_with_timeouts(timeouts).await;
+    let mut client = request_bind(proxy, Ipv4Addr::LOCALHOST).await;
+
+    let reply = support::read_reply(&mut client).await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+
+    let reply = support::read_reply(&mut client).await;
+    assert_eq!(reply.rep, ResponseCode::TtlExpired as u8);
+}
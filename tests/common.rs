@@ -3,7 +3,13 @@ use merino::*;
 #[tokio::test]
 /// Can we crate a new `Merino` instance
 async fn merino_contructor() {
-    assert!(Merino::new(1080, "127.0.0.1", Vec::new(), Vec::new(), None)
-        .await
-        .is_ok())
+    assert!(Merino::new(
+        1080,
+        "127.0.0.1",
+        Vec::new(),
+        Box::new(MemoryAuthenticator::default()),
+        Timeouts::default()
+    )
+    .await
+    .is_ok())
 }
@@ -0,0 +1,56 @@
+mod support;
+
+use merino::*;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+#[test]
+/// Error replies keep the all-zero IPv4 form
+fn reply_without_addr() {
+    assert_eq!(
+        SocksReply::new(ResponseCode::HostUnreachable).as_bytes(),
+        &[5, 4, 0, 1, 0, 0, 0, 0, 0, 0]
+    );
+}
+
+#[test]
+/// ATYP and BND.ADDR follow the address family
+fn reply_with_addr() {
+    let v4 = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 0x1234));
+    assert_eq!(
+        SocksReply::with_addr(ResponseCode::Success, v4).as_bytes(),
+        &[5, 0, 0, 1, 192, 0, 2, 1, 0x12, 0x34]
+    );
+
+    let v6 = SocketAddr::from((Ipv6Addr::LOCALHOST, 1080));
+    let mut expected = vec![5, 0, 0, 4];
+    expected.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+    expected.extend_from_slice(&1080u16.to_be_bytes());
+    assert_eq!(
+        SocksReply::with_addr(ResponseCode::Success, v6).as_bytes(),
+        &expected[..]
+    );
+}
+
+#[tokio::test]
+/// CONNECT reply carries the local address of the outbound IPv4 socket
+async fn connect_reply_v4() {
+    let proxy = support::start_proxy(|_| {}).await;
+    let (target, mut peers) = support::start_target("127.0.0.1").await;
+
+    let (_client, reply) = support::connect(proxy, target).await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+    assert!(reply.bound.is_ipv4());
+    assert_eq!(reply.bound, peers.recv().await.unwrap());
+}
+
+#[tokio::test]
+/// CONNECT reply carries the local address of the outbound IPv6 socket
+async fn connect_reply_v6() {
+    let proxy = support::start_proxy(|_| {}).await;
+    let (target, mut peers) = support::start_target("::1").await;
+
+    let (_client, reply) = support::connect(proxy, target).await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+    assert!(reply.bound.is_ipv6());
+    assert_eq!(reply.bound, peers.recv().await.unwrap());
+}
@@ -0,0 +1,158 @@
+mod support;
+
+use merino::*;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream};
+
+/// Proxy with alice and bob, also accepting clients without authentication
+async fn limited_proxy(limits: ConnectionLimits) -> Merino {
+    let users = vec![User::new("alice", "secret"), User::new("bob", "secret")];
+    let methods = vec![AuthMethods::NoAuth as u8, AuthMethods::UserPass as u8];
+    let mut merino = support::proxy(methods, users).await;
+    merino.set_connection_limits(limits);
+    merino
+}
+
+/// Offer `method`, returning the connection and the method chosen by the proxy
+async fn greet(client: TcpSocket, proxy: SocketAddr, method: u8) -> (TcpStream, u8) {
+    let mut client = client.connect(proxy).await.unwrap();
+    client.write_all(&[5, 1, method]).await.unwrap();
+    let mut reply = [0u8; 2];
+    client.read_exact(&mut reply).await.unwrap();
+    (client, reply[1])
+}
+
+/// Client socket bound to `ip`
+fn socket(ip: &str) -> TcpSocket {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind(format!("{}:0", ip).parse().unwrap()).unwrap();
+    socket
+}
+
+/// Log in as `username` and CONNECT to `target`, returning the connection and REP of the reply
+async fn login(proxy: SocketAddr, target: SocketAddr, username: &str) -> (TcpStream, u8) {
+    let (mut client, method) = greet(socket("127.0.0.1"), proxy, 2).await;
+    assert_eq!(method, AuthMethods::UserPass as u8);
+
+    let mut auth = vec![1, username.len() as u8];
+    auth.extend_from_slice(username.as_bytes());
+    auth.extend_from_slice(b"\x06secret");
+    client.write_all(&auth).await.unwrap();
+    let mut status = [0u8; 2];
+    client.read_exact(&mut status).await.unwrap();
+    assert_eq!(status, [1, 0]);
+
+    let reply = support::request(&mut client, 1, &support::encode_addr(target)).await;
+    (client, reply.rep)
+}
+
+#[tokio::test]
+/// Clients over the total limit are refused during method negotiation
+async fn total_limit() {
+    let merino = limited_proxy(ConnectionLimits {
+        total: Some(2),
+        ..ConnectionLimits::default()
+    })
+    .await;
+    let proxy = support::serve(merino);
+
+    let (first, method) = greet(socket("127.0.0.1"), proxy, 0).await;
+    assert_eq!(method, AuthMethods::NoAuth as u8);
+    let (_second, method) = greet(socket("127.0.0.2"), proxy, 0).await;
+    assert_eq!(method, AuthMethods::NoAuth as u8);
+    let (_, method) = greet(socket("127.0.0.3"), proxy, 0).await;
+    assert_eq!(method, AuthMethods::NoMethods as u8);
+
+    // Closed connections free their slot
+    drop(first);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let (_, method) = greet(socket("127.0.0.3"), proxy, 0).await;
+    assert_eq!(method, AuthMethods::NoAuth as u8);
+}
+
+#[tokio::test]
+/// Each client address has its own limit
+async fn per_ip_limit() {
+    let merino = limited_proxy(ConnectionLimits {
+        per_ip: Some(1),
+        ..ConnectionLimits::default()
+    })
+    .await;
+    let proxy = support::serve(merino);
+
+    let (_first, method) = greet(socket("127.0.0.1"), proxy, 0).await;
+    assert_eq!(method, AuthMethods::NoAuth as u8);
+    let (_, method) = greet(socket("127.0.0.1"), proxy, 0).await;
+    assert_eq!(method, AuthMethods::NoMethods as u8);
+    let (_other, method) = greet(socket("127.0.0.2"), proxy, 0).await;
+    assert_eq!(method, AuthMethods::NoAuth as u8);
+}
+
+#[tokio::test]
+/// Sessions over the limit of a user are refused once the user is known
+async fn per_user_limit() {
+    let target = support::start_echo().await;
+    let merino = limited_proxy(ConnectionLimits {
+        per_user: Some(1),
+        ..ConnectionLimits::default()
+    })
+    .await;
+    let bans = merino.get_bans();
+    let proxy = support::serve(merino);
+
+    let (first, rep) = login(proxy, target, "alice").await;
+    assert_eq!(rep, ResponseCode::Success as u8);
+    let (_, rep) = login(proxy, target, "alice").await;
+    assert_eq!(rep, ResponseCode::RuleFailure as u8);
+    let (_bob, rep) = login(proxy, target, "bob").await;
+    assert_eq!(rep, ResponseCode::Success as u8);
+    assert_eq!(
+        bans.rejected(),
+        vec!["127.0.0.1".parse::<IpAddr>().unwrap()]
+    );
+
+    drop(first);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let (_, rep) = login(proxy, target, "alice").await;
+    assert_eq!(rep, ResponseCode::Success as u8);
+}
+
+#[tokio::test]
+/// SOCKS4 clients over the limit get a rejection reply
+async fn socks4_refused() {
+    let merino = limited_proxy(ConnectionLimits {
+        total: Some(1),
+        ..ConnectionLimits::default()
+    })
+    .await;
+    let proxy = support::serve(merino);
+
+    let (_first, _) = greet(socket("127.0.0.1"), proxy, 0).await;
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client
+        .write_all(&[4, 1, 0, 80, 127, 0, 0, 1, 0])
+        .await
+        .unwrap();
+    let mut reply = [0u8; 8];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], 0x5B);
+}
+
+#[tokio::test]
+/// Clients over the limit which don't send their greeting are closed quickly
+async fn silent_client_refused() {
+    let merino = limited_proxy(ConnectionLimits {
+        total: Some(1),
+        ..ConnectionLimits::default()
+    })
+    .await;
+    let proxy = support::serve(merino);
+
+    let (_first, _) = greet(socket("127.0.0.1"), proxy, 0).await;
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    let mut buf = [0u8; 2];
+    let read = tokio::time::timeout(Duration::from_secs(3), client.read(&mut buf)).await;
+    assert_eq!(read.unwrap().unwrap(), 0);
+}
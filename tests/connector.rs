@@ -0,0 +1,140 @@
+mod support;
+
+use async_trait::async_trait;
+use merino::*;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Requests seen by a connector
+#[derive(Clone, Debug, PartialEq)]
+struct Seen {
+    target: Target,
+    user: Option<String>,
+    addrs: Vec<SocketAddr>,
+}
+
+/// Connects to in-memory echo servers, or fails with `error`
+struct MockConnector {
+    seen: Arc<Mutex<Vec<Seen>>>,
+    error: Option<io::ErrorKind>,
+    remote: bool,
+}
+
+#[async_trait]
+impl Connector for MockConnector {
+    async fn connect(&self, target: &Target, context: &ConnectContext<'_>) -> io::Result<Outbound> {
+        self.seen.lock().unwrap().push(Seen {
+            target: target.clone(),
+            user: context.user.map(str::to_string),
+            addrs: context.addrs.to_vec(),
+        });
+        if let Some(kind) = self.error {
+            return Err(io::Error::new(kind, "mock failure"));
+        }
+
+        let (client, mut server) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let (mut reader, mut writer) = tokio::io::split(&mut server);
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        });
+        Ok(Outbound::new(client))
+    }
+
+    fn resolves_remotely(&self) -> bool {
+        self.remote
+    }
+}
+
+/// Start a proxy for alice using a mock connector, `service.test` resolves to 192.0.2.1
+async fn mock_proxy(
+    error: Option<io::ErrorKind>,
+    remote: bool,
+    rules: &str,
+) -> (SocketAddr, Arc<Mutex<Vec<Seen>>>) {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let connector = MockConnector {
+        seen: seen.clone(),
+        error,
+        remote,
+    };
+    let users = vec![User::new("alice", "secret")];
+    let proxy = support::start_proxy_with(vec![AuthMethods::UserPass as u8], users, |merino| {
+        merino.set_resolver(support::hosts_resolver("192.0.2.1 service.test\n"));
+        merino.set_connector(Box::new(connector));
+        merino.set_rules(rules.parse().unwrap());
+    })
+    .await;
+    (proxy, seen)
+}
+
+/// Log in as alice and CONNECT to `domain`:80
+async fn connect_as_alice(proxy: SocketAddr, domain: &str) -> (TcpStream, support::Reply) {
+    let mut client = support::login(proxy, "alice", "secret").await;
+    let reply = support::request(&mut client, 1, &support::encode_domain(domain, 80)).await;
+    (client, reply)
+}
+
+#[tokio::test]
+async fn custom_connector() {
+    let (proxy, seen) = mock_proxy(None, false, "").await;
+
+    let (mut client, reply) = connect_as_alice(proxy, "service.test").await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+    // Streams without an address are bound to 0.0.0.0:0
+    assert_eq!(reply.bound, SocketAddr::from(([0, 0, 0, 0], 0)));
+
+    client.write_all(b"ping").await.unwrap();
+    let mut pong = [0u8; 4];
+    client.read_exact(&mut pong).await.unwrap();
+    assert_eq!(&pong, b"ping");
+
+    let addr: SocketAddr = "192.0.2.1:80".parse().unwrap();
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![Seen {
+            target: Target::Domain("service.test".to_string(), 80),
+            user: Some("alice".to_string()),
+            addrs: vec![addr],
+        }]
+    );
+}
+
+#[tokio::test]
+/// Errors of the connector are sent as the closest reply
+async fn connector_error() {
+    let (proxy, _) = mock_proxy(Some(io::ErrorKind::ConnectionRefused), false, "").await;
+    let (_, reply) = connect_as_alice(proxy, "service.test").await;
+    assert_eq!(reply.rep, ResponseCode::ConnectionRefused as u8);
+}
+
+#[tokio::test]
+/// Denied requests never reach the connector
+async fn checked_before() {
+    let (proxy, seen) = mock_proxy(None, false, "deny dest=192.0.2.0/24").await;
+    let (_, reply) = connect_as_alice(proxy, "service.test").await;
+    assert_eq!(reply.rep, ResponseCode::RuleFailure as u8);
+    assert!(seen.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+/// Connectors resolving remotely get domains the proxy can't resolve, checked by name
+async fn remote_resolution() {
+    let (proxy, seen) = mock_proxy(None, true, "deny dest=.blocked.invalid").await;
+
+    let (_, reply) = connect_as_alice(proxy, "remote.invalid").await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+    let (_, reply) = connect_as_alice(proxy, "www.blocked.invalid").await;
+    assert_eq!(reply.rep, ResponseCode::RuleFailure as u8);
+
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![Seen {
+            target: Target::Domain("remote.invalid".to_string(), 80),
+            user: Some("alice".to_string()),
+            addrs: Vec::new(),
+        }]
+    );
+}
@@ -0,0 +1,151 @@
+mod support;
+
+use merino::*;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::UdpSocket;
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+fn sources(addrs: &[&str], rotation: Rotation) -> Egress {
+    Egress::new(EgressConfig {
+        addrs: addrs.iter().map(|addr| ip(addr)).collect(),
+        device: None,
+        rotation,
+    })
+}
+
+/// Relay a datagram through a UDP ASSOCIATE of `proxy` to `target`, returning the source
+/// address the target sees
+async fn relay_datagram(proxy: SocketAddr, target: &UdpSocket) -> IpAddr {
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut control = support::no_auth(proxy).await;
+    let client_addr = support::encode_addr(client.local_addr().unwrap());
+    let reply = support::request(&mut control, 3, &client_addr).await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+    let relay = reply.bound;
+
+    let mut datagram = vec![0, 0, 0];
+    datagram.extend_from_slice(&support::encode_addr(target.local_addr().unwrap()));
+    datagram.extend_from_slice(b"hello");
+    client.send_to(&datagram, relay).await.unwrap();
+
+    let mut buf = [0u8; 16];
+    let (_, src) = target.recv_from(&mut buf).await.unwrap();
+    src.ip()
+}
+
+#[test]
+fn rotation() {
+    let v4: SocketAddr = "192.0.2.1:80".parse().unwrap();
+    let v6: SocketAddr = "[2001:db8::1]:80".parse().unwrap();
+    let client = ip("198.51.100.1");
+
+    // Families are rotated separately
+    let egress = sources(
+        &["10.0.0.1", "2001:db8::a", "10.0.0.2"],
+        Rotation::RoundRobin,
+    );
+    assert_eq!(egress.source(&v4, None, client), Some(ip("10.0.0.1")));
+    assert_eq!(egress.source(&v6, None, client), Some(ip("2001:db8::a")));
+    assert_eq!(egress.source(&v4, None, client), Some(ip("10.0.0.2")));
+    assert_eq!(egress.source(&v4, None, client), Some(ip("10.0.0.1")));
+
+    // Without addresses of the family, the kernel chooses
+    let egress = sources(&["10.0.0.1"], Rotation::Random);
+    assert_eq!(egress.source(&v6, None, client), None);
+    assert_eq!(egress.source(&v4, None, client), Some(ip("10.0.0.1")));
+
+    // Random sources spread over all addresses
+    let egress = sources(&["10.0.0.1", "10.0.0.2"], Rotation::Random);
+    let picked: std::collections::HashSet<_> =
+        (0..64).map(|_| egress.source(&v4, None, client)).collect();
+    assert_eq!(picked.len(), 2);
+
+    let addrs: Vec<String> = (1..=16).map(|i| format!("10.0.0.{}", i)).collect();
+    let addrs: Vec<&str> = addrs.iter().map(String::as_str).collect();
+    let egress = sources(&addrs, Rotation::StickyUser);
+    let alice = egress.source(&v4, Some("alice"), client);
+    for _ in 0..8 {
+        assert_eq!(egress.source(&v4, Some("alice"), ip("198.51.100.2")), alice);
+    }
+    // Clients without a user stick by their address
+    let anonymous = egress.source(&v4, None, client);
+    assert_eq!(egress.source(&v4, None, client), anonymous);
+
+    let egress = sources(&addrs, Rotation::StickyClient);
+    let first = egress.source(&v4, Some("alice"), client);
+    assert_eq!(egress.source(&v4, Some("bob"), client), first);
+
+    assert_eq!("sticky-client".parse(), Ok(Rotation::StickyClient));
+    assert!("sticky".parse::<Rotation>().is_err());
+}
+
+#[tokio::test]
+async fn fixed_source() {
+    let (target, mut peers) = support::start_target("127.0.0.1").await;
+    let config = EgressConfig {
+        addrs: vec![ip("127.0.0.2")],
+        ..EgressConfig::default()
+    };
+    let proxy = support::start_proxy(|merino| merino.set_egress(config)).await;
+
+    assert_eq!(
+        support::connect(proxy, target).await.1.rep,
+        ResponseCode::Success as u8
+    );
+    assert_eq!(
+        peers.recv().await.map(|peer| peer.ip()),
+        Some(ip("127.0.0.2"))
+    );
+}
+
+#[tokio::test]
+async fn rotated_sources() {
+    let (target, mut peers) = support::start_target("127.0.0.1").await;
+    let config = EgressConfig {
+        addrs: vec![ip("127.0.0.2"), ip("127.0.0.3")],
+        rotation: Rotation::RoundRobin,
+        ..EgressConfig::default()
+    };
+    let proxy = support::start_proxy(|merino| merino.set_egress(config)).await;
+
+    for expected in ["127.0.0.2", "127.0.0.3", "127.0.0.2"] {
+        assert_eq!(
+            support::connect(proxy, target).await.1.rep,
+            ResponseCode::Success as u8
+        );
+        assert_eq!(peers.recv().await.map(|peer| peer.ip()), Some(ip(expected)));
+    }
+}
+
+#[tokio::test]
+/// Relayed datagrams are sent from the source addresses too
+async fn udp_source() {
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let config = EgressConfig {
+        addrs: vec![ip("127.0.0.2")],
+        ..EgressConfig::default()
+    };
+    let proxy = support::start_proxy(|merino| merino.set_egress(config)).await;
+
+    assert_eq!(relay_datagram(proxy, &target).await, ip("127.0.0.2"));
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+/// Connections through an interface which can't be bound fail
+async fn unknown_device() {
+    let target = support::start_echo().await;
+    let config = EgressConfig {
+        device: Some("merino-none0".to_string()),
+        ..EgressConfig::default()
+    };
+    let proxy = support::start_proxy(|merino| merino.set_egress(config)).await;
+
+    assert_ne!(
+        support::connect(proxy, target).await.1.rep,
+        ResponseCode::Success as u8
+    );
+}
@@ -0,0 +1,123 @@
+mod support;
+
+use merino::*;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+    list.iter().map(|addr| addr.parse().unwrap()).collect()
+}
+
+/// Start a NoAuth proxy with `ip_family`, resolving `dual.test` to both loopback addresses
+/// and `v6.test` to IPv6 only
+async fn dual_stack_proxy(ip_family: IpFamily) -> SocketAddr {
+    support::start_proxy(|merino| {
+        merino.set_resolver(support::hosts_resolver(
+            "::1 dual.test v6.test\n127.0.0.1 dual.test\n",
+        ));
+        merino.set_ip_family(ip_family);
+    })
+    .await
+}
+
+#[test]
+/// Families are interleaved, starting with the preferred one
+fn sort_interleaves_families() {
+    let mixed = addrs(&["10.0.0.1:1", "10.0.0.2:1", "[::1]:1", "[::2]:1", "[::3]:1"]);
+
+    assert_eq!(
+        IpFamily::PreferIpv6.sort(&mixed),
+        addrs(&["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"])
+    );
+    assert_eq!(
+        IpFamily::PreferIpv4.sort(&mixed),
+        addrs(&["10.0.0.1:1", "[::1]:1", "10.0.0.2:1", "[::2]:1", "[::3]:1"])
+    );
+}
+
+#[test]
+/// Forced family drops the other one
+fn sort_forced_family() {
+    let mixed = addrs(&["10.0.0.1:1", "[::1]:1", "10.0.0.2:1"]);
+
+    assert_eq!(
+        IpFamily::Ipv4.sort(&mixed),
+        addrs(&["10.0.0.1:1", "10.0.0.2:1"])
+    );
+    assert_eq!(IpFamily::Ipv6.sort(&mixed), addrs(&["[::1]:1"]));
+}
+
+#[test]
+fn parse_family() {
+    for family in [
+        IpFamily::PreferIpv6,
+        IpFamily::PreferIpv4,
+        IpFamily::Ipv4,
+        IpFamily::Ipv6,
+    ] {
+        assert_eq!(family.to_string().parse::<IpFamily>(), Ok(family));
+    }
+    assert!("ipv5".parse::<IpFamily>().is_err());
+}
+
+#[tokio::test]
+/// Refused IPv6 attempt falls back to IPv4 immediately
+async fn falls_back_to_ipv4() {
+    let proxy = dual_stack_proxy(IpFamily::PreferIpv6).await;
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = target.local_addr().unwrap().port();
+
+    let start = Instant::now();
+    assert_eq!(
+        support::connect_domain(proxy, "dual.test", port)
+            .await
+            .1
+            .rep,
+        ResponseCode::Success as u8
+    );
+    assert!(start.elapsed() < CONNECTION_ATTEMPT_DELAY);
+}
+
+#[tokio::test]
+/// Hanging IPv6 attempt is raced by IPv4 after the attempt delay
+async fn races_hanging_ipv6() {
+    let proxy = dual_stack_proxy(IpFamily::PreferIpv6).await;
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = target.local_addr().unwrap().port();
+
+    // IPv6 listener which is never accepting, so new connections hang once its backlog is full
+    let socket = TcpSocket::new_v6().unwrap();
+    socket
+        .bind(format!("[::1]:{}", port).parse().unwrap())
+        .unwrap();
+    let _hanging = socket.listen(0).unwrap();
+    let mut backlog = Vec::new();
+    for _ in 0..4 {
+        let connect = TcpStream::connect(("::1", port));
+        if let Ok(Ok(stream)) = tokio::time::timeout(Duration::from_millis(100), connect).await {
+            backlog.push(stream);
+        }
+    }
+
+    let start = Instant::now();
+    assert_eq!(
+        support::connect_domain(proxy, "dual.test", port)
+            .await
+            .1
+            .rep,
+        ResponseCode::Success as u8
+    );
+    assert!(start.elapsed() >= CONNECTION_ATTEMPT_DELAY);
+}
+
+#[tokio::test]
+/// Forced IPv4 can't reach an IPv6 only host
+async fn forced_family_without_addresses() {
+    let proxy = dual_stack_proxy(IpFamily::Ipv4).await;
+
+    assert_eq!(
+        support::connect_domain(proxy, "v6.test", 80).await.1.rep,
+        ResponseCode::HostUnreachable as u8
+    );
+}
@@ -0,0 +1,95 @@
+mod support;
+
+use merino::*;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Hashes of "secret" with cheap parameters
+const ARGON2: &str =
+    "$argon2id$v=19$m=64,t=1,p=1$bWVyaW5vLXRlc3Qtc2FsdA$Gz0H6PAQC5r/v21yM7nQWHJ2LAqb8LZzbJQoJuz/z6A";
+const SCRYPT: &str =
+    "$scrypt$ln=4,r=8,p=1$bWVyaW5vLXRlc3Qtc2FsdA$xIvPBNT7L42ZLHSSNDdx1A/jTXnKyWCh2LFALrJpVr0";
+const BCRYPT: &str = "$2b$04$eQfWJD4bTNbmo4BgZAjLQe3zuK2VJqshrjr.RXxUbgGq3TPQv68Fi";
+
+/// Authenticate with USER/PASS, returning the status of the sub-negotiation
+async fn login(proxy: SocketAddr, username: &str, password: &str) -> u8 {
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(&[5, 1, 2]).await.unwrap();
+    let mut method = [0u8; 2];
+    client.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [5, 2]);
+
+    let mut request = vec![1, username.len() as u8];
+    request.extend_from_slice(username.as_bytes());
+    request.push(password.len() as u8);
+    request.extend_from_slice(password.as_bytes());
+    client.write_all(&request).await.unwrap();
+
+    let mut status = [0u8; 2];
+    client.read_exact(&mut status).await.unwrap();
+    status[1]
+}
+
+#[test]
+fn verify_hashes() {
+    for hash in [ARGON2, SCRYPT, BCRYPT] {
+        let user = User::new("user", hash);
+        assert!(!user.has_plaintext_password());
+        assert!(user.verify_password("secret"), "{}", hash);
+        assert!(!user.verify_password("Secret"), "{}", hash);
+        assert!(!user.verify_password(""), "{}", hash);
+    }
+}
+
+#[test]
+fn verify_plaintext() {
+    let user = User::new("user", "secret");
+    assert!(user.has_plaintext_password());
+    assert!(user.verify_password("secret"));
+    assert!(!user.verify_password("secret2"));
+    assert!(!user.verify_password("secre"));
+}
+
+#[test]
+/// Broken hashes never match, not even their own text
+fn verify_malformed_hash() {
+    let broken = "$argon2id$v=19$m=64";
+    let user = User::new("user", broken);
+    assert!(!user.has_plaintext_password());
+    assert!(!user.verify_password(broken));
+}
+
+#[test]
+fn hash_roundtrip() {
+    let hash = hash_password("secret", HashAlgorithm::Argon2id).unwrap();
+    assert!(hash.starts_with("$argon2id$"));
+    assert!(User::new("user", &hash).verify_password("secret"));
+}
+
+#[test]
+fn parse_algorithm() {
+    for algorithm in [
+        HashAlgorithm::Argon2id,
+        HashAlgorithm::Bcrypt,
+        HashAlgorithm::Scrypt,
+    ] {
+        assert_eq!(
+            algorithm.to_string().parse::<HashAlgorithm>(),
+            Ok(algorithm)
+        );
+    }
+    assert!("md5".parse::<HashAlgorithm>().is_err());
+}
+
+#[tokio::test]
+async fn userpass_with_hash() {
+    let users = vec![User::new("argon", ARGON2), User::new("bcrypt", BCRYPT)];
+    let proxy = support::start_proxy_with(vec![AuthMethods::UserPass as u8], users, |_| {}).await;
+
+    assert_eq!(login(proxy, "argon", "secret").await, 0);
+    assert_eq!(login(proxy, "bcrypt", "secret").await, 0);
+    assert_ne!(login(proxy, "argon", "wrong").await, 0);
+    // Password of another user
+    assert_ne!(login(proxy, "nobody", "secret").await, 0);
+}
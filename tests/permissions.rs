@@ -0,0 +1,181 @@
+mod support;
+
+use merino::rules::{Command, Request};
+use merino::*;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+fn client() -> SocketAddr {
+    "127.0.0.1:4000".parse().unwrap()
+}
+
+fn request<'a>(command: Command, domain: Option<&'a str>, ip: &str) -> Request<'a> {
+    Request {
+        command,
+        user: None,
+        source: "127.0.0.1".parse().unwrap(),
+        domain,
+        ip: Some(ip.parse().unwrap()),
+        port: 443,
+    }
+}
+
+#[test]
+fn checks() {
+    let mut permissions = Permissions::default();
+    assert!(permissions.allows(&request(Command::Bind, None, "10.0.0.1")));
+
+    permissions.set_destinations("10.0.0.0/8, .example.com");
+    permissions.set_commands("connect,udp").unwrap();
+    permissions.set_sources("127.0.0.0/8").unwrap();
+    assert!(permissions.allows(&request(Command::Connect, None, "10.0.0.1")));
+    assert!(permissions.allows(&request(Command::Udp, Some("www.example.com"), "8.8.8.8")));
+    assert!(!permissions.allows(&request(Command::Connect, None, "8.8.8.8")));
+    assert!(!permissions.allows(&request(Command::Bind, None, "10.0.0.1")));
+
+    assert!(permissions.allows_source(&IpAddr::from([127, 0, 0, 2])));
+    assert!(!permissions.allows_source(&IpAddr::from([192, 168, 0, 1])));
+
+    permissions.enabled = false;
+    assert!(!permissions.allows(&request(Command::Connect, None, "10.0.0.1")));
+
+    assert!(permissions.set_commands("connect,associate").is_err());
+    assert!(permissions.set_sources("example.com").is_err());
+}
+
+#[tokio::test]
+/// Permission columns of the users file are optional
+async fn users_file() {
+    let path = support::temp_file(
+        "users",
+        "username,password,enabled,destinations,commands,sources\n\
+         alice,secret,,\"10.0.0.0/8,.example.com\",connect,\n\
+         bob,secret,false,,,\n\
+         carol,secret,true,,,192.168.0.0/16\n",
+    );
+    let auth = CsvAuthenticator::load(&path, true).unwrap();
+
+    let alice = auth
+        .authenticate("alice", "secret", client())
+        .await
+        .unwrap();
+    assert!(alice.permissions.enabled);
+    assert!(alice
+        .permissions
+        .allows(&request(Command::Connect, None, "10.1.1.1")));
+    assert!(!alice
+        .permissions
+        .allows(&request(Command::Bind, None, "10.1.1.1")));
+    assert!(!alice
+        .permissions
+        .allows(&request(Command::Connect, None, "8.8.8.8")));
+
+    let bob = auth.authenticate("bob", "secret", client()).await.unwrap();
+    assert!(!bob.permissions.enabled);
+
+    let carol = auth.identify("carol").await.unwrap();
+    assert!(!carol.permissions.allows_source(&client().ip()));
+    std::fs::remove_file(path).unwrap();
+
+    let path = support::temp_file("plain", "username,password\nalice,secret\n");
+    let auth = CsvAuthenticator::load(&path, true).unwrap();
+    let alice = auth.identify("alice").await.unwrap();
+    assert_eq!(alice.permissions, Permissions::default());
+    std::fs::remove_file(path).unwrap();
+
+    let path = support::temp_file(
+        "invalid",
+        "username,password,commands\nalice,secret,listen\n",
+    );
+    assert!(CsvAuthenticator::load(&path, true).is_err());
+    std::fs::remove_file(path).unwrap();
+}
+
+fn user(name: &str, configure: impl FnOnce(&mut Permissions)) -> User {
+    let mut permissions = Permissions::default();
+    configure(&mut permissions);
+    let mut user = User::new(name, "secret");
+    user.set_permissions(permissions);
+    user
+}
+
+/// Start a USER/PASS proxy for users with different permissions
+async fn permissions_proxy() -> SocketAddr {
+    let users = vec![
+        user("alice", |p| {
+            p.set_destinations("127.0.0.0/8");
+            p.set_commands("connect").unwrap();
+        }),
+        user("bob", |p| p.enabled = false),
+        user("carol", |p| p.set_sources("10.0.0.0/8").unwrap()),
+        user("dave", |p| p.set_destinations("10.0.0.0/8")),
+    ];
+    support::start_proxy_with(vec![AuthMethods::UserPass as u8], users, |_| {}).await
+}
+
+/// Authenticate as `username`, returning the connection if access was granted
+async fn login(proxy: SocketAddr, username: &str) -> Option<TcpStream> {
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(&[5, 1, 2]).await.unwrap();
+    let mut method = [0u8; 2];
+    client.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [5, 2]);
+
+    let mut auth = vec![1, username.len() as u8];
+    auth.extend_from_slice(username.as_bytes());
+    auth.push(6);
+    auth.extend_from_slice(b"secret");
+    client.write_all(&auth).await.unwrap();
+
+    let mut status = [0u8; 2];
+    client.read_exact(&mut status).await.unwrap();
+    (status[1] == 0).then_some(client)
+}
+
+#[tokio::test]
+/// Disabled users and users from other networks can't log in
+async fn login_denied() {
+    let proxy = permissions_proxy().await;
+    assert!(login(proxy, "alice").await.is_some());
+    assert!(login(proxy, "bob").await.is_none());
+    assert!(login(proxy, "carol").await.is_none());
+}
+
+#[tokio::test]
+async fn requests_checked() {
+    let proxy = permissions_proxy().await;
+    let target = support::start_echo().await;
+
+    let mut alice = login(proxy, "alice").await.unwrap();
+    assert_eq!(
+        support::request(&mut alice, 1, &support::encode_addr(target))
+            .await
+            .rep,
+        ResponseCode::Success as u8
+    );
+
+    let mut alice = login(proxy, "alice").await.unwrap();
+    assert_eq!(
+        support::request(&mut alice, 2, &support::encode_addr(target))
+            .await
+            .rep,
+        ResponseCode::RuleFailure as u8
+    );
+
+    let mut alice = login(proxy, "alice").await.unwrap();
+    assert_eq!(
+        support::request(&mut alice, 3, &support::encode_addr(target))
+            .await
+            .rep,
+        ResponseCode::RuleFailure as u8
+    );
+
+    let mut dave = login(proxy, "dave").await.unwrap();
+    assert_eq!(
+        support::request(&mut dave, 1, &support::encode_addr(target))
+            .await
+            .rep,
+        ResponseCode::RuleFailure as u8
+    );
+}
@@ -0,0 +1,323 @@
+mod support;
+
+use merino::*;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Modes of a fake upstream
+const UP: u8 = 0;
+/// Closes connections without a reply
+const DOWN: u8 = 1;
+/// Replies that the target refused the connection
+const REFUSE: u8 = 2;
+
+/// SOCKS5 proxy which tunnels every request to the same target
+struct FakeUpstream {
+    addr: SocketAddr,
+    /// Requests answered with success
+    served: Arc<AtomicUsize>,
+    mode: Arc<AtomicU8>,
+}
+
+impl FakeUpstream {
+    async fn start(target: SocketAddr) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = Arc::new(AtomicUsize::new(0));
+        let mode = Arc::new(AtomicU8::new(UP));
+        let (counter, state) = (served.clone(), mode.clone());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mode = state.load(Ordering::Relaxed);
+                if mode == DOWN {
+                    continue;
+                }
+                let served = counter.clone();
+                tokio::spawn(async move {
+                    let mut greeting = [0u8; 2];
+                    stream.read_exact(&mut greeting).await?;
+                    let mut methods = vec![0u8; greeting[1] as usize];
+                    stream.read_exact(&mut methods).await?;
+                    stream.write_all(&[5, 0]).await?;
+
+                    let mut request = [0u8; 4];
+                    stream.read_exact(&mut request).await?;
+                    let len = match request[3] {
+                        1 => 4,
+                        4 => 16,
+                        _ => stream.read_u8().await? as usize,
+                    };
+                    let mut addr = vec![0u8; len + 2];
+                    stream.read_exact(&mut addr).await?;
+
+                    if mode == REFUSE {
+                        return stream.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).await;
+                    }
+                    stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+                    served.fetch_add(1, Ordering::Relaxed);
+                    let mut target = TcpStream::connect(target).await?;
+                    tokio::io::copy_bidirectional(&mut stream, &mut target)
+                        .await
+                        .map(drop)
+                });
+            }
+        });
+        FakeUpstream { addr, served, mode }
+    }
+
+    fn upstream(&self, name: &str) -> Upstream {
+        format!("{}=socks5://{}", name, self.addr).parse().unwrap()
+    }
+
+    fn served(&self) -> usize {
+        self.served.load(Ordering::Relaxed)
+    }
+
+    fn set_mode(&self, mode: u8) {
+        self.mode.store(mode, Ordering::Relaxed);
+    }
+}
+
+/// Options without health checks, which would show up as requests
+fn options() -> PoolOptions {
+    PoolOptions {
+        health_check: None,
+        ..PoolOptions::default()
+    }
+}
+
+/// Start a merino sending everything through a pool of `upstreams`, returning its address
+/// and the pool
+async fn start_front(
+    strategy: &str,
+    upstreams: &[&FakeUpstream],
+    options: PoolOptions,
+) -> (SocketAddr, Arc<Pool>) {
+    let upstreams: Vec<Upstream> = upstreams
+        .iter()
+        .enumerate()
+        .map(|(i, upstream)| upstream.upstream(&format!("up{}", i)))
+        .collect();
+    let names: Vec<&str> = upstreams.iter().map(|u| u.name.as_str()).collect();
+    let config = format!("pool={}:{}", strategy, names.join(","))
+        .parse()
+        .unwrap();
+    let pool = Pool::new(config, &upstreams, options).unwrap();
+    let router = Router::new(upstreams, vec![pool], "via pool".parse().unwrap()).unwrap();
+    let pool = router.pool("pool").unwrap().clone();
+
+    let proxy = support::start_proxy(|merino| merino.set_router(router)).await;
+    (proxy, pool)
+}
+
+/// CONNECT and check that the tunnel reaches the echo server
+async fn assert_echo(proxy: SocketAddr, target: SocketAddr) -> TcpStream {
+    let (mut client, reply) = support::connect(proxy, target).await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+    client.write_all(b"ping").await.unwrap();
+    let mut pong = [0u8; 4];
+    client.read_exact(&mut pong).await.unwrap();
+    assert_eq!(&pong, b"ping");
+    client
+}
+
+#[test]
+fn parse_pool() {
+    let config: PoolConfig = "corp=least-connections: a, b".parse().unwrap();
+    assert_eq!(config.name, "corp");
+    assert_eq!(config.strategy, Strategy::LeastConnections);
+    assert_eq!(config.upstreams, vec!["a", "b"]);
+
+    assert!("corp=a,b".parse::<PoolConfig>().is_err());
+    assert!("corp=random:a,b".parse::<PoolConfig>().is_err());
+    assert!("corp=round-robin:".parse::<PoolConfig>().is_err());
+
+    let upstream: Upstream = "a=socks5://127.0.0.1:1080".parse().unwrap();
+    let config: PoolConfig = "corp=round-robin:a,b".parse().unwrap();
+    assert!(Pool::new(
+        config,
+        std::slice::from_ref(&upstream),
+        PoolOptions::default()
+    )
+    .is_err());
+
+    // Pools and upstreams share their names
+    let config: PoolConfig = "a=round-robin:a".parse().unwrap();
+    let pool = Pool::new(
+        config,
+        std::slice::from_ref(&upstream),
+        PoolOptions::default(),
+    )
+    .unwrap();
+    assert!(Router::new(vec![upstream], vec![pool], Routes::default()).is_err());
+}
+
+#[tokio::test]
+async fn round_robin() {
+    let target = support::start_echo().await;
+    let (a, b) = (
+        FakeUpstream::start(target).await,
+        FakeUpstream::start(target).await,
+    );
+    let (front, _) = start_front("round-robin", &[&a, &b], options()).await;
+
+    for _ in 0..4 {
+        assert_echo(front, target).await;
+    }
+    assert_eq!((a.served(), b.served()), (2, 2));
+}
+
+#[tokio::test]
+async fn least_connections() {
+    let target = support::start_echo().await;
+    let (a, b) = (
+        FakeUpstream::start(target).await,
+        FakeUpstream::start(target).await,
+    );
+    let (front, pool) = start_front("least-connections", &[&a, &b], options()).await;
+
+    let _open = assert_echo(front, target).await;
+    let busy = pool
+        .status()
+        .iter()
+        .position(|member| member.connections == 1)
+        .unwrap();
+    // Closed connections leave the other upstream idle for the next one
+    for _ in 0..3 {
+        drop(assert_echo(front, target).await);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let served = [a.served(), b.served()];
+    assert_eq!(served[busy], 1);
+    assert_eq!(served[1 - busy], 3);
+}
+
+#[tokio::test]
+/// Connections to a destination stay on one upstream until it fails
+async fn consistent_hash() {
+    let target = support::start_echo().await;
+    let upstreams = [
+        FakeUpstream::start(target).await,
+        FakeUpstream::start(target).await,
+        FakeUpstream::start(target).await,
+    ];
+    let (front, _) = start_front(
+        "consistent-hash",
+        &[&upstreams[0], &upstreams[1], &upstreams[2]],
+        options(),
+    )
+    .await;
+
+    for _ in 0..3 {
+        assert_echo(front, target).await;
+    }
+    let served: Vec<usize> = upstreams.iter().map(FakeUpstream::served).collect();
+    let chosen = served.iter().position(|&served| served == 3).unwrap();
+    assert_eq!(served.iter().sum::<usize>(), 3);
+
+    upstreams[chosen].set_mode(DOWN);
+    for _ in 0..3 {
+        assert_echo(front, target).await;
+    }
+    let moved: Vec<usize> = upstreams
+        .iter()
+        .map(FakeUpstream::served)
+        .zip(&served)
+        .map(|(now, before)| now - before)
+        .collect();
+    assert!(moved.contains(&3));
+}
+
+#[tokio::test]
+/// Failed connections are retried on the next upstream, which is ejected after repeated failures
+async fn failover() {
+    let target = support::start_echo().await;
+    let (a, b) = (
+        FakeUpstream::start(target).await,
+        FakeUpstream::start(target).await,
+    );
+    a.set_mode(DOWN);
+    let (front, pool) = start_front(
+        "round-robin",
+        &[&a, &b],
+        PoolOptions {
+            max_failures: 2,
+            ..options()
+        },
+    )
+    .await;
+
+    for _ in 0..4 {
+        assert_echo(front, target).await;
+    }
+    assert_eq!(b.served(), 4);
+    let status = pool.status();
+    assert!(status[0].ejected.is_some());
+    assert!(status[1].ejected.is_none());
+}
+
+#[tokio::test]
+/// Targets refused by an upstream are not retried, the upstream works
+async fn refused_target() {
+    let target = support::start_echo().await;
+    let (a, b) = (
+        FakeUpstream::start(target).await,
+        FakeUpstream::start(target).await,
+    );
+    a.set_mode(REFUSE);
+    b.set_mode(REFUSE);
+    let (front, pool) = start_front(
+        "round-robin",
+        &[&a, &b],
+        PoolOptions {
+            max_failures: 1,
+            ..options()
+        },
+    )
+    .await;
+
+    let (_, reply) = support::connect(front, target).await;
+    assert_eq!(reply.rep, ResponseCode::ConnectionRefused as u8);
+    assert!(pool.status().iter().all(|member| member.ejected.is_none()));
+}
+
+#[tokio::test]
+/// Upstreams failing health checks are skipped until they pass again
+async fn health_check() {
+    let target = support::start_echo().await;
+    let (a, b) = (
+        FakeUpstream::start(target).await,
+        FakeUpstream::start(target).await,
+    );
+    let (front, pool) = start_front(
+        "round-robin",
+        &[&a, &b],
+        PoolOptions {
+            max_failures: 1,
+            health_check: Some(HealthCheck {
+                interval: Duration::from_millis(50),
+                probe: Some(target.to_string().parse().unwrap()),
+            }),
+            ..PoolOptions::default()
+        },
+    )
+    .await;
+
+    a.set_mode(DOWN);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!pool.status()[0].healthy);
+    assert!(pool.status()[1].healthy);
+    // Trying the unhealthy upstream would have ejected it
+    for _ in 0..2 {
+        assert_echo(front, target).await;
+    }
+    assert!(pool.status()[0].ejected.is_none());
+
+    a.set_mode(UP);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(pool.status()[0].healthy);
+}
@@ -0,0 +1,163 @@
+mod support;
+
+use merino::*;
+use std::net::{IpAddr, SocketAddr};
+
+fn blocks(filter: &PrivateFilter, ip: &str) -> bool {
+    filter.blocks(&ip.parse::<IpAddr>().unwrap())
+}
+
+#[test]
+/// An address from each special-purpose range is blocked
+fn special_purpose_ranges() {
+    let filter = PrivateFilter::default();
+    for ip in [
+        "0.1.2.3",
+        "10.20.30.40",
+        "100.64.0.1",
+        "100.127.255.254",
+        "127.0.0.1",
+        "127.255.255.255",
+        "169.254.169.254",
+        "172.16.0.1",
+        "172.31.255.255",
+        "192.0.0.8",
+        "192.0.2.1",
+        "192.88.99.1",
+        "192.168.1.1",
+        "198.18.0.1",
+        "198.19.255.255",
+        "198.51.100.1",
+        "203.0.113.1",
+        "224.0.0.1",
+        "239.255.255.250",
+        "240.0.0.1",
+        "255.255.255.255",
+        "::",
+        "::1",
+        "::127.0.0.1",
+        "64:ff9b:1::1",
+        "100::1",
+        "2001::1",
+        "2001:db8::1",
+        "fc00::1",
+        "fd12:3456::1",
+        "fe80::1",
+        "fec0::1",
+        "ff02::1",
+    ] {
+        assert!(blocks(&filter, ip), "{}", ip);
+    }
+}
+
+#[test]
+fn public_addresses() {
+    let filter = PrivateFilter::default();
+    for ip in [
+        "1.1.1.1",
+        "8.8.8.8",
+        "100.63.255.255",
+        "100.128.0.0",
+        "172.15.255.255",
+        "172.32.0.0",
+        "192.169.0.1",
+        "198.20.0.1",
+        "223.255.255.255",
+        "2606:4700:4700::1111",
+        "2a00:1450::1",
+    ] {
+        assert!(!blocks(&filter, ip), "{}", ip);
+    }
+}
+
+#[test]
+/// IPv4 embedded into IPv6 addresses is checked as IPv4
+fn embedded_ipv4() {
+    let filter = PrivateFilter::default();
+    assert!(blocks(&filter, "::ffff:127.0.0.1"));
+    assert!(blocks(&filter, "::ffff:169.254.169.254"));
+    assert!(!blocks(&filter, "::ffff:8.8.8.8"));
+
+    // NAT64
+    assert!(blocks(&filter, "64:ff9b::10.0.0.1"));
+    assert!(!blocks(&filter, "64:ff9b::8.8.8.8"));
+
+    // 6to4
+    assert!(blocks(&filter, "2002:c0a8:101::1"));
+    assert!(!blocks(&filter, "2002:808:808::1"));
+}
+
+#[test]
+fn allowlist() {
+    let allowed: IpSet = ["10.1.2.0/24", "fd00::1"]
+        .iter()
+        .map(|net| parse_net(net).unwrap())
+        .collect();
+    let filter = PrivateFilter::new(allowed);
+
+    assert!(!blocks(&filter, "10.1.2.3"));
+    assert!(!blocks(&filter, "::ffff:10.1.2.3"));
+    assert!(blocks(&filter, "10.1.3.1"));
+    assert!(!blocks(&filter, "fd00::1"));
+    assert!(blocks(&filter, "fd00::2"));
+}
+
+/// Start a NoAuth proxy on a random port of `ip`, reached through loopback
+async fn listening_on(ip: &str, filter: Option<Option<PrivateFilter>>) -> SocketAddr {
+    let mut merino = Merino::new(
+        0,
+        ip,
+        vec![AuthMethods::NoAuth as u8],
+        Box::new(MemoryAuthenticator::default()),
+        Timeouts::default(),
+    )
+    .await
+    .unwrap();
+    if let Some(filter) = filter {
+        merino.set_private_filter(filter);
+    }
+    let port = support::serve(merino).port();
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+#[tokio::test]
+/// Private destinations are blocked by default unless listening on loopback
+async fn blocked_by_default() {
+    let target = support::start_echo().await;
+
+    let proxy = listening_on("0.0.0.0", None).await;
+    assert_eq!(
+        support::connect(proxy, target).await.1.rep,
+        ResponseCode::RuleFailure as u8
+    );
+
+    let proxy = listening_on("127.0.0.1", None).await;
+    assert_eq!(
+        support::connect(proxy, target).await.1.rep,
+        ResponseCode::Success as u8
+    );
+}
+
+#[tokio::test]
+async fn configured() {
+    let target = support::start_echo().await;
+
+    let proxy = listening_on("0.0.0.0", Some(None)).await;
+    assert_eq!(
+        support::connect(proxy, target).await.1.rep,
+        ResponseCode::Success as u8
+    );
+
+    let proxy = listening_on("127.0.0.1", Some(Some(PrivateFilter::default()))).await;
+    assert_eq!(
+        support::connect(proxy, target).await.1.rep,
+        ResponseCode::RuleFailure as u8
+    );
+
+    let allowed = std::iter::once(parse_net("127.0.0.1").unwrap()).collect();
+    let proxy = listening_on("127.0.0.1", Some(Some(PrivateFilter::new(allowed)))).await;
+    assert_eq!(
+        support::connect(proxy, target).await.1.rep,
+        ResponseCode::Success as u8
+    );
+}
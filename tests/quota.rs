@@ -0,0 +1,250 @@
+mod support;
+
+use merino::*;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+fn daily(bytes: u64) -> Option<Quota> {
+    Some(Quota {
+        bytes,
+        period: Period::Day,
+    })
+}
+
+#[test]
+fn parse() {
+    assert_eq!(
+        "50G/month".parse(),
+        Ok(Quota {
+            bytes: 50 << 30,
+            period: Period::Month
+        })
+    );
+    assert_eq!("1k/Day".parse(), Ok(daily(1024).unwrap()));
+    assert!("50G".parse::<Quota>().is_err());
+    assert!("50G/week".parse::<Quota>().is_err());
+    assert!("lots/month".parse::<Quota>().is_err());
+}
+
+#[test]
+/// Sessions of a user share the counter
+fn accounting() {
+    let quotas = Quotas::new();
+    let first = quotas.session("alice", daily(1000));
+    let second = quotas.session("alice", daily(1000));
+    let bob = quotas.session("bob", None);
+
+    first.transferred(600);
+    assert!(!second.is_exhausted());
+    second.transferred(400);
+    assert!(first.is_exhausted());
+    bob.transferred(5000);
+    assert!(!bob.is_exhausted());
+
+    let usage = quotas.usage();
+    assert_eq!(usage.len(), 2);
+    assert_eq!(usage[0].username, "alice");
+    assert_eq!(usage[0].bytes, 1000);
+    assert_eq!(usage[0].quota, daily(1000));
+    assert_eq!(usage[0].period, Period::Day);
+    assert_eq!(usage[1].bytes, 5000);
+    assert_eq!(usage[1].quota, None);
+    assert_eq!(usage[1].period, Period::Month);
+
+    assert!(quotas.reset("alice"));
+    assert!(!first.is_exhausted());
+    assert!(!quotas.reset("carol"));
+}
+
+#[test]
+/// Users without their own quota get the default one
+fn default_quota() {
+    let mut quotas = Quotas::new();
+    quotas.set_default(daily(100));
+
+    let alice = quotas.session("alice", None);
+    alice.transferred(100);
+    assert!(alice.is_exhausted());
+
+    let bob = quotas.session("bob", daily(1000));
+    bob.transferred(100);
+    assert!(!bob.is_exhausted());
+}
+
+#[tokio::test]
+async fn exhausted() {
+    let mut quotas = Quotas::new();
+    let session = quotas.session("alice", daily(100));
+    session.transferred(100);
+    // Only new sessions are refused by default
+    assert!(timeout(Duration::from_millis(50), session.exhausted())
+        .await
+        .is_err());
+
+    quotas.set_close_sessions(true);
+    let session = quotas.session("bob", daily(100));
+    let waiting = tokio::spawn(async move {
+        session.exhausted().await;
+    });
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    quotas.session("bob", daily(100)).transferred(150);
+    timeout(Duration::from_secs(1), waiting)
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[test]
+/// Counters survive restarts, counters of past periods start over
+fn persistence() {
+    let path = support::temp_path("state");
+    let quotas = Quotas::load(&path).unwrap();
+    assert!(quotas.usage().is_empty());
+    quotas.session("alice", daily(1000)).transferred(300);
+    quotas.save().unwrap();
+
+    let quotas = Quotas::load(&path).unwrap();
+    let usage = quotas.usage();
+    assert_eq!(usage.len(), 1);
+    assert_eq!(usage[0].bytes, 300);
+    assert_eq!(usage[0].period, Period::Day);
+
+    std::fs::write(
+        &path,
+        "username,period,start,bytes\nalice,month,2000-01-01,300\n",
+    )
+    .unwrap();
+    let quotas = Quotas::load(&path).unwrap();
+    assert_eq!(quotas.usage()[0].bytes, 0);
+    assert_ne!(quotas.usage()[0].start, "2000-01-01");
+
+    std::fs::write(
+        &path,
+        "username,period,start,bytes\nalice,week,2000-01-01,300\n",
+    )
+    .unwrap();
+    assert!(Quotas::load(&path).is_err());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+/// Saves running at the same time don't get in each other's way
+fn concurrent_saves() {
+    let path = support::temp_path("concurrent");
+    let quotas = std::sync::Arc::new(Quotas::load(&path).unwrap());
+    quotas.session("alice", daily(1000)).transferred(300);
+
+    let savers: Vec<_> = (0..8)
+        .map(|_| {
+            let quotas = quotas.clone();
+            std::thread::spawn(move || (0..50).try_for_each(|_| quotas.save()))
+        })
+        .collect();
+    for saver in savers {
+        saver.join().unwrap().unwrap();
+    }
+
+    assert_eq!(Quotas::load(&path).unwrap().usage()[0].bytes, 300);
+    std::fs::remove_file(path).unwrap();
+}
+
+/// Start a target which sends `size` bytes to every connection
+async fn start_sender(size: usize) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let _ = stream.write_all(&vec![0u8; size]).await;
+            });
+        }
+    });
+    addr
+}
+
+/// Proxy with alice, whose quota is 64 KiB a day
+async fn quota_proxy(close_sessions: bool) -> Merino {
+    let mut permissions = Permissions::default();
+    permissions.quota = daily(64 * 1024);
+    let mut alice = User::new("alice", "secret");
+    alice.set_permissions(permissions);
+
+    let mut merino = support::proxy(vec![AuthMethods::UserPass as u8], vec![alice]).await;
+    let mut quotas = Quotas::new();
+    quotas.set_close_sessions(close_sessions);
+    merino.set_quotas(quotas);
+    merino
+}
+
+/// Log in as alice and CONNECT to `target`, returning the connection and REP of the reply
+async fn connect_as_alice(proxy: SocketAddr, target: SocketAddr) -> (TcpStream, u8) {
+    let mut client = support::login(proxy, "alice", "secret").await;
+    let reply = support::request(&mut client, 1, &support::encode_addr(target)).await;
+    (client, reply.rep)
+}
+
+#[tokio::test]
+/// Once the quota is used up, new connections are refused
+async fn refuses_new_sessions() {
+    let target = start_sender(100 * 1024).await;
+    let merino = quota_proxy(false).await;
+    let quotas = merino.get_quotas();
+    let proxy = support::serve(merino);
+
+    let (mut client, rep) = connect_as_alice(proxy, target).await;
+    assert_eq!(rep, ResponseCode::Success as u8);
+    // The running session goes on past the quota
+    let mut data = Vec::new();
+    client.read_to_end(&mut data).await.unwrap();
+    assert_eq!(data.len(), 100 * 1024);
+
+    let (_, rep) = connect_as_alice(proxy, target).await;
+    assert_eq!(rep, ResponseCode::RuleFailure as u8);
+
+    assert!(quotas.reset("alice"));
+    let (_, rep) = connect_as_alice(proxy, target).await;
+    assert_eq!(rep, ResponseCode::Success as u8);
+}
+
+#[tokio::test]
+/// Running sessions are closed when the quota is used up, if configured
+async fn closes_sessions() {
+    let target = start_sender(64 * 1024 * 1024).await;
+    let merino = quota_proxy(true).await;
+    let stats = merino.get_stats();
+    let proxy = support::serve(merino);
+
+    let (mut client, rep) = connect_as_alice(proxy, target).await;
+    assert_eq!(rep, ResponseCode::Success as u8);
+    let mut data = Vec::new();
+    let _ = client.read_to_end(&mut data).await;
+    assert!(data.len() >= 64 * 1024);
+    assert!(data.len() < 64 * 1024 * 1024);
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let stats = stats.read().unwrap();
+    assert_eq!(stats.closed.get(&CloseReason::Quota), Some(&1));
+}
+
+#[tokio::test]
+/// Quotas of users are read from the users file
+async fn users_file() {
+    let path = support::temp_path("users");
+    std::fs::write(
+        &path,
+        "username,password,quota\nalice,secret,1G/day\nbob,secret,\n",
+    )
+    .unwrap();
+    let auth = CsvAuthenticator::load(&path, true).unwrap();
+    let alice = auth.identify("alice").await.unwrap();
+    assert_eq!(alice.permissions.quota, daily(1 << 30));
+    let bob = auth.identify("bob").await.unwrap();
+    assert_eq!(bob.permissions.quota, None);
+
+    std::fs::write(&path, "username,password,quota\nalice,secret,1G\n").unwrap();
+    assert!(CsvAuthenticator::load(&path, true).is_err());
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,208 @@
+mod support;
+
+use merino::*;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+
+/// Hashes of "secret" and "hunter2" with cheap parameters
+const SECRET: &str =
+    "$argon2id$v=19$m=64,t=1,p=1$bWVyaW5vLXRlc3Qtc2FsdA$Gz0H6PAQC5r/v21yM7nQWHJ2LAqb8LZzbJQoJuz/z6A";
+const HUNTER2: &str = "$2b$04$aHYE9qlqse.2S3/2HciMFO97P60co7Fl/QENodRNfuNdaUOJ7vsoi";
+
+/// Unique directory for the files of a test
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = support::temp_path(name);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Write a users file only its owner can access
+fn write_users(path: &Path, password_hash: &str) {
+    std::fs::write(
+        path,
+        format!("username,password\nalice,\"{}\"\n", password_hash),
+    )
+    .unwrap();
+    #[cfg(unix)]
+    set_mode(path, 0o600);
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+}
+
+/// Start a USER/PASS proxy with `whitelist` on a random loopback port
+async fn reloading_proxy(users: &Path, whitelist: &Path) -> (SocketAddr, Reloader) {
+    let mut merino = support::proxy_with(
+        vec![AuthMethods::UserPass as u8],
+        Box::new(CsvAuthenticator::load(users, false).unwrap()),
+        Timeouts::default(),
+    )
+    .await;
+    merino.load_whitelist(whitelist);
+    let reloader = merino.get_reloader();
+    (support::serve(merino), reloader)
+}
+
+/// Authenticate with USER/PASS, returning the status of the sub-negotiation
+async fn login(proxy: SocketAddr, password: &str) -> u8 {
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(&[5, 1, 2]).await.unwrap();
+    let mut method = [0u8; 2];
+    client.read_exact(&mut method).await.unwrap();
+
+    let mut request = vec![1, 5];
+    request.extend_from_slice(b"alice");
+    request.push(password.len() as u8);
+    request.extend_from_slice(password.as_bytes());
+    client.write_all(&request).await.unwrap();
+
+    let mut status = [0u8; 2];
+    client.read_exact(&mut status).await.unwrap();
+    status[1]
+}
+
+/// Offer only NO AUTH, returning the selected method
+async fn selected_method(proxy: SocketAddr) -> u8 {
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut method = [0u8; 2];
+    client.read_exact(&mut method).await.unwrap();
+    method[1]
+}
+
+/// Poll `check` until it's true or a second passes
+async fn eventually<F, Fut>(mut check: F) -> bool
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = bool>,
+{
+    for _ in 0..20 {
+        if check().await {
+            return true;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    false
+}
+
+#[tokio::test]
+async fn reload_users() {
+    let dir = temp_dir("users");
+    let users = dir.join("users.csv");
+    write_users(&users, SECRET);
+    let (proxy, reloader) = reloading_proxy(&users, &dir.join("allowed.txt")).await;
+
+    assert_eq!(login(proxy, "secret").await, 0);
+
+    write_users(&users, HUNTER2);
+    assert!(reloader.reload().await);
+    assert_ne!(login(proxy, "secret").await, 0);
+    assert_eq!(login(proxy, "hunter2").await, 0);
+
+    // Broken file keeps the previous users
+    std::fs::write(&users, "username,password\nalice\n").unwrap();
+    assert!(!reloader.reload().await);
+    assert_eq!(login(proxy, "hunter2").await, 0);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+/// Reloading checks the permissions of the users file like loading it does
+async fn reload_refuses_open_users_file() {
+    let dir = temp_dir("open");
+    let users = dir.join("users.csv");
+    write_users(&users, SECRET);
+    let (proxy, reloader) = reloading_proxy(&users, &dir.join("allowed.txt")).await;
+
+    write_users(&users, HUNTER2);
+    set_mode(&users, 0o644);
+    assert!(!reloader.reload().await);
+    assert_eq!(login(proxy, "secret").await, 0);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn reload_whitelist() {
+    let dir = temp_dir("whitelist");
+    let users = dir.join("users.csv");
+    write_users(&users, SECRET);
+    let whitelist = dir.join("allowed.txt");
+    let (proxy, reloader) = reloading_proxy(&users, &whitelist).await;
+
+    assert_eq!(selected_method(proxy).await, AuthMethods::NoMethods as u8);
+
+    std::fs::write(&whitelist, "127.0.0.1\n").unwrap();
+    assert!(reloader.reload().await);
+    assert_eq!(selected_method(proxy).await, AuthMethods::NoAuth as u8);
+
+    // Broken file keeps the previous whitelist
+    std::fs::write(&whitelist, "127.0.0.1\nlocalhost\n").unwrap();
+    assert!(!reloader.reload().await);
+    assert_eq!(selected_method(proxy).await, AuthMethods::NoAuth as u8);
+
+    // Addresses are removed as well
+    std::fs::write(&whitelist, "").unwrap();
+    assert!(reloader.reload().await);
+    assert_eq!(selected_method(proxy).await, AuthMethods::NoMethods as u8);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn reload_on_sighup() {
+    let dir = temp_dir("sighup");
+    let users = dir.join("users.csv");
+    write_users(&users, SECRET);
+    let (proxy, reloader) = reloading_proxy(&users, &dir.join("allowed.txt")).await;
+
+    tokio::spawn(reloader.watch_sighup());
+    // Let the handler get installed, SIGHUP would terminate the tests otherwise
+    sleep(Duration::from_millis(100)).await;
+
+    write_users(&users, HUNTER2);
+    let status = std::process::Command::new("kill")
+        .args(["-HUP", &std::process::id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    assert!(eventually(|| async { login(proxy, "hunter2").await == 0 }).await);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn reload_on_change() {
+    let dir = temp_dir("watch");
+    let users = dir.join("users.csv");
+    write_users(&users, SECRET);
+    let whitelist = dir.join("allowed.txt");
+    let (proxy, reloader) = reloading_proxy(&users, &whitelist).await;
+
+    tokio::spawn(reloader.watch_files());
+    sleep(Duration::from_millis(100)).await;
+
+    // Replaced the way editors do it
+    let tmp = dir.join("users.csv.tmp");
+    write_users(&tmp, HUNTER2);
+    std::fs::rename(&tmp, &users).unwrap();
+    assert!(eventually(|| async { login(proxy, "hunter2").await == 0 }).await);
+
+    std::fs::write(&whitelist, "127.0.0.1\n").unwrap();
+    assert!(
+        eventually(|| async { selected_method(proxy).await == AuthMethods::NoAuth as u8 }).await
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
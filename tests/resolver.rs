@@ -0,0 +1,138 @@
+mod support;
+
+use merino::*;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+/// Start a DNS server answering A queries for `name` with 127.0.0.1 and NXDOMAIN for others.
+/// If `silent`, queries are never answered.
+async fn start_stub_dns(name: &'static str, silent: bool) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        while let Ok((len, src)) = socket.recv_from(&mut buf).await {
+            if silent {
+                continue;
+            }
+            socket
+                .send_to(&answer(&buf[..len], name), src)
+                .await
+                .unwrap();
+        }
+    });
+    addr
+}
+
+/// Build the response for a DNS query
+fn answer(query: &[u8], name: &str) -> Vec<u8> {
+    // Question section starts after the 12 bytes header
+    let mut labels = Vec::new();
+    let mut pos = 12;
+    while query[pos] != 0 {
+        let len = query[pos] as usize;
+        labels.push(String::from_utf8_lossy(&query[pos + 1..pos + 1 + len]).to_string());
+        pos += len + 1;
+    }
+    let qtype = u16::from_be_bytes([query[pos + 1], query[pos + 2]]);
+    let question_end = pos + 5;
+
+    let known = labels.join(".").eq_ignore_ascii_case(name);
+    let with_record = known && qtype == 1;
+
+    let mut response = query[..2].to_vec();
+    // QR, RD, RA and RCODE: NOERROR or NXDOMAIN
+    response.extend_from_slice(&[0x81, if known { 0x80 } else { 0x83 }]);
+    // QDCOUNT, ANCOUNT, NSCOUNT, ARCOUNT
+    response.extend_from_slice(&[0, 1, 0, with_record as u8, 0, 0, 0, 0]);
+    response.extend_from_slice(&query[12..question_end]);
+    if with_record {
+        // Pointer to the name in the question, A, IN, TTL, RDLENGTH, RDATA
+        response.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 127, 0, 0, 1]);
+    }
+    response
+}
+
+#[tokio::test]
+/// Names are resolved by the configured nameserver
+async fn resolves_with_nameserver() {
+    let dns = start_stub_dns("proxy.test", false).await;
+    let resolver = Resolver::new(&[dns], None, Duration::from_secs(1)).unwrap();
+
+    assert_eq!(
+        resolver.lookup("proxy.test", 80).await.unwrap(),
+        vec![SocketAddr::from((Ipv4Addr::LOCALHOST, 80))]
+    );
+}
+
+#[tokio::test]
+/// NXDOMAIN is reported as an unreachable host
+async fn unknown_name_is_unreachable() {
+    let dns = start_stub_dns("proxy.test", false).await;
+    let resolver = Resolver::new(&[dns], None, Duration::from_secs(1)).unwrap();
+
+    let error = resolver.lookup("missing.test", 80).await.unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::HostUnreachable);
+}
+
+#[tokio::test]
+/// Lookups give up after the DNS timeout
+async fn lookup_times_out() {
+    let dns = start_stub_dns("proxy.test", true).await;
+    let resolver = Resolver::new(&[dns], None, Duration::from_millis(200)).unwrap();
+
+    let start = Instant::now();
+    let error = resolver.lookup("proxy.test", 80).await.unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::TimedOut);
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+#[tokio::test]
+/// Hosts file takes precedence over DNS
+async fn resolves_from_hosts_file() {
+    let dns = start_stub_dns("proxy.test", true).await;
+    let path = support::temp_file(
+        "hosts",
+        "# comment\n10.1.2.3 custom.test alias.test\n\n::1 custom.test\n",
+    );
+    let resolver = Resolver::new(&[dns], Some(&path), Duration::from_millis(200)).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let expected: Vec<SocketAddr> =
+        vec!["10.1.2.3:80".parse().unwrap(), "[::1]:80".parse().unwrap()];
+    assert_eq!(resolver.lookup("Custom.Test.", 80).await.unwrap(), expected);
+    assert_eq!(
+        resolver.lookup("alias.test", 80).await.unwrap(),
+        expected[..1].to_vec()
+    );
+}
+
+#[tokio::test]
+/// CONNECT to a domain goes through the configured resolver
+async fn connect_to_domain() {
+    let dns = start_stub_dns("echo.test", false).await;
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = target.local_addr().unwrap().port();
+
+    let mut merino = support::proxy(vec![AuthMethods::NoAuth as u8], Vec::new()).await;
+    merino.set_resolver(Resolver::new(&[dns], None, Duration::from_secs(1)).unwrap());
+    let proxy = support::serve(merino);
+
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut method = [0u8; 2];
+    client.read_exact(&mut method).await.unwrap();
+
+    let mut request = vec![5, 1, 0, 3, 9];
+    request.extend_from_slice(b"echo.test");
+    request.extend_from_slice(&port.to_be_bytes());
+    client.write_all(&request).await.unwrap();
+
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], ResponseCode::Success as u8);
+    assert!(target.accept().await.is_ok());
+}
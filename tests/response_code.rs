@@ -0,0 +1,90 @@
+mod support;
+
+use merino::*;
+use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, TcpListener};
+
+fn code(kind: ErrorKind) -> ResponseCode {
+    ResponseCode::from(MerinoError::Io(Error::from(kind)))
+}
+
+#[test]
+fn connection_refused() {
+    assert_eq!(
+        code(ErrorKind::ConnectionRefused),
+        ResponseCode::ConnectionRefused
+    );
+}
+
+#[test]
+fn host_unreachable() {
+    assert_eq!(
+        code(ErrorKind::HostUnreachable),
+        ResponseCode::HostUnreachable
+    );
+}
+
+#[test]
+fn network_unreachable() {
+    assert_eq!(
+        code(ErrorKind::NetworkUnreachable),
+        ResponseCode::NetworkUnreachable
+    );
+}
+
+#[test]
+fn timed_out() {
+    assert_eq!(code(ErrorKind::TimedOut), ResponseCode::TtlExpired);
+}
+
+#[test]
+fn permission_denied() {
+    assert_eq!(code(ErrorKind::PermissionDenied), ResponseCode::RuleFailure);
+}
+
+#[test]
+fn other_io_error() {
+    assert_eq!(code(ErrorKind::Other), ResponseCode::Failure);
+}
+
+#[test]
+fn socks_error() {
+    for expected in [
+        ResponseCode::RuleFailure,
+        ResponseCode::TtlExpired,
+        ResponseCode::CommandNotSupported,
+    ] {
+        assert_eq!(ResponseCode::from(MerinoError::Socks(expected)), expected);
+    }
+}
+
+#[tokio::test]
+/// Closed port is reported as "Connection refused"
+async fn connect_refused_reply() {
+    // Reserve a port and close it again
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let proxy = support::start_proxy(|_| {}).await;
+    let target = SocketAddr::from(([127, 0, 0, 1], port));
+    assert_eq!(
+        support::connect(proxy, target).await.1.rep,
+        ResponseCode::ConnectionRefused as u8
+    );
+}
+
+#[tokio::test]
+/// Domain which can't be resolved is reported as "Host unreachable"
+async fn unresolvable_domain_reply() {
+    let proxy = support::start_proxy(|_| {}).await;
+    assert_eq!(
+        support::connect_domain(proxy, "nonexistent.invalid", 80)
+            .await
+            .1
+            .rep,
+        ResponseCode::HostUnreachable as u8
+    );
+}
@@ -0,0 +1,256 @@
+mod support;
+
+use merino::rules::{Action, Command, Request};
+use merino::*;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::timeout;
+
+fn request<'a>(domain: Option<&'a str>, ip: &str, port: u16) -> Request<'a> {
+    Request {
+        command: Command::Connect,
+        user: None,
+        source: "192.168.1.10".parse().unwrap(),
+        domain,
+        ip: Some(ip.parse().unwrap()),
+        port,
+    }
+}
+
+/// Line of the rule matching `req`, 0 if none
+fn matching(rules: &Rules, req: &Request<'_>) -> usize {
+    rules.check(req).map(|rule| rule.line).unwrap_or_default()
+}
+
+#[test]
+fn parse_errors() {
+    for rules in [
+        "permit dest=10.0.0.0/8",
+        "deny dest",
+        "deny host=example.com",
+        "deny port=80-20",
+        "deny port=http",
+        "deny command=associate",
+        "deny source=example.com",
+    ] {
+        assert!(rules.parse::<Rules>().is_err(), "{}", rules);
+    }
+
+    let error = "allow\n\n# comment\ndeny port=x"
+        .parse::<Rules>()
+        .unwrap_err();
+    assert!(error.starts_with("line 4:"), "{}", error);
+}
+
+#[test]
+fn destinations() {
+    let rules: Rules = "
+        deny dest=10.0.0.0/8,2001:db8::/32
+        deny dest=.internal.example
+        deny dest=admin.*.example.org
+        deny dest=exact.example.net
+    "
+    .parse()
+    .unwrap();
+    assert_eq!(rules.len(), 4);
+
+    assert_eq!(matching(&rules, &request(None, "10.1.1.1", 80)), 2);
+    assert_eq!(matching(&rules, &request(None, "2001:db8::1", 80)), 2);
+    assert_eq!(matching(&rules, &request(None, "11.1.1.1", 80)), 0);
+    // Domains are checked together with the address they resolve to
+    assert_eq!(
+        matching(&rules, &request(Some("example.com"), "10.0.0.1", 80)),
+        2
+    );
+
+    let ip = "203.0.113.1";
+    assert_eq!(
+        matching(&rules, &request(Some("internal.example"), ip, 80)),
+        3
+    );
+    assert_eq!(
+        matching(&rules, &request(Some("a.b.Internal.Example."), ip, 80)),
+        3
+    );
+    assert_eq!(
+        matching(&rules, &request(Some("notinternal.example"), ip, 80)),
+        0
+    );
+    assert_eq!(
+        matching(&rules, &request(Some("admin.eu.example.org"), ip, 80)),
+        4
+    );
+    assert_eq!(
+        matching(&rules, &request(Some("admin.example.org"), ip, 80)),
+        0
+    );
+    assert_eq!(
+        matching(&rules, &request(Some("exact.example.net"), ip, 80)),
+        5
+    );
+    assert_eq!(
+        matching(&rules, &request(Some("www.exact.example.net"), ip, 80)),
+        0
+    );
+}
+
+#[test]
+fn conditions() {
+    let rules: Rules = "
+        allow user=alice,bob port=22
+        deny port=1-1023 command=connect,bind
+        deny command=udp source=192.168.0.0/16
+        allow
+    "
+    .parse()
+    .unwrap();
+    let ip = "203.0.113.1";
+
+    let mut req = request(None, ip, 22);
+    assert_eq!(matching(&rules, &req), 3);
+    req.user = Some("bob");
+    assert_eq!(matching(&rules, &req), 2);
+    assert_eq!(rules.check(&req).unwrap().action, Action::Allow);
+    req.user = Some("carol");
+    assert_eq!(matching(&rules, &req), 3);
+    assert!(!rules.allows(&req));
+
+    let mut req = request(None, ip, 1024);
+    assert_eq!(matching(&rules, &req), 5);
+    req.command = Command::Udp;
+    assert_eq!(matching(&rules, &req), 4);
+    req.source = "10.0.0.1".parse().unwrap();
+    assert_eq!(matching(&rules, &req), 5);
+    assert!(rules.allows(&req));
+}
+
+#[test]
+/// Commands are only denied up front by rules which don't depend on the destination
+fn commands() {
+    let rules: Rules = "
+        deny command=udp port=53
+        allow command=udp user=alice dest=10.0.0.0/8
+        deny command=udp
+    "
+    .parse()
+    .unwrap();
+    let mut req = Request {
+        command: Command::Udp,
+        user: None,
+        source: "192.168.1.10".parse().unwrap(),
+        domain: None,
+        ip: None,
+        port: 0,
+    };
+
+    assert!(!rules.allows_command(&req));
+    req.user = Some("alice");
+    assert!(rules.allows_command(&req));
+    req.user = None;
+    req.command = Command::Connect;
+    assert!(rules.allows_command(&req));
+}
+
+#[test]
+/// Requests matching no rule are allowed
+fn default_allow() {
+    let rules = Rules::default();
+    assert!(rules.is_empty());
+    assert!(rules.allows(&request(None, "10.0.0.1", 80)));
+}
+
+/// Start a NoAuth proxy with `rules` on a random loopback port
+async fn rules_proxy(rules: &str) -> SocketAddr {
+    support::start_proxy(|merino| merino.set_rules(rules.parse().unwrap())).await
+}
+
+/// Negotiate NoAuth and send `command` for `target`, returning the reply
+async fn send_request(
+    proxy: SocketAddr,
+    command: u8,
+    target: SocketAddr,
+) -> (TcpStream, support::Reply) {
+    let mut client = support::no_auth(proxy).await;
+    let reply = support::request(&mut client, command, &support::encode_addr(target)).await;
+    (client, reply)
+}
+
+#[tokio::test]
+async fn connect_denied() {
+    let target = support::start_echo().await;
+    let proxy = rules_proxy(&format!(
+        "deny dest=127.0.0.0/8 port={}\nallow",
+        target.port()
+    ))
+    .await;
+
+    let (_, reply) = send_request(proxy, 1, target).await;
+    assert_eq!(reply.rep, ResponseCode::RuleFailure as u8);
+
+    let other = support::start_echo().await;
+    let (_, reply) = send_request(proxy, 1, other).await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+}
+
+#[tokio::test]
+async fn bind_denied() {
+    let proxy = rules_proxy("deny command=bind").await;
+    for target in ["127.0.0.1:0", "0.0.0.0:0"] {
+        let (_, reply) = send_request(proxy, 2, target.parse().unwrap()).await;
+        assert_eq!(reply.rep, ResponseCode::RuleFailure as u8, "{}", target);
+    }
+}
+
+#[tokio::test]
+/// BIND for 0.0.0.0 is checked against the address of the peer once it has connected
+async fn bind_unspecified() {
+    for (rules, allowed) in [
+        ("allow dest=127.0.0.1 command=bind\ndeny", true),
+        ("deny dest=127.0.0.1 command=bind", false),
+    ] {
+        let proxy = rules_proxy(rules).await;
+        let (mut client, reply) = send_request(proxy, 2, "0.0.0.0:0".parse().unwrap()).await;
+        assert_eq!(reply.rep, ResponseCode::Success as u8, "{}", rules);
+
+        let _peer = TcpStream::connect(reply.bound).await.unwrap();
+        let reply = support::read_reply(&mut client).await;
+        let expected = if allowed {
+            ResponseCode::Success
+        } else {
+            ResponseCode::RuleFailure
+        };
+        assert_eq!(reply.rep, expected as u8, "{}", rules);
+    }
+}
+
+#[tokio::test]
+async fn udp_associate_denied() {
+    let proxy = rules_proxy("deny command=udp").await;
+    let (_, reply) = send_request(proxy, 3, "0.0.0.0:0".parse().unwrap()).await;
+    assert_eq!(reply.rep, ResponseCode::RuleFailure as u8);
+}
+
+#[tokio::test]
+/// Datagrams to denied targets are dropped
+async fn udp_denied() {
+    let denied = support::start_udp_echo().await;
+    let allowed = support::start_udp_echo().await;
+    let proxy = rules_proxy(&format!("deny command=udp port={}", denied.port())).await;
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (_control, reply) = send_request(proxy, 3, client.local_addr().unwrap()).await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+    let relay = reply.bound;
+
+    let mut buf = [0u8; 1500];
+    for (target, answered) in [(denied, false), (allowed, true)] {
+        let mut datagram = vec![0, 0, 0];
+        datagram.extend_from_slice(&support::encode_addr(target));
+        datagram.extend_from_slice(b"hello");
+        client.send_to(&datagram, relay).await.unwrap();
+
+        let received = timeout(Duration::from_millis(200), client.recv(&mut buf)).await;
+        assert_eq!(received.is_ok(), answered, "{}", target);
+    }
+}
@@ -0,0 +1,222 @@
+mod support;
+
+use merino::*;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+
+const KIB: u64 = 1024;
+
+/// Send `total` bytes in 8 KiB chunks through `throttle`, returning the elapsed time
+async fn transfer<F, Fut>(total: u64, throttle: F) -> Duration
+where
+    F: Fn(usize) -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    let start = Instant::now();
+    let mut sent = 0;
+    while sent < total {
+        throttle(8 * 1024).await;
+        sent += 8 * 1024;
+    }
+    start.elapsed()
+}
+
+/// `elapsed` is about `secs` seconds
+fn assert_about(elapsed: Duration, secs: f64) {
+    let elapsed = elapsed.as_secs_f64();
+    assert!(
+        (elapsed - secs).abs() < 0.2,
+        "took {}s, expected {}s",
+        elapsed,
+        secs
+    );
+}
+
+fn ip(last: u8) -> IpAddr {
+    IpAddr::from([192, 0, 2, last])
+}
+
+#[test]
+fn rates() {
+    assert_eq!(parse_rate("512"), Ok(512));
+    assert_eq!(parse_rate("64K"), Ok(64 * 1024));
+    assert_eq!(parse_rate("10m"), Ok(10 * 1024 * 1024));
+    assert_eq!(parse_rate("1G"), Ok(1024 * 1024 * 1024));
+    assert!(parse_rate("0").is_err());
+    assert!(parse_rate("fast").is_err());
+    assert!(parse_rate("99999999999G").is_err());
+}
+
+#[tokio::test(start_paused = true)]
+/// After the initial burst of one second, traffic goes at the rate
+async fn bucket_rate() {
+    let bucket = TokenBucket::new(100 * KIB);
+    let elapsed = transfer(1000 * KIB, |n| bucket.consume(n)).await;
+    assert_about(elapsed, 9.0);
+}
+
+#[tokio::test(start_paused = true)]
+/// The bucket refills while idle, but only up to the burst
+async fn bucket_refill() {
+    let bucket = TokenBucket::new(100 * KIB);
+    transfer(100 * KIB, |n| bucket.consume(n)).await;
+
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let elapsed = transfer(300 * KIB, |n| bucket.consume(n)).await;
+    assert_about(elapsed, 2.0);
+}
+
+/// Run transfers of `total` bytes for each session at once, returning the longest time
+async fn concurrent(sessions: Vec<SessionShaper>, total: u64, upload: bool) -> Duration {
+    let tasks: Vec<_> = sessions
+        .into_iter()
+        .map(|session| {
+            tokio::spawn(async move {
+                let session = Arc::new(session);
+                transfer(total, |n| {
+                    let session = session.clone();
+                    async move {
+                        if upload {
+                            session.upload(n).await
+                        } else {
+                            session.download(n).await
+                        }
+                    }
+                })
+                .await
+            })
+        })
+        .collect();
+
+    let mut longest = Duration::ZERO;
+    for task in tasks {
+        longest = longest.max(task.await.unwrap());
+    }
+    longest
+}
+
+#[tokio::test(start_paused = true)]
+/// Sessions of a user share its buckets, other users have their own
+async fn per_user() {
+    let shaper = Shaper::new(BandwidthLimits {
+        per_user: Bandwidth {
+            upload: Some(100 * KIB),
+            download: None,
+        },
+        ..Default::default()
+    });
+
+    let sessions = vec![
+        shaper.session(Some("alice"), ip(1)),
+        shaper.session(Some("alice"), ip(2)),
+    ];
+    assert_about(concurrent(sessions, 500 * KIB, true).await, 9.0);
+
+    let sessions = vec![
+        shaper.session(Some("alice"), ip(1)),
+        shaper.session(Some("bob"), ip(1)),
+    ];
+    assert_about(concurrent(sessions, 500 * KIB, true).await, 4.0);
+
+    // Download is unlimited
+    let sessions = vec![shaper.session(Some("alice"), ip(1))];
+    assert_about(concurrent(sessions, 500 * KIB, false).await, 0.0);
+
+    // Sessions without a user are not limited
+    let sessions = vec![shaper.session(None, ip(1)), shaper.session(None, ip(1))];
+    assert_about(concurrent(sessions, 500 * KIB, true).await, 0.0);
+}
+
+#[tokio::test(start_paused = true)]
+/// Sessions from an address share its buckets, whoever the user is
+async fn per_ip() {
+    let shaper = Shaper::new(BandwidthLimits {
+        per_ip: Bandwidth {
+            upload: None,
+            download: Some(100 * KIB),
+        },
+        ..Default::default()
+    });
+
+    let sessions = vec![
+        shaper.session(Some("alice"), ip(1)),
+        shaper.session(Some("bob"), ip(1)),
+        shaper.session(None, ip(1)),
+    ];
+    assert_about(concurrent(sessions, 400 * KIB, false).await, 11.0);
+
+    let sessions = vec![shaper.session(None, ip(1)), shaper.session(None, ip(2))];
+    assert_about(concurrent(sessions, 400 * KIB, false).await, 3.0);
+}
+
+#[tokio::test(start_paused = true)]
+/// The slowest of the global, user and address limits wins
+async fn levels() {
+    let shaper = Shaper::new(BandwidthLimits {
+        global: Bandwidth {
+            upload: Some(200 * KIB),
+            download: None,
+        },
+        per_user: Bandwidth {
+            upload: Some(50 * KIB),
+            download: None,
+        },
+        per_ip: Bandwidth {
+            upload: Some(100 * KIB),
+            download: None,
+        },
+    });
+
+    // Alice is limited by her own bucket
+    let sessions = vec![shaper.session(Some("alice"), ip(1))];
+    assert_about(concurrent(sessions, 250 * KIB, true).await, 4.0);
+
+    // Four users from four addresses share the global bucket
+    let sessions = (1..=4)
+        .map(|i| shaper.session(Some(&format!("user{}", i)), ip(i)))
+        .collect();
+    assert_about(concurrent(sessions, 100 * KIB, true).await, 1.0);
+}
+
+#[tokio::test]
+/// Relayed sessions are shaped
+async fn relay_download() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        stream.write_all(&[0u8; 192 * 1024]).await.unwrap();
+    });
+
+    let mut merino = support::proxy(vec![AuthMethods::NoAuth as u8], Vec::new()).await;
+    merino.set_bandwidth_limits(BandwidthLimits {
+        per_ip: Bandwidth {
+            upload: None,
+            download: Some(128 * KIB),
+        },
+        ..Default::default()
+    });
+    let proxy = support::serve(merino);
+
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut method = [0u8; 2];
+    client.read_exact(&mut method).await.unwrap();
+    let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+    request.extend_from_slice(&target.port().to_be_bytes());
+    client.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], ResponseCode::Success as u8);
+
+    let start = std::time::Instant::now();
+    let mut data = Vec::new();
+    client.read_to_end(&mut data).await.unwrap();
+    assert_eq!(data.len(), 192 * 1024);
+    // One second of burst, then half a second at the rate
+    assert!(start.elapsed() >= Duration::from_millis(400));
+}
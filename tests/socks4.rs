@@ -0,0 +1,130 @@
+mod support;
+
+use merino::*;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+fn alice() -> Vec<User> {
+    vec![User::new("alice", "secret")]
+}
+
+/// Send a SOCKS4 CONNECT request, with a SOCKS4a `domain` if given
+async fn socks4_request(
+    proxy: SocketAddr,
+    target: SocketAddr,
+    user_id: &str,
+    domain: Option<&str>,
+) -> TcpStream {
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    let mut request = vec![SOCKS4_VERSION, 1];
+    request.extend_from_slice(&target.port().to_be_bytes());
+    match (domain, target) {
+        (Some(_), _) => request.extend_from_slice(&[0, 0, 0, 1]),
+        (None, SocketAddr::V4(target)) => request.extend_from_slice(&target.ip().octets()),
+        (None, SocketAddr::V6(_)) => unreachable!(),
+    }
+    request.extend_from_slice(user_id.as_bytes());
+    request.push(0);
+    if let Some(domain) = domain {
+        request.extend_from_slice(domain.as_bytes());
+        request.push(0);
+    }
+    client.write_all(&request).await.unwrap();
+    client
+}
+
+async fn read_socks4_reply(client: &mut TcpStream) -> u8 {
+    let mut reply = [0u8; 8];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[0], 0);
+    reply[1]
+}
+
+async fn assert_echo(client: &mut TcpStream) {
+    let mut buf = [0u8; 4];
+    client.write_all(b"ping").await.unwrap();
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+}
+
+#[tokio::test]
+/// SOCKS4 CONNECT to an IPv4 address
+async fn socks4_connect() {
+    let proxy = support::start_proxy_with(vec![AuthMethods::NoAuth as u8], alice(), |_| {}).await;
+    let echo = support::start_echo().await;
+
+    let mut client = socks4_request(proxy, echo, "", None).await;
+    assert_eq!(
+        read_socks4_reply(&mut client).await,
+        Socks4Code::Granted as u8
+    );
+    assert_echo(&mut client).await;
+}
+
+#[tokio::test]
+/// SOCKS4a CONNECT to a domain name resolved by the proxy
+async fn socks4a_connect() {
+    let proxy = support::start_proxy_with(vec![AuthMethods::NoAuth as u8], alice(), |_| {}).await;
+    let echo = support::start_echo().await;
+
+    let mut client = socks4_request(proxy, echo, "", Some("127.0.0.1")).await;
+    assert_eq!(
+        read_socks4_reply(&mut client).await,
+        Socks4Code::Granted as u8
+    );
+    assert_echo(&mut client).await;
+}
+
+#[tokio::test]
+/// Disabled SOCKS4 is treated as an unsupported version
+async fn socks4_disabled() {
+    let proxy = support::start_proxy_with(vec![AuthMethods::NoAuth as u8], alice(), |m| {
+        m.set_socks4(false)
+    })
+    .await;
+    let echo = support::start_echo().await;
+
+    let mut client = socks4_request(proxy, echo, "", None).await;
+    let mut buf = [0u8; 8];
+    assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+}
+
+#[tokio::test]
+/// SOCKS4 can't bypass username/password authentication
+async fn socks4_rejected_when_auth_required() {
+    let proxy = support::start_proxy_with(vec![AuthMethods::UserPass as u8], alice(), |_| {}).await;
+    let echo = support::start_echo().await;
+
+    let mut client = socks4_request(proxy, echo, "alice", None).await;
+    assert_eq!(
+        read_socks4_reply(&mut client).await,
+        Socks4Code::Rejected as u8
+    );
+}
+
+#[tokio::test]
+/// USERID is checked against the users list
+async fn socks4_userid_check() {
+    let proxy = support::start_proxy_with(vec![AuthMethods::UserPass as u8], alice(), |m| {
+        m.set_socks4_check_userid(true)
+    })
+    .await;
+    let echo = support::start_echo().await;
+
+    let mut client = socks4_request(proxy, echo, "mallory", None).await;
+    assert_eq!(
+        read_socks4_reply(&mut client).await,
+        Socks4Code::UserIdMismatch as u8
+    );
+    // Nothing follows the reply
+    let mut buf = [0u8; 8];
+    assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+
+    let mut client = socks4_request(proxy, echo, "alice", None).await;
+    assert_eq!(
+        read_socks4_reply(&mut client).await,
+        Socks4Code::Granted as u8
+    );
+    assert_echo(&mut client).await;
+}
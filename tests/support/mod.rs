@@ -0,0 +1,223 @@
+//! Helpers shared by the integration tests. Each test crate uses only some of them.
+#![allow(dead_code)]
+
+use merino::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+
+/// Proxy on a random loopback port offering `methods`, with `users` and default timeouts
+pub async fn proxy(methods: Vec<u8>, users: Vec<User>) -> Merino {
+    proxy_with(
+        methods,
+        Box::new(MemoryAuthenticator::new(users)),
+        Timeouts::default(),
+    )
+    .await
+}
+
+/// Proxy on a random loopback port with any authenticator and timeouts
+pub async fn proxy_with(
+    methods: Vec<u8>,
+    authenticator: Box<dyn Authenticator>,
+    timeouts: Timeouts,
+) -> Merino {
+    Merino::new(0, "127.0.0.1", methods, authenticator, timeouts)
+        .await
+        .unwrap()
+}
+
+/// Serve `merino` in the background, returning its address
+pub fn serve(mut merino: Merino) -> SocketAddr {
+    let addr = merino.local_addr().unwrap();
+    tokio::spawn(async move { merino.serve().await });
+    addr
+}
+
+/// Start a NoAuth proxy without users, changed by `setup` before it's served
+pub async fn start_proxy(setup: impl FnOnce(&mut Merino)) -> SocketAddr {
+    start_proxy_with(vec![AuthMethods::NoAuth as u8], Vec::new(), setup).await
+}
+
+/// Start a proxy offering `methods` to `users`, changed by `setup` before it's served
+pub async fn start_proxy_with(
+    methods: Vec<u8>,
+    users: Vec<User>,
+    setup: impl FnOnce(&mut Merino),
+) -> SocketAddr {
+    let mut merino = proxy(methods, users).await;
+    setup(&mut merino);
+    serve(merino)
+}
+
+/// Start a NoAuth proxy without users and with `timeouts`
+pub async fn start_proxy_with_timeouts(timeouts: Timeouts) -> SocketAddr {
+    let authenticator = Box::new(MemoryAuthenticator::default());
+    serve(proxy_with(vec![AuthMethods::NoAuth as u8], authenticator, timeouts).await)
+}
+
+/// Start a TCP server on `ip` which sends back everything, reporting every peer
+pub async fn start_target(ip: &str) -> (SocketAddr, mpsc::UnboundedReceiver<SocketAddr>) {
+    let listener = TcpListener::bind((ip, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (peers, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut stream, peer)) = listener.accept().await {
+            let _ = peers.send(peer);
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    (addr, receiver)
+}
+
+/// Start a TCP server on loopback which sends back everything
+pub async fn start_echo() -> SocketAddr {
+    start_target("127.0.0.1").await.0
+}
+
+/// Start a UDP server on loopback which sends every datagram back
+pub async fn start_udp_echo() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1500];
+        while let Ok((len, src)) = socket.recv_from(&mut buf).await {
+            socket.send_to(&buf[..len], src).await.unwrap();
+        }
+    });
+    addr
+}
+
+/// ATYP, ADDR and PORT fields for `addr`
+pub fn encode_addr(addr: SocketAddr) -> Vec<u8> {
+    let mut buf = match addr.ip() {
+        IpAddr::V4(ip) => [&[1][..], &ip.octets()].concat(),
+        IpAddr::V6(ip) => [&[4][..], &ip.octets()].concat(),
+    };
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+    buf
+}
+
+/// ATYP, ADDR and PORT fields for `domain`
+pub fn encode_domain(domain: &str, port: u16) -> Vec<u8> {
+    let mut buf = vec![3, domain.len() as u8];
+    buf.extend_from_slice(domain.as_bytes());
+    buf.extend_from_slice(&port.to_be_bytes());
+    buf
+}
+
+/// Connect to `proxy` and negotiate NoAuth
+pub async fn no_auth(proxy: SocketAddr) -> TcpStream {
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut method = [0u8; 2];
+    client.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [5, 0]);
+    client
+}
+
+/// Connect to `proxy` and log in with USER/PASS
+pub async fn login(proxy: SocketAddr, username: &str, password: &str) -> TcpStream {
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(&[5, 1, 2]).await.unwrap();
+    let mut method = [0u8; 2];
+    client.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [5, 2]);
+
+    let mut auth = vec![1, username.len() as u8];
+    auth.extend_from_slice(username.as_bytes());
+    auth.push(password.len() as u8);
+    auth.extend_from_slice(password.as_bytes());
+    client.write_all(&auth).await.unwrap();
+    let mut status = [0u8; 2];
+    client.read_exact(&mut status).await.unwrap();
+    assert_eq!(status, [1, 0]);
+    client
+}
+
+/// Reply to a request
+#[derive(Debug)]
+pub struct Reply {
+    pub rep: u8,
+    /// BND.ADDR and BND.PORT
+    pub bound: SocketAddr,
+}
+
+/// Send `command` for the encoded `dest` and read the reply
+pub async fn request(client: &mut TcpStream, command: u8, dest: &[u8]) -> Reply {
+    let mut request = vec![5, command, 0];
+    request.extend_from_slice(dest);
+    client.write_all(&request).await.unwrap();
+    read_reply(client).await
+}
+
+/// Read a reply with an IPv4 or IPv6 address
+pub async fn read_reply(client: &mut TcpStream) -> Reply {
+    let mut header = [0u8; 4];
+    client.read_exact(&mut header).await.unwrap();
+    let ip = match header[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            client.read_exact(&mut ip).await.unwrap();
+            IpAddr::from(Ipv4Addr::from(ip))
+        }
+        4 => {
+            let mut ip = [0u8; 16];
+            client.read_exact(&mut ip).await.unwrap();
+            IpAddr::from(Ipv6Addr::from(ip))
+        }
+        atyp => panic!("unexpected address type {}", atyp),
+    };
+    let port = client.read_u16().await.unwrap();
+    Reply {
+        rep: header[1],
+        bound: SocketAddr::new(ip, port),
+    }
+}
+
+/// Negotiate NoAuth with `proxy` and CONNECT to `target`
+pub async fn connect(proxy: SocketAddr, target: SocketAddr) -> (TcpStream, Reply) {
+    let mut client = no_auth(proxy).await;
+    let reply = request(&mut client, 1, &encode_addr(target)).await;
+    (client, reply)
+}
+
+/// Negotiate NoAuth with `proxy` and CONNECT to `domain`:`port`
+pub async fn connect_domain(proxy: SocketAddr, domain: &str, port: u16) -> (TcpStream, Reply) {
+    let mut client = no_auth(proxy).await;
+    let reply = request(&mut client, 1, &encode_domain(domain, port)).await;
+    (client, reply)
+}
+
+/// Unique path in the temporary directory, as tests run concurrently. Nothing is created.
+pub fn temp_path(name: &str) -> PathBuf {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    std::env::temp_dir().join(format!(
+        "merino-{}-{}-{}",
+        name,
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Write `content` to a unique temporary file
+pub fn temp_file(name: &str, content: &str) -> PathBuf {
+    let path = temp_path(name);
+    std::fs::write(&path, content).unwrap();
+    path
+}
+
+/// Resolver answering from a hosts file with `hosts`
+pub fn hosts_resolver(hosts: &str) -> Resolver {
+    let path = temp_file("hosts", hosts);
+    let resolver = Resolver::new(&[], Some(&path), Duration::from_secs(1)).unwrap();
+    std::fs::remove_file(path).unwrap();
+    resolver
+}
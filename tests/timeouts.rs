@@ -0,0 +1,168 @@
+mod support;
+
+use merino::*;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::time::{sleep, timeout};
+
+/// Wait for the proxy to close the connection
+async fn assert_closed(client: &mut TcpStream) {
+    let mut buf = [0u8; 16];
+    let read = timeout(Duration::from_secs(2), client.read(&mut buf)).await;
+    assert_eq!(read.unwrap().unwrap(), 0);
+}
+
+#[tokio::test]
+/// Clients which don't finish the handshake are cut off
+async fn handshake_timeout() {
+    let proxy = support::start_proxy_with_timeouts(Timeouts {
+        handshake: Duration::from_millis(100),
+        ..Timeouts::default()
+    })
+    .await;
+
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(&[5, 1]).await.unwrap();
+
+    let reply = support::read_reply(&mut client).await;
+    assert_eq!(reply.rep, ResponseCode::TtlExpired as u8);
+    assert_closed(&mut client).await;
+}
+
+#[tokio::test]
+/// Outbound connections which don't complete in time are reported as TTL expired
+async fn connect_timeout() {
+    let proxy = support::start_proxy_with_timeouts(Timeouts {
+        connect: Duration::from_millis(200),
+        ..Timeouts::default()
+    })
+    .await;
+
+    // Listener which is never accepting, so new connections hang once its backlog is full
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let hanging = socket.listen(0).unwrap();
+    let target = hanging.local_addr().unwrap();
+    let mut backlog = Vec::new();
+    for _ in 0..4 {
+        if let Ok(Ok(stream)) =
+            timeout(Duration::from_millis(100), TcpStream::connect(target)).await
+        {
+            backlog.push(stream);
+        }
+    }
+
+    let (_, reply) = support::connect(proxy, target).await;
+    assert_eq!(reply.rep, ResponseCode::TtlExpired as u8);
+}
+
+#[tokio::test]
+/// Sessions without traffic are closed after the idle timeout
+async fn idle_timeout() {
+    let proxy = support::start_proxy_with_timeouts(Timeouts {
+        idle: Some(Duration::from_millis(300)),
+        ..Timeouts::default()
+    })
+    .await;
+    let echo = support::start_echo().await;
+
+    let (mut client, reply) = support::connect(proxy, echo).await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+
+    // Traffic keeps the session open
+    let mut buf = [0u8; 4];
+    for _ in 0..4 {
+        sleep(Duration::from_millis(150)).await;
+        client.write_all(b"ping").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+    }
+
+    assert_closed(&mut client).await;
+}
+
+#[tokio::test]
+/// UDP associations without datagrams are closed after the idle timeout
+async fn udp_idle_timeout() {
+    let proxy = support::start_proxy_with_timeouts(Timeouts {
+        idle: Some(Duration::from_millis(100)),
+        ..Timeouts::default()
+    })
+    .await;
+
+    let mut client = support::no_auth(proxy).await;
+    let client_addr = support::encode_addr("127.0.0.1:0".parse().unwrap());
+    let reply = support::request(&mut client, 3, &client_addr).await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+
+    assert_closed(&mut client).await;
+}
+
+#[tokio::test]
+/// Sessions are closed after the maximum lifetime, even with traffic
+async fn max_session_lifetime() {
+    let proxy = support::start_proxy_with_timeouts(Timeouts {
+        lifetime: Some(Duration::from_millis(400)),
+        ..Timeouts::default()
+    })
+    .await;
+    let echo = support::start_echo().await;
+
+    let (mut client, reply) = support::connect(proxy, echo).await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+
+    let mut buf = [0u8; 4];
+    let closed = timeout(Duration::from_secs(2), async {
+        loop {
+            sleep(Duration::from_millis(50)).await;
+            if client.write_all(b"ping").await.is_err() {
+                break;
+            }
+            match client.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+        }
+    })
+    .await;
+    assert!(closed.is_ok());
+}
+
+#[tokio::test]
+/// Finished sessions are counted by close reason
+async fn close_reason_stats() {
+    let merino = support::proxy_with(
+        vec![AuthMethods::NoAuth as u8],
+        Box::new(MemoryAuthenticator::default()),
+        Timeouts {
+            idle: Some(Duration::from_millis(100)),
+            ..Timeouts::default()
+        },
+    )
+    .await;
+    let stats = merino.get_stats();
+    let proxy = support::serve(merino);
+    let echo = support::start_echo().await;
+
+    // Closed by the client
+    let (mut client, reply) = support::connect(proxy, echo).await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+    client.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    client.read_exact(&mut buf).await.unwrap();
+    client.shutdown().await.unwrap();
+    assert_closed(&mut client).await;
+
+    // Closed as idle
+    let (mut client, reply) = support::connect(proxy, echo).await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+    assert_closed(&mut client).await;
+
+    sleep(Duration::from_millis(100)).await;
+    let stats = stats.read().unwrap();
+    assert_eq!(stats.sessions, 2);
+    assert_eq!(stats.upload, 4);
+    assert_eq!(stats.download, 4);
+    assert_eq!(stats.closed.get(&CloseReason::Eof), Some(&1));
+    assert_eq!(stats.closed.get(&CloseReason::Idle), Some(&1));
+}
@@ -0,0 +1,208 @@
+mod support;
+
+use merino::*;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::timeout;
+
+/// Send UDP ASSOCIATE for `client`, returning the control connection and the relay address
+async fn associate(proxy: SocketAddr, client: SocketAddr) -> (TcpStream, SocketAddr) {
+    let mut control = TcpStream::connect(proxy).await.unwrap();
+    control.write_all(&[5, 1, 0]).await.unwrap();
+    let mut method = [0u8; 2];
+    control.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [5, 0]);
+
+    let mut request = vec![5, 3, 0];
+    request.extend_from_slice(&support::encode_addr(client));
+    control.write_all(&request).await.unwrap();
+
+    let mut reply = [0u8; 10];
+    control.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], ResponseCode::Success as u8);
+    let ip = Ipv4Addr::new(reply[4], reply[5], reply[6], reply[7]);
+    let port = u16::from_be_bytes([reply[8], reply[9]]);
+
+    (control, SocketAddr::from((ip, port)))
+}
+
+/// Datagram with a UDP request header for `addr`
+fn datagram(frag: u8, addr: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0, 0, frag];
+    packet.extend_from_slice(&support::encode_addr(addr));
+    packet.extend_from_slice(data);
+    packet
+}
+
+async fn recv(socket: &UdpSocket) -> Option<Vec<u8>> {
+    let mut buf = [0u8; 1500];
+    match timeout(Duration::from_millis(200), socket.recv(&mut buf)).await {
+        Ok(len) => Some(buf[..len.unwrap()].to_vec()),
+        Err(_) => None,
+    }
+}
+
+#[tokio::test]
+/// Datagrams are relayed to the target and answers are wrapped with its address
+async fn udp_relays_both_ways() {
+    let proxy = support::start_proxy(|_| {}).await;
+    let echo = support::start_udp_echo().await;
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (_control, relay) = associate(proxy, client.local_addr().unwrap()).await;
+
+    client
+        .send_to(&datagram(0, echo, b"hello"), relay)
+        .await
+        .unwrap();
+
+    assert_eq!(recv(&client).await.unwrap(), datagram(0, echo, b"hello"));
+}
+
+#[tokio::test]
+/// Fragmented datagrams are dropped
+async fn udp_drops_fragments() {
+    let proxy = support::start_proxy(|_| {}).await;
+    let echo = support::start_udp_echo().await;
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (_control, relay) = associate(proxy, client.local_addr().unwrap()).await;
+
+    client
+        .send_to(&datagram(1, echo, b"fragment"), relay)
+        .await
+        .unwrap();
+    client
+        .send_to(&datagram(0, echo, b"whole"), relay)
+        .await
+        .unwrap();
+
+    assert_eq!(recv(&client).await.unwrap(), datagram(0, echo, b"whole"));
+    assert_eq!(recv(&client).await, None);
+}
+
+#[tokio::test]
+/// Only the announced address may use the association
+async fn udp_restricts_client_source() {
+    let proxy = support::start_proxy(|_| {}).await;
+    let echo = support::start_udp_echo().await;
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (_control, relay) = associate(proxy, client.local_addr().unwrap()).await;
+
+    stranger
+        .send_to(&datagram(0, echo, b"hello"), relay)
+        .await
+        .unwrap();
+
+    assert_eq!(recv(&stranger).await, None);
+}
+
+#[tokio::test]
+/// Closing the control connection tears the association down
+async fn udp_closes_with_control_connection() {
+    let proxy = support::start_proxy(|_| {}).await;
+    let echo = support::start_udp_echo().await;
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (control, relay) = associate(proxy, client.local_addr().unwrap()).await;
+
+    drop(control);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    client
+        .send_to(&datagram(0, echo, b"hello"), relay)
+        .await
+        .unwrap();
+
+    assert_eq!(recv(&client).await, None);
+}
+
+#[tokio::test]
+/// Datagrams which can't be sent are dropped without ending the association
+async fn udp_survives_failed_send() {
+    let proxy = support::start_proxy(|_| {}).await;
+    let echo = support::start_udp_echo().await;
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (_control, relay) = associate(proxy, client.local_addr().unwrap()).await;
+
+    // Nothing can be sent to port 0
+    let unreachable = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    client
+        .send_to(&datagram(0, unreachable, b"lost"), relay)
+        .await
+        .unwrap();
+    client
+        .send_to(&datagram(0, echo, b"hello"), relay)
+        .await
+        .unwrap();
+
+    assert_eq!(recv(&client).await.unwrap(), datagram(0, echo, b"hello"));
+}
+
+#[tokio::test]
+/// Targets the client hasn't sent to in a long time are forgotten and can't answer anymore
+async fn udp_forgets_old_targets() {
+    let proxy = support::start_proxy(|_| {}).await;
+    let echo = support::start_udp_echo().await;
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (_control, relay) = associate(proxy, client.local_addr().unwrap()).await;
+
+    client
+        .send_to(&datagram(0, target.local_addr().unwrap(), b"hello"), relay)
+        .await
+        .unwrap();
+    let mut buf = [0u8; 16];
+    let (_, outbound) = target.recv_from(&mut buf).await.unwrap();
+
+    // An association remembers 1024 targets, nothing listens on these
+    for batch in 0..16 {
+        for i in 0..64 {
+            let port = 10_000 + batch * 64 + i;
+            let addr = SocketAddr::from((Ipv4Addr::new(127, 0, 1, 1), port));
+            client
+                .send_to(&datagram(0, addr, b"nobody"), relay)
+                .await
+                .unwrap();
+        }
+        // Recent targets can still answer
+        client
+            .send_to(&datagram(0, echo, b"hello"), relay)
+            .await
+            .unwrap();
+        assert_eq!(recv(&client).await.unwrap(), datagram(0, echo, b"hello"));
+    }
+
+    target.send_to(b"late", outbound).await.unwrap();
+    assert_eq!(recv(&client).await, None);
+}
+
+#[tokio::test]
+/// Datagrams to addresses keep flowing while a domain is resolved
+async fn udp_resolves_in_background() {
+    // Nameserver which never answers
+    let nameserver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let nameserver = nameserver.local_addr().unwrap();
+    let _listener = tokio::net::TcpListener::bind(nameserver).await.unwrap();
+
+    let proxy = support::start_proxy(|merino| {
+        merino.set_resolver(Resolver::new(&[nameserver], None, Duration::from_secs(5)).unwrap())
+    })
+    .await;
+
+    let echo = support::start_udp_echo().await;
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (_control, relay) = associate(proxy, client.local_addr().unwrap()).await;
+
+    let mut slow = vec![0, 0, 0, 3, 9];
+    slow.extend_from_slice(b"slow.test");
+    slow.extend_from_slice(&echo.port().to_be_bytes());
+    slow.extend_from_slice(b"later");
+    client.send_to(&slow, relay).await.unwrap();
+    client
+        .send_to(&datagram(0, echo, b"hello"), relay)
+        .await
+        .unwrap();
+
+    assert_eq!(recv(&client).await.unwrap(), datagram(0, echo, b"hello"));
+}
@@ -0,0 +1,283 @@
+mod support;
+
+use merino::*;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Name only the upstreams can resolve
+const TARGET_DOMAIN: &str = "target.test";
+
+/// Merino which resolves `TARGET_DOMAIN`, with alice if `users` is set
+async fn start_merino(users: bool, router: Router) -> Merino {
+    let (methods, users) = match users {
+        true => (
+            vec![AuthMethods::UserPass as u8],
+            vec![User::new("alice", "secret")],
+        ),
+        false => (vec![AuthMethods::NoAuth as u8], Vec::new()),
+    };
+    let mut merino = support::proxy(methods, users).await;
+    merino.set_resolver(support::hosts_resolver(&format!(
+        "127.0.0.1 {}\n",
+        TARGET_DOMAIN
+    )));
+    merino.set_router(router);
+    merino
+}
+
+/// Start a merino without upstreams and return its address
+async fn start_upstream(users: bool) -> SocketAddr {
+    support::serve(start_merino(users, Router::default()).await)
+}
+
+/// Start a merino which sends everything through `chain`
+async fn start_front(chain: &str) -> SocketAddr {
+    let upstream = format!("corp={}", chain).parse().unwrap();
+    let router = Router::new(vec![upstream], Vec::new(), "via corp".parse().unwrap()).unwrap();
+    let mut merino = start_merino(false, router).await;
+    // The front can't resolve the target, so only the upstreams can connect to it
+    merino.set_resolver(Resolver::new(&[], None, Duration::from_secs(1)).unwrap());
+    support::serve(merino)
+}
+
+/// The tunnel reaches the echo server
+async fn assert_echo(client: &mut TcpStream) {
+    client.write_all(b"ping").await.unwrap();
+    let mut pong = [0u8; 4];
+    client.read_exact(&mut pong).await.unwrap();
+    assert_eq!(&pong, b"ping");
+}
+
+#[test]
+fn parse_proxy() {
+    let proxy: Proxy = "socks5://alice:p@ss:word@proxy.example.com:1080"
+        .parse()
+        .unwrap();
+    assert_eq!(proxy.protocol, Protocol::Socks5);
+    assert_eq!(proxy.host, "proxy.example.com");
+    assert_eq!(proxy.port, 1080);
+    assert_eq!(proxy.username.as_deref(), Some("alice"));
+    assert_eq!(proxy.password.as_deref(), Some("p@ss:word"));
+    // Passwords are not shown
+    assert_eq!(proxy.to_string(), "socks5://alice@proxy.example.com:1080");
+
+    let proxy: Proxy = "http://[::1]:3128/".parse().unwrap();
+    assert_eq!(proxy.protocol, Protocol::Http);
+    assert_eq!(proxy.host, "::1");
+    assert_eq!(proxy.to_string(), "http://[::1]:3128");
+
+    assert!("socks4://proxy:1080".parse::<Proxy>().is_err());
+    assert!("socks5://proxy".parse::<Proxy>().is_err());
+    assert!("proxy:1080".parse::<Proxy>().is_err());
+}
+
+#[test]
+fn parse_upstream() {
+    let upstream: Upstream = "chain=socks5://a:1080, http://b:3128".parse().unwrap();
+    assert_eq!(upstream.name, "chain");
+    assert_eq!(upstream.hops.len(), 2);
+    assert_eq!(upstream.hops[1].protocol, Protocol::Http);
+
+    assert!("socks5://a:1080".parse::<Upstream>().is_err());
+    assert!("empty=".parse::<Upstream>().is_err());
+}
+
+#[test]
+fn routes() {
+    let routes: Routes = "# internal hosts go through the parent proxy\n\
+        direct dest=.public.corp\n\
+        via corp dest=.corp,10.0.0.0/8 port=443\n"
+        .parse()
+        .unwrap();
+    assert_eq!(routes.len(), 2);
+
+    let request = |domain, ip: Option<&str>| rules::Request {
+        command: rules::Command::Connect,
+        user: None,
+        source: "192.168.0.1".parse().unwrap(),
+        domain,
+        ip: ip.map(|ip| ip.parse().unwrap()),
+        port: 443,
+    };
+    let via = Route::Via("corp".to_string());
+    assert_eq!(routes.route(&request(Some("git.corp"), None)), &via);
+    assert_eq!(routes.route(&request(None, Some("10.1.2.3"))), &via);
+    assert_eq!(
+        routes.route(&request(Some("www.public.corp"), None)),
+        &Route::Direct
+    );
+    assert_eq!(
+        routes.route(&request(Some("example.com"), None)),
+        &Route::Direct
+    );
+
+    assert!("via".parse::<Routes>().is_err());
+    assert!("proxy dest=.corp".parse::<Routes>().is_err());
+    assert!(Router::new(Vec::new(), Vec::new(), "via corp".parse().unwrap()).is_err());
+}
+
+#[tokio::test]
+async fn socks5_with_login() {
+    let target = support::start_echo().await;
+    let upstream = start_upstream(true).await;
+
+    let front = start_front(&format!("socks5://alice:secret@{}", upstream)).await;
+    let (mut client, reply) = support::connect_domain(front, TARGET_DOMAIN, target.port()).await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+    assert_echo(&mut client).await;
+
+    // Failures of the upstream are passed on
+    let front = start_front(&format!("socks5://alice:guess@{}", upstream)).await;
+    let (_, reply) = support::connect_domain(front, TARGET_DOMAIN, target.port()).await;
+    assert_eq!(reply.rep, ResponseCode::RuleFailure as u8);
+}
+
+#[tokio::test]
+async fn socks4a() {
+    let target = support::start_echo().await;
+    let upstream = start_upstream(false).await;
+    let front = start_front(&format!("socks4a://{}", upstream)).await;
+
+    let (mut client, reply) = support::connect_domain(front, TARGET_DOMAIN, target.port()).await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+    assert_echo(&mut client).await;
+}
+
+/// Start an HTTP proxy which only tunnels to `target` for alice
+async fn start_http_proxy(target: SocketAddr) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut header = Vec::new();
+                while !header.ends_with(b"\r\n\r\n") {
+                    header.push(stream.read_u8().await.unwrap());
+                }
+                let header = String::from_utf8(header).unwrap();
+                let expected = format!(
+                    "CONNECT {0}:{1} HTTP/1.1\r\nHost: {0}:{1}\r\n",
+                    TARGET_DOMAIN,
+                    target.port()
+                );
+                if !header.starts_with(&expected)
+                    || !header.contains("Proxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n")
+                {
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                        .await;
+                    return;
+                }
+
+                stream
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                    .await
+                    .unwrap();
+                let mut target = TcpStream::connect(target).await.unwrap();
+                let _ = tokio::io::copy_bidirectional(&mut stream, &mut target).await;
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn http_connect() {
+    let target = support::start_echo().await;
+    let upstream = start_http_proxy(target).await;
+
+    let front = start_front(&format!("http://alice:secret@{}", upstream)).await;
+    let (mut client, reply) = support::connect_domain(front, TARGET_DOMAIN, target.port()).await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+    assert_echo(&mut client).await;
+
+    let front = start_front(&format!("http://bob:secret@{}", upstream)).await;
+    let (_, reply) = support::connect_domain(front, TARGET_DOMAIN, target.port()).await;
+    assert_eq!(reply.rep, ResponseCode::RuleFailure as u8);
+}
+
+/// Start a fake upstream which accepts a request and answers with `response`, then `data`
+async fn start_fake_upstream(response: &'static [u8], data: &'static [u8]) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = [0u8; 64];
+            let _ = stream.read(&mut request).await;
+            let _ = stream.write_all(&[response, data].concat()).await;
+            // Keep the tunnel open
+            let _ = stream.read(&mut request).await;
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+/// Data sent by the target right after an HTTP proxy connected reaches the client
+async fn http_early_data() {
+    let upstream = start_fake_upstream(b"HTTP/1.1 200 OK\r\n\r\n", b"banner").await;
+    let front = start_front(&format!("http://{}", upstream)).await;
+
+    let (mut client, reply) = support::connect_domain(front, TARGET_DOMAIN, 22).await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+    let mut banner = [0u8; 6];
+    client.read_exact(&mut banner).await.unwrap();
+    assert_eq!(&banner, b"banner");
+}
+
+#[tokio::test]
+/// Replies of upstreams which don't speak SOCKS5 are refused
+async fn socks5_wrong_version() {
+    // Method selection and a SOCKS4-like reply at once
+    let upstream = start_fake_upstream(&[5, 0, 4, 0, 0, 1, 127, 0, 0, 1, 0, 22], b"").await;
+    let front = start_front(&format!("socks5://{}", upstream)).await;
+
+    let (_, reply) = support::connect_domain(front, TARGET_DOMAIN, 22).await;
+    assert_ne!(reply.rep, ResponseCode::Success as u8);
+}
+
+#[tokio::test]
+/// Every proxy of a chain connects to the next one
+async fn multi_hop() {
+    let target = support::start_echo().await;
+    let last = start_upstream(true).await;
+    let merino = start_merino(false, Router::default()).await;
+    let quotas = merino.get_quotas();
+    let first = support::serve(merino);
+    let merino = start_merino(true, Router::default()).await;
+    let middle_quotas = merino.get_quotas();
+    let middle = support::serve(merino);
+
+    let front = start_front(&format!(
+        "socks4a://{},socks5://alice:secret@{},socks5://alice:secret@{}",
+        first, middle, last
+    ))
+    .await;
+    let (mut client, reply) = support::connect_domain(front, TARGET_DOMAIN, target.port()).await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+    assert_echo(&mut client).await;
+
+    // Only users are accounted, the first hop has none
+    assert!(quotas.usage().is_empty());
+    assert_eq!(middle_quotas.usage()[0].username, "alice");
+}
+
+#[tokio::test]
+/// Requests matching no route are connected directly, and are checked as before
+async fn direct() {
+    let target = support::start_echo().await;
+    let upstream: Upstream = "corp=socks5://127.0.0.1:9".parse().unwrap();
+    let router = Router::new(
+        vec![upstream],
+        Vec::new(),
+        "via corp dest=.corp".parse().unwrap(),
+    )
+    .unwrap();
+    let front = support::serve(start_merino(false, router).await);
+
+    let (mut client, reply) = support::connect_domain(front, TARGET_DOMAIN, target.port()).await;
+    assert_eq!(reply.rep, ResponseCode::Success as u8);
+    assert_echo(&mut client).await;
+}
@@ -0,0 +1,113 @@
+mod support;
+
+use merino::*;
+use std::net::IpAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+fn set(nets: &[&str]) -> IpSet {
+    nets.iter().map(|net| parse_net(net).unwrap()).collect()
+}
+
+#[test]
+fn ipv4_networks() {
+    let set = set(&["10.20.4.0/22", "192.168.1.7"]);
+    assert!(set.contains(&ip("10.20.4.0")));
+    assert!(set.contains(&ip("10.20.7.255")));
+    assert!(!set.contains(&ip("10.20.8.0")));
+    assert!(!set.contains(&ip("10.20.3.255")));
+    assert!(set.contains(&ip("192.168.1.7")));
+    assert!(!set.contains(&ip("192.168.1.8")));
+    assert!(!set.contains(&ip("::1")));
+}
+
+#[test]
+fn ipv6_networks() {
+    let set = set(&["2001:db8:aa::/48", "::1"]);
+    assert!(set.contains(&ip("2001:db8:aa::1")));
+    assert!(set.contains(&ip("2001:db8:aa:ffff:ffff:ffff:ffff:ffff")));
+    assert!(!set.contains(&ip("2001:db8:ab::")));
+    assert!(set.contains(&ip("::1")));
+    assert!(!set.contains(&ip("::2")));
+    assert!(!set.contains(&ip("127.0.0.1")));
+}
+
+#[test]
+/// Clients of dual-stack listeners show up as IPv4-mapped addresses
+fn ipv4_mapped() {
+    let set = set(&["10.0.0.0/8", "::ffff:172.16.0.0/108"]);
+    assert!(set.contains(&ip("::ffff:10.1.2.3")));
+    assert!(set.contains(&ip("172.16.1.1")));
+    assert!(!set.contains(&ip("::ffff:11.0.0.1")));
+}
+
+#[test]
+fn nested_networks() {
+    // Wider network added after a narrower one, and the other way round
+    let set = set(&[
+        "10.1.2.0/24",
+        "10.0.0.0/8",
+        "172.16.0.0/12",
+        "172.16.5.0/24",
+    ]);
+    assert!(set.contains(&ip("10.1.2.3")));
+    assert!(set.contains(&ip("10.200.0.1")));
+    assert!(set.contains(&ip("172.31.0.1")));
+    assert_eq!(set.len(), 4);
+}
+
+#[test]
+fn everything() {
+    let set = set(&["0.0.0.0/0"]);
+    assert!(set.contains(&ip("1.2.3.4")));
+    assert!(!set.contains(&ip("::1")));
+}
+
+#[test]
+fn insert_and_iter() {
+    let mut set = IpSet::new();
+    assert!(set.is_empty());
+    // Host bits are ignored
+    assert!(set.insert(parse_net("10.0.0.5/8").unwrap()));
+    assert!(!set.insert(parse_net("10.0.0.0/8").unwrap()));
+    assert!(set.insert(parse_net("::1").unwrap()));
+    assert_eq!(
+        set.iter().map(|net| net.to_string()).collect::<Vec<_>>(),
+        ["10.0.0.0/8", "::1/128"]
+    );
+}
+
+#[test]
+fn parse() {
+    assert_eq!(parse_net("1.2.3.4").unwrap().to_string(), "1.2.3.4/32");
+    assert_eq!(parse_net("fe80::/10").unwrap().to_string(), "fe80::/10");
+    assert!(parse_net("1.2.3.4/33").is_err());
+    assert!(parse_net("localhost").is_err());
+}
+
+#[tokio::test]
+/// Networks and comments in the whitelist file
+async fn whitelist_file() {
+    let path = support::temp_file(
+        "whitelist",
+        "# Office\n10.20.4.0/22\n\n  127.0.0.0/8  # loopback\n2001:db8:aa::/48\n",
+    );
+
+    let mut merino = support::proxy(vec![AuthMethods::UserPass as u8], Vec::new()).await;
+    merino.load_whitelist(&path);
+    assert_eq!(merino.get_whitelist().read().unwrap().len(), 3);
+    let proxy = support::serve(merino);
+
+    // 127.0.0.1 is offered NO AUTH
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut method = [0u8; 2];
+    client.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [5, AuthMethods::NoAuth as u8]);
+
+    std::fs::remove_file(path).unwrap();
+}